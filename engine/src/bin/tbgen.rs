@@ -0,0 +1,32 @@
+// Generates endgame tablebase files for the moon_chess engine.
+// Usage: moon-chess-tbgen <out_dir> <material>...   e.g. moon-chess-tbgen tables KQvK KRvK KPvK KBNvK
+use std::env;
+use std::process;
+use std::time::Instant;
+use moon_chess_engine::tablebase::Tablebase;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (out_dir, names) = match args.split_first() {
+        Some((out_dir, names)) if !names.is_empty() => (out_dir, names),
+        _ => {
+            eprintln!("usage: moon-chess-tbgen <out_dir> <material>...");
+            process::exit(2);
+        }
+    };
+    // Reuse tables that were generated before
+    let mut tablebase = Tablebase::load_dir(out_dir).unwrap_or_default();
+    for name in names {
+        let started = Instant::now();
+        if let Err(error) = tablebase.generate(name) {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+        eprintln!("{} generated in {:.1}s", name, started.elapsed().as_secs_f64());
+    }
+    if let Err(error) = tablebase.write_dir(out_dir) {
+        eprintln!("could not write {}: {}", out_dir, error);
+        process::exit(1);
+    }
+    eprintln!("wrote {}", tablebase.materials().join(" "));
+}
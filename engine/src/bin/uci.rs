@@ -0,0 +1,169 @@
+// Universal Chess Interface front end for the moon_chess engine.
+// Reads commands from stdin and writes responses to stdout, one per line.
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use chess_game::code_generator::apply_turn;
+use chess_game::game_state::GameState;
+use moon_chess_engine::notation::{from_fen,parse_uci,to_uci,START_FEN};
+use moon_chess_engine::{position_key,EvalParams,Limits,SearchResult,Searcher};
+
+const ENGINE_NAME: &str = "Moon Chess";
+const DEFAULT_HASH_MB: usize = 16;
+
+#[derive(Default)]
+struct Position {
+    game_state: GameState,
+    past_keys: Vec<u64>, // Keys of the positions before the current one
+}
+
+struct Engine {
+    searcher: Option<Searcher>,
+    running: Option<JoinHandle<Searcher>>,
+    stop: Arc<AtomicBool>,
+    position: Position,
+}
+impl Engine {
+    fn new() -> Self {
+        let searcher = Searcher::new(EvalParams::default(), DEFAULT_HASH_MB);
+        Self {
+            stop: searcher.stop_handle(),
+            searcher: Some(searcher),
+            running: None,
+            position: Position::default(),
+        }
+    }
+
+    fn stop(&mut self) {
+        // Ends any running search (which prints its bestmove) and takes the searcher back.
+        // The flag is set before joining, or an infinite search would never return.
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.running.take() {
+            self.searcher = Some(handle.join().expect("search thread panicked"));
+        }
+    }
+
+    fn searcher(&mut self) -> &mut Searcher {
+        self.stop();
+        self.searcher.as_mut().unwrap()
+    }
+
+    fn set_option(&mut self, args: &[&str]) {
+        // setoption name <id> [value <x>]
+        if args.first() != Some(&"name") {
+            return;
+        }
+        let value_at = args.iter().position(|arg| *arg == "value");
+        let name = args[1..value_at.unwrap_or(args.len())].join(" ");
+        let value = value_at.map(|at| args[at+1..].join(" "));
+        if name.eq_ignore_ascii_case("hash") {
+            if let Some(hash_mb) = value.and_then(|value| value.parse().ok()) {
+                self.stop();
+                let searcher = Searcher::new(EvalParams::default(), hash_mb);
+                self.stop = searcher.stop_handle();
+                self.searcher = Some(searcher);
+            }
+        }
+    }
+
+    fn set_position(&mut self, args: &[&str]) -> Result<(), String> {
+        // position [startpos | fen <fen>] [moves <move1> ... <movei>]
+        let moves_at = args.iter().position(|arg| *arg == "moves").unwrap_or(args.len());
+        let mut game_state = match args.first() {
+            Some(&"startpos") => from_fen(START_FEN),
+            Some(&"fen") => from_fen(&args[1..moves_at].join(" ")),
+            _ => return Err("expected startpos or fen".to_string()),
+        }.map_err(|error| error.to_string())?;
+        let mut past_keys = Vec::new();
+        for text in args.iter().skip(moves_at + 1) {
+            let turn = parse_uci(&game_state, text).map_err(|error| error.to_string())?;
+            past_keys.push(position_key(&game_state));
+            game_state = apply_turn(&game_state, turn).unwrap();
+        }
+        self.position = Position { game_state, past_keys };
+        Ok(())
+    }
+
+    fn go(&mut self, args: &[&str]) {
+        let limits = Limits::from_go(args, self.position.game_state.white_active);
+        let infinite = args.contains(&"infinite");
+
+        let mut searcher = self.searcher.take().expect("searcher is only taken while a search runs");
+        self.stop.store(false, Ordering::Relaxed);
+        let stop = self.stop.clone();
+        let game_state = self.position.game_state.clone();
+        let past_keys = self.position.past_keys.clone();
+        self.running = Some(thread::spawn(move || {
+            let result = searcher.search_with(&game_state, &past_keys, limits, print_info);
+            // In infinite mode the GUI expects bestmove only after it sends stop
+            while infinite && !stop.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(5));
+            }
+            let best = result.best.map(|turn| to_uci(&turn)).unwrap_or_else(|| "0000".to_string());
+            respond(&format!("bestmove {}", best));
+            searcher
+        }));
+    }
+}
+
+fn print_info(result: &SearchResult) {
+    let score = match result.mate_in() {
+        Some(moves) => format!("mate {}", moves),
+        None => format!("cp {}", result.score),
+    };
+    let millis = result.elapsed.as_millis().max(1);
+    let pv: Vec<String> = result.pv.iter().map(to_uci).collect();
+    respond(&format!("info depth {} score {} nodes {} nps {} time {} pv {}",
+        result.depth, score, result.nodes, u128::from(result.nodes) * 1000 / millis, millis, pv.join(" ")));
+}
+
+fn respond(line: &str) {
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{}", line).unwrap();
+    stdout.flush().unwrap();
+}
+
+fn main() {
+    let mut engine = Engine::new();
+    for line in io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match args.split_first() {
+            Some((command, args)) => (*command, args),
+            None => continue,
+        };
+        match command {
+            "uci" => {
+                respond(&format!("id name {} {}", ENGINE_NAME, env!("CARGO_PKG_VERSION")));
+                respond("id author moon_chess");
+                respond(&format!("option name Hash type spin default {} min 1 max 4096", DEFAULT_HASH_MB));
+                respond("uciok");
+            }
+            "isready" => respond("readyok"),
+            "setoption" => engine.set_option(args),
+            "ucinewgame" => {
+                engine.searcher().clear();
+                engine.position = Position::default();
+            }
+            "position" => {
+                engine.stop();
+                if let Err(error) = engine.set_position(args) {
+                    respond(&format!("info string {}", error));
+                }
+            }
+            "go" => {
+                engine.stop();
+                engine.go(args);
+            }
+            "stop" => engine.stop(),
+            "quit" => break,
+            _ => respond(&format!("info string unknown command {}", command)),
+        }
+    }
+    engine.stop();
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::fmt;
+use chess_game::code_generator::apply_turn;
+use chess_game::game_state::GameState;
+use chess_game::helpers::Turn;
+use crate::notation::{parse_uci,NotationError};
+use crate::polyglot::polyglot_key;
+
+// One or more openings for each of the 500 ECO codes, one per line: code, name and the moves
+// from the start position in UCI notation
+const ECO_TABLE: &str = include_str!("../data/eco.tsv");
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Opening {
+    pub eco: &'static str,
+    pub name: &'static str,
+    pub plies: usize, // Length of the line in the table
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EcoError {
+    MissingField(usize), // Line number, counted from 1
+    BadMove(usize, NotationError),
+}
+impl fmt::Display for EcoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingField(line) => write!(f, "ECO table line {}: expected code, name and moves", line),
+            Self::BadMove(line, error) => write!(f, "ECO table line {}: {}", line, error),
+        }
+    }
+}
+impl std::error::Error for EcoError {}
+
+pub struct EcoClassifier {
+    by_key: HashMap<u64, Opening>,
+}
+impl EcoClassifier {
+    pub fn new() -> Result<Self, EcoError> {
+        // Classifier for the table bundled with the engine
+        Self::from_table(ECO_TABLE)
+    }
+
+    pub fn from_table(table: &'static str) -> Result<Self, EcoError> {
+        let mut by_key = HashMap::new();
+        for (number, line) in table.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let mut fields = line.split('\t');
+            let (eco, name, moves) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(eco), Some(name), Some(moves)) => (eco, name, moves),
+                _ => return Err(EcoError::MissingField(number + 1)),
+            };
+            let mut game_state = GameState::default();
+            let mut plies = 0;
+            for text in moves.split_whitespace() {
+                let turn = parse_uci(&game_state, text).map_err(|error| EcoError::BadMove(number + 1, error))?;
+                game_state = apply_turn(&game_state, turn).unwrap();
+                plies += 1;
+            }
+            let opening = Opening { eco, name, plies };
+            // The first line reaching a position names it
+            by_key.entry(polyglot_key(&game_state)).or_insert(opening);
+        }
+        Ok(Self { by_key })
+    }
+
+    pub fn classify(&self, turns: &[Turn]) -> Option<Opening> {
+        // Plays the moves from the start position and names the deepest known position reached,
+        // so transpositions into a book line are recognised. Stops at the first illegal move.
+        let mut game_state = GameState::default();
+        let mut best = self.by_key.get(&polyglot_key(&game_state)).copied();
+        for turn in turns {
+            game_state = match apply_turn(&game_state, *turn) {
+                Some(next_state) => next_state,
+                None => break,
+            };
+            best = deeper(best, self.by_key.get(&polyglot_key(&game_state)).copied());
+        }
+        best
+    }
+
+    pub fn classify_move_list(&self, moves: &[u16]) -> Option<Opening> {
+        // Same as classify, from the turn codes stored after the Game account
+        let turns: Vec<Turn> = moves.iter().map(|turn| Turn { turn: *turn }).collect();
+        self.classify(&turns)
+    }
+}
+
+fn deeper(current: Option<Opening>, candidate: Option<Opening>) -> Option<Opening> {
+    match (current, candidate) {
+        (Some(current), Some(candidate)) if candidate.plies < current.plies => Some(current),
+        (current, None) => current,
+        (_, candidate) => candidate,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(moves: &str) -> Vec<Turn> {
+        let mut game_state = GameState::default();
+        moves.split_whitespace().map(|text| {
+            let turn = parse_uci(&game_state, text).unwrap();
+            game_state = apply_turn(&game_state, turn).unwrap();
+            turn
+        }).collect()
+    }
+
+    #[test]
+    fn bundled_table_parses() {
+        let classifier = EcoClassifier::new().unwrap();
+        let mut codes: Vec<&str> = ECO_TABLE.lines().map(|line| line.split('\t').next().unwrap()).collect();
+        codes.dedup();
+        assert_eq!(codes.len(), 500);
+        assert!(codes.windows(2).all(|pair| pair[0] < pair[1]));
+        // Every line names its own position, none is hidden behind an earlier transposition
+        for line in ECO_TABLE.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let opening = classifier.classify(&self::line(fields[2])).unwrap();
+            assert_eq!((opening.eco, opening.name), (fields[0], fields[1]));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_tables() {
+        assert_eq!(EcoClassifier::from_table("A00\tPolish Opening").err(), Some(EcoError::MissingField(1)));
+        assert!(matches!(EcoClassifier::from_table("\nB00\tNonsense\te2e4 e2e4").err(), Some(EcoError::BadMove(2, _))));
+    }
+
+    #[test]
+    fn classifies_known_lines() {
+        let classifier = EcoClassifier::new().unwrap();
+        let opening = classifier.classify(&line("e2e4 e7e5 g1f3 b8c6 f1b5")).unwrap();
+        assert_eq!(opening.eco, "C60");
+        // Later moves outside the table keep the deepest match
+        let opening = classifier.classify(&line("e2e4 e7e5 g1f3 b8c6 f1b5 a7a6 b5a4 g8f6 e1g1 f8e7 f1e1 b7b5 a4b3 d7d6 c2c3")).unwrap();
+        assert_eq!((opening.eco, opening.name, opening.plies), ("C90", "Ruy Lopez: Closed, 7...d6", 14));
+        assert_eq!(classifier.classify(&line("e2e4 b8c6")).unwrap().name, "Nimzowitsch Defense");
+    }
+
+    #[test]
+    fn classifies_transpositions() {
+        let classifier = EcoClassifier::new().unwrap();
+        let direct = classifier.classify(&line("d2d4 g8f6 c2c4 e7e6")).unwrap();
+        let transposed = classifier.classify(&line("c2c4 e7e6 d2d4 g8f6")).unwrap();
+        assert_eq!(direct, transposed);
+    }
+
+    #[test]
+    fn classifies_stored_move_lists() {
+        let classifier = EcoClassifier::new().unwrap();
+        let turns = line("e2e4 c7c5 g1f3 d7d6");
+        let moves: Vec<u16> = turns.iter().map(|turn| turn.turn).collect();
+        assert_eq!(classifier.classify_move_list(&moves), classifier.classify(&turns));
+        assert_eq!(classifier.classify_move_list(&moves).unwrap().eco, "B50");
+        assert_eq!(classifier.classify_move_list(&[]), classifier.classify(&[]));
+    }
+}
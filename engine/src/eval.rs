@@ -0,0 +1,176 @@
+use chess_game::game_state::GameState;
+use chess_game::helpers::Pieces;
+
+// Piece-square tables, written from white's point of view with rank 8 on top
+// (index 0 is a8, index 63 is h1)
+const PAWN_TABLE: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+    50, 50, 50, 50, 50, 50, 50, 50,
+    10, 10, 20, 30, 30, 20, 10, 10,
+     5,  5, 10, 25, 25, 10,  5,  5,
+     0,  0,  0, 20, 20,  0,  0,  0,
+     5, -5,-10,  0,  0,-10, -5,  5,
+     5, 10, 10,-20,-20, 10, 10,  5,
+     0,  0,  0,  0,  0,  0,  0,  0,
+];
+const KNIGHT_TABLE: [i32; 64] = [
+    -50,-40,-30,-30,-30,-30,-40,-50,
+    -40,-20,  0,  0,  0,  0,-20,-40,
+    -30,  0, 10, 15, 15, 10,  0,-30,
+    -30,  5, 15, 20, 20, 15,  5,-30,
+    -30,  0, 15, 20, 20, 15,  0,-30,
+    -30,  5, 10, 15, 15, 10,  5,-30,
+    -40,-20,  0,  5,  5,  0,-20,-40,
+    -50,-40,-30,-30,-30,-30,-40,-50,
+];
+const BISHOP_TABLE: [i32; 64] = [
+    -20,-10,-10,-10,-10,-10,-10,-20,
+    -10,  0,  0,  0,  0,  0,  0,-10,
+    -10,  0,  5, 10, 10,  5,  0,-10,
+    -10,  5,  5, 10, 10,  5,  5,-10,
+    -10,  0, 10, 10, 10, 10,  0,-10,
+    -10, 10, 10, 10, 10, 10, 10,-10,
+    -10,  5,  0,  0,  0,  0,  5,-10,
+    -20,-10,-10,-10,-10,-10,-10,-20,
+];
+const ROOK_TABLE: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+     5, 10, 10, 10, 10, 10, 10,  5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+     0,  0,  0,  5,  5,  0,  0,  0,
+];
+const QUEEN_TABLE: [i32; 64] = [
+    -20,-10,-10, -5, -5,-10,-10,-20,
+    -10,  0,  0,  0,  0,  0,  0,-10,
+    -10,  0,  5,  5,  5,  5,  0,-10,
+     -5,  0,  5,  5,  5,  5,  0, -5,
+      0,  0,  5,  5,  5,  5,  0, -5,
+    -10,  5,  5,  5,  5,  5,  0,-10,
+    -10,  0,  5,  0,  0,  0,  0,-10,
+    -20,-10,-10, -5, -5,-10,-10,-20,
+];
+const KING_MIDDLE_TABLE: [i32; 64] = [
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -20,-30,-30,-40,-40,-30,-30,-20,
+    -10,-20,-20,-20,-20,-20,-20,-10,
+     20, 20,  0,  0,  0,  0, 20, 20,
+     20, 30, 10,  0,  0, 10, 30, 20,
+];
+const KING_END_TABLE: [i32; 64] = [
+    -50,-40,-30,-20,-20,-30,-40,-50,
+    -30,-20,-10,  0,  0,-10,-20,-30,
+    -30,-10, 20, 30, 30, 20,-10,-30,
+    -30,-10, 30, 40, 40, 30,-10,-30,
+    -30,-10, 30, 40, 40, 30,-10,-30,
+    -30,-10, 20, 30, 30, 20,-10,-30,
+    -30,-30,  0,  0,  0,  0,-30,-30,
+    -50,-30,-30,-30,-30,-30,-30,-50,
+];
+
+// Game phase weights: 24 with all minor and major pieces on the board, 0 in a pawn ending
+const MAX_PHASE: i32 = 24;
+
+#[derive(Clone, Copy, Debug)]
+pub struct EvalParams {
+    pub pawn: i32,
+    pub knight: i32,
+    pub bishop: i32,
+    pub rook: i32,
+    pub queen: i32,
+    pub bishop_pair: i32,
+    pub tempo: i32,
+    pub pst_percent: i32, // Scales all piece-square tables, 100 for the tables as written
+}
+impl Default for EvalParams {
+    fn default() -> Self {
+        Self {
+            pawn: 100,
+            knight: 320,
+            bishop: 330,
+            rook: 500,
+            queen: 900,
+            bishop_pair: 30,
+            tempo: 10,
+            pst_percent: 100,
+        }
+    }
+}
+impl EvalParams {
+    pub fn piece_value(&self, piece: Pieces) -> i32 {
+        match piece {
+            Pieces::P => self.pawn,
+            Pieces::N => self.knight,
+            Pieces::B => self.bishop,
+            Pieces::R => self.rook,
+            Pieces::Q => self.queen,
+            _ => 0,
+        }
+    }
+}
+
+pub fn evaluate(game_state: &GameState, params: &EvalParams) -> i32 {
+    // Static evaluation in centipawns from the point of view of the side to move
+    let mut material = [0i32; 2];
+    let mut placement = [0i32; 2];
+    let mut king_middle = [0i32; 2];
+    let mut king_end = [0i32; 2];
+    let mut bishops = [0usize; 2];
+    let mut phase = 0;
+    for i in 0..8 {
+        for j in 0..8 {
+            let piece = game_state.piece_board[i][j];
+            if piece == Pieces::Empty {
+                continue;
+            }
+            let is_white = game_state.white_board[i][j];
+            let side = usize::from(!is_white);
+            // Tables are laid out with rank 8 first, so white reads them upside down
+            let index = if is_white { (7-i)*8+j } else { i*8+j };
+            material[side] += params.piece_value(piece);
+            match piece {
+                Pieces::P => placement[side] += PAWN_TABLE[index],
+                Pieces::N => {
+                    placement[side] += KNIGHT_TABLE[index];
+                    phase += 1;
+                }
+                Pieces::B => {
+                    placement[side] += BISHOP_TABLE[index];
+                    bishops[side] += 1;
+                    phase += 1;
+                }
+                Pieces::R => {
+                    placement[side] += ROOK_TABLE[index];
+                    phase += 2;
+                }
+                Pieces::Q => {
+                    placement[side] += QUEEN_TABLE[index];
+                    phase += 4;
+                }
+                Pieces::K => {
+                    king_middle[side] += KING_MIDDLE_TABLE[index];
+                    king_end[side] += KING_END_TABLE[index];
+                }
+                _ => {}
+            }
+        }
+    }
+    let phase = phase.min(MAX_PHASE);
+    let mut score = [0i32; 2];
+    for side in 0..2 {
+        let king = (king_middle[side]*phase + king_end[side]*(MAX_PHASE-phase)) / MAX_PHASE;
+        score[side] = material[side] + (placement[side] + king) * params.pst_percent / 100;
+        if bishops[side] >= 2 {
+            score[side] += params.bishop_pair;
+        }
+    }
+    let white_score = score[0] - score[1];
+    let relative = if game_state.white_active { white_score } else { -white_score };
+    relative + params.tempo
+}
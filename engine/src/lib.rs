@@ -0,0 +1,19 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use chess_game::game_state::GameState;
+
+pub mod eco;
+pub mod eval;
+pub mod notation;
+pub mod polyglot;
+pub mod search;
+pub mod tablebase;
+pub use eval::{evaluate,EvalParams};
+pub use search::{best_move,Limits,SearchResult,Searcher};
+
+pub fn position_key(game_state: &GameState) -> u64 {
+    // Full 64-bit version of GameState::small_hash
+    let mut s = DefaultHasher::new();
+    game_state.hash(&mut s);
+    s.finish()
+}
@@ -0,0 +1,259 @@
+use std::fmt;
+use chess_game::code_generator::apply_turn;
+use chess_game::game_state::GameState;
+use chess_game::helpers::{Pieces,Turn};
+
+pub const START_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotationError {
+    InvalidFen(&'static str),
+    InvalidMove(String),
+    IllegalMove(String),
+}
+impl fmt::Display for NotationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidFen(reason) => write!(f, "invalid FEN: {}", reason),
+            Self::InvalidMove(text) => write!(f, "invalid move: {}", text),
+            Self::IllegalMove(text) => write!(f, "illegal move: {}", text),
+        }
+    }
+}
+impl std::error::Error for NotationError {}
+
+pub fn piece_letter(piece: Pieces) -> char {
+    match piece {
+        Pieces::R|Pieces::PToR => 'r',
+        Pieces::N|Pieces::PToN => 'n',
+        Pieces::B|Pieces::PToB => 'b',
+        Pieces::Q|Pieces::PToQ => 'q',
+        Pieces::K => 'k',
+        Pieces::P => 'p',
+        Pieces::Empty => ' ',
+    }
+}
+
+pub fn square_name(rank: usize, col: usize) -> String {
+    format!("{}{}", (b'a' + col as u8) as char, rank + 1)
+}
+
+fn parse_square(text: &str) -> Option<(usize, usize)> {
+    let bytes = text.as_bytes();
+    if bytes.len() != 2 || !(b'a'..=b'h').contains(&bytes[0]) || !(b'1'..=b'8').contains(&bytes[1]) {
+        return None;
+    }
+    Some(((bytes[1] - b'1').into(), (bytes[0] - b'a').into()))
+}
+
+pub fn from_fen(fen: &str) -> Result<GameState, NotationError> {
+    let fields: Vec<&str> = fen.split_whitespace().collect();
+    if fields.len() < 4 {
+        return Err(NotationError::InvalidFen("expected at least 4 fields"));
+    }
+    let mut game_state = GameState {
+        piece_board: [[Pieces::Empty; 8]; 8],
+        white_board: [[false; 8]; 8],
+        ..GameState::default()
+    };
+
+    let rows: Vec<&str> = fields[0].split('/').collect();
+    if rows.len() != 8 {
+        return Err(NotationError::InvalidFen("expected 8 ranks"));
+    }
+    for (row_num, row) in rows.iter().enumerate() {
+        let rank = 7 - row_num;
+        let mut col = 0;
+        for c in row.chars() {
+            if let Some(skip) = c.to_digit(10) {
+                col += skip as usize;
+                continue;
+            }
+            if col >= 8 {
+                return Err(NotationError::InvalidFen("rank too long"));
+            }
+            game_state.piece_board[rank][col] = match c.to_ascii_lowercase() {
+                'p' => Pieces::P,
+                'n' => Pieces::N,
+                'b' => Pieces::B,
+                'r' => Pieces::R,
+                'q' => Pieces::Q,
+                'k' => Pieces::K,
+                _ => return Err(NotationError::InvalidFen("unknown piece")),
+            };
+            game_state.white_board[rank][col] = c.is_ascii_uppercase();
+            col += 1;
+        }
+        if col != 8 {
+            return Err(NotationError::InvalidFen("rank has the wrong length"));
+        }
+    }
+
+    game_state.white_active = match fields[1] {
+        "w" => true,
+        "b" => false,
+        _ => return Err(NotationError::InvalidFen("side to move must be w or b")),
+    };
+    game_state.white_castle_king = fields[2].contains('K');
+    game_state.white_castle_queen = fields[2].contains('Q');
+    game_state.black_castle_king = fields[2].contains('k');
+    game_state.black_castle_queen = fields[2].contains('q');
+    game_state.en_passant = match fields[3] {
+        "-" => 64,
+        square => match parse_square(square) {
+            Some((rank, col)) => (rank*8+col) as u8,
+            None => return Err(NotationError::InvalidFen("bad en passant square")),
+        },
+    };
+    game_state.half_moves = match fields.get(4) {
+        Some(half_moves) => half_moves.parse().map_err(|_| NotationError::InvalidFen("bad halfmove clock"))?,
+        None => 0,
+    };
+    Ok(game_state)
+}
+
+pub fn to_fen(game_state: &GameState, full_moves: u16) -> String {
+    let mut fen = String::new();
+    for rank in (0..8).rev() {
+        let mut empty = 0;
+        for col in 0..8 {
+            let piece = game_state.piece_board[rank][col];
+            if piece == Pieces::Empty {
+                empty += 1;
+                continue;
+            }
+            if empty > 0 {
+                fen.push_str(&empty.to_string());
+                empty = 0;
+            }
+            let letter = piece_letter(piece);
+            fen.push(if game_state.white_board[rank][col] { letter.to_ascii_uppercase() } else { letter });
+        }
+        if empty > 0 {
+            fen.push_str(&empty.to_string());
+        }
+        if rank > 0 {
+            fen.push('/');
+        }
+    }
+    fen.push_str(if game_state.white_active { " w " } else { " b " });
+    let mut castling = String::new();
+    for (allowed, letter) in [
+        (game_state.white_castle_king, 'K'), (game_state.white_castle_queen, 'Q'),
+        (game_state.black_castle_king, 'k'), (game_state.black_castle_queen, 'q'),
+    ] {
+        if allowed {
+            castling.push(letter);
+        }
+    }
+    fen.push_str(if castling.is_empty() { "-" } else { &castling });
+    fen.push(' ');
+    if game_state.en_passant < 64 {
+        let en_passant = usize::from(game_state.en_passant);
+        fen.push_str(&square_name(en_passant / 8, en_passant % 8));
+    } else {
+        fen.push('-');
+    }
+    fen.push_str(&format!(" {} {}", game_state.half_moves, full_moves));
+    fen
+}
+
+pub fn to_uci(turn: &Turn) -> String {
+    // Long algebraic notation, e.g. e2e4 or e7e8q
+    let mut text = square_name(turn.from_rank(), turn.from_col()) + &square_name(turn.to_rank(), turn.to_col());
+    match turn.piece() {
+        Pieces::PToR|Pieces::PToN|Pieces::PToB|Pieces::PToQ => text.push(piece_letter(turn.piece())),
+        _ => {}
+    }
+    text
+}
+
+pub fn parse_uci(game_state: &GameState, text: &str) -> Result<Turn, NotationError> {
+    // The piece is read from the board, so the move has to be made from `game_state`
+    let invalid = || NotationError::InvalidMove(text.to_string());
+    if text.len() != 4 && text.len() != 5 {
+        return Err(invalid());
+    }
+    let (from_rank, from_col) = parse_square(text.get(0..2).ok_or_else(invalid)?).ok_or_else(invalid)?;
+    let (to_rank, to_col) = parse_square(text.get(2..4).ok_or_else(invalid)?).ok_or_else(invalid)?;
+    let piece = match (game_state.piece_board[from_rank][from_col], text.get(4..)) {
+        (Pieces::Empty, _) => return Err(NotationError::IllegalMove(text.to_string())),
+        (Pieces::P, Some("q")) => Pieces::PToQ,
+        (Pieces::P, Some("r")) => Pieces::PToR,
+        (Pieces::P, Some("b")) => Pieces::PToB,
+        (Pieces::P, Some("n")) => Pieces::PToN,
+        (piece, Some("")) => piece,
+        _ => return Err(invalid()),
+    };
+    let turn = Turn::new(piece, from_rank, from_col, to_rank, to_col);
+    if apply_turn(game_state, turn).is_none() {
+        return Err(NotationError::IllegalMove(text.to_string()));
+    }
+    Ok(turn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::position_key;
+
+    #[test]
+    fn fen_round_trip() {
+        let game_state = from_fen(START_FEN).unwrap();
+        assert_eq!(to_fen(&GameState::default(), 1), START_FEN);
+        assert_eq!(position_key(&game_state), position_key(&GameState::default()));
+        for fen in [
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+            "8/P7/8/8/8/8/6k1/4K2R b K - 17 42",
+        ] {
+            assert_eq!(to_fen(&from_fen(fen).unwrap(), fen.rsplit(' ').next().unwrap().parse().unwrap()), fen);
+        }
+        let game_state = from_fen("rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w Kq f6 5 3").unwrap();
+        assert_eq!(game_state.piece_board[4][4], Pieces::P);
+        assert!(game_state.white_board[4][4] && !game_state.white_board[4][5]);
+        assert_eq!(game_state.en_passant, 5*8 + 5);
+        assert_eq!(game_state.half_moves, 5);
+        assert!(game_state.white_active && game_state.white_castle_king && !game_state.white_castle_queen);
+        assert!(!game_state.black_castle_king && game_state.black_castle_queen);
+        // The move counters may be left out
+        assert_eq!(to_fen(&from_fen("k7/8/8/8/8/8/8/7K b - -").unwrap(), 1), "k7/8/8/8/8/8/8/7K b - - 0 1");
+    }
+
+    #[test]
+    fn malformed_fen() {
+        for (fen, reason) in [
+            ("8/8/8/8/8/8/8/8 w KQkq", "expected at least 4 fields"),
+            ("8/8/8/8/8/8/8 w - -", "expected 8 ranks"),
+            ("k7/8/8/8/8/8/8/7X w - -", "unknown piece"),
+            ("k7/8/8/8/8/8/8/8K w - -", "rank too long"),
+            ("k7/8/8/8/8/8/8/6K w - -", "rank has the wrong length"),
+            ("k7/8/8/8/8/8/8/7K x - -", "side to move must be w or b"),
+            ("k7/8/8/8/8/8/8/7K w - e9", "bad en passant square"),
+            ("k7/8/8/8/8/8/8/7K w - - x 1", "bad halfmove clock"),
+        ] {
+            assert_eq!(from_fen(fen).err(), Some(NotationError::InvalidFen(reason)), "{}", fen);
+        }
+    }
+
+    #[test]
+    fn uci_moves() {
+        let game_state = from_fen("1r2k2r/P7/8/8/8/8/8/R3K2R w KQk - 0 1").unwrap();
+        let promotion = parse_uci(&game_state, "a7b8n").unwrap();
+        assert_eq!((promotion.piece(), promotion.to_rank(), promotion.to_col()), (Pieces::PToN, 7, 1));
+        assert_eq!(to_uci(&promotion), "a7b8n");
+        assert_eq!(parse_uci(&game_state, "a7a8q").unwrap().piece(), Pieces::PToQ);
+        let castling = parse_uci(&game_state, "e1c1").unwrap();
+        assert_eq!((castling.piece(), castling.from_col(), castling.to_col()), (Pieces::K, 4, 2));
+        assert_eq!(to_uci(&castling), "e1c1");
+        assert_eq!(parse_uci(&game_state, "e1g1").unwrap().to_col(), 6);
+
+        // Badly written, or not playable here
+        for text in ["e1", "e1g1qq", "i1g1", "a7b8k", "e1g1q"] {
+            assert_eq!(parse_uci(&game_state, text), Err(NotationError::InvalidMove(text.to_string())));
+        }
+        for text in ["e2e4", "a1a8", "e1e3"] {
+            assert_eq!(parse_uci(&game_state, text), Err(NotationError::IllegalMove(text.to_string())));
+        }
+    }
+}
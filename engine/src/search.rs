@@ -0,0 +1,563 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use chess_game::code_generator::{apply_turn,candidate_turns};
+use chess_game::game_state::GameState;
+use chess_game::helpers::{Pieces,Turn};
+use crate::eval::{evaluate,EvalParams};
+use crate::position_key;
+
+pub const MATE: i32 = 30_000;
+const INFINITY: i32 = 32_000;
+const MAX_PLY: usize = 64;
+const DEFAULT_HASH_MB: usize = 16;
+const CHECK_EVERY_NODES: u64 = 1024; // Must be a power of two
+const MOVE_OVERHEAD_MS: u64 = 50;
+const DEFAULT_MOVES_TO_GO: u64 = 30;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub depth: Option<u8>,
+    pub nodes: Option<u64>,
+    pub time: Option<Duration>,
+}
+impl Limits {
+    pub fn from_go(args: &[&str], white_active: bool) -> Self {
+        // The arguments of a UCI go command. Without a movetime, the clock of the side to move
+        // sets the time for this move
+        let mut limits = Self::default();
+        let mut clock: [Option<u64>; 2] = [None, None];
+        let mut increment: [u64; 2] = [0, 0];
+        let mut moves_to_go: Option<u64> = None;
+        let mut i = 0;
+        while i < args.len() {
+            let value = args.get(i+1).and_then(|value| value.parse::<u64>().ok());
+            match args[i] {
+                "depth" => limits.depth = value.map(|depth| depth.min(u64::from(u8::MAX)) as u8),
+                "nodes" => limits.nodes = value,
+                "movetime" => limits.time = value.map(Duration::from_millis),
+                "wtime" => clock[0] = value,
+                "btime" => clock[1] = value,
+                "winc" => increment[0] = value.unwrap_or(0),
+                "binc" => increment[1] = value.unwrap_or(0),
+                "movestogo" => moves_to_go = value,
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            }
+            i += 2;
+        }
+        let side = usize::from(!white_active);
+        if let (None, Some(remaining)) = (limits.time, clock[side]) {
+            // Spend an even share of the remaining time plus most of the increment
+            let share = remaining / moves_to_go.unwrap_or(DEFAULT_MOVES_TO_GO).max(1) + increment[side] * 3 / 4;
+            let budget = share.min(remaining / 2).saturating_sub(MOVE_OVERHEAD_MS).max(1);
+            limits.time = Some(Duration::from_millis(budget));
+        }
+        limits
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SearchResult {
+    pub best: Option<Turn>,
+    pub score: i32, // centipawns for the side to move, +-(MATE - plies) for forced mates
+    pub depth: u8,
+    pub nodes: u64,
+    pub elapsed: Duration,
+    pub pv: Vec<Turn>,
+}
+impl SearchResult {
+    pub fn mate_in(&self) -> Option<i32> {
+        // Moves (not plies) until mate, negative if the side to move is getting mated
+        if self.score.abs() < MATE - MAX_PLY as i32 {
+            return None;
+        }
+        let plies = MATE - self.score.abs();
+        let moves = (plies + 1) / 2;
+        Some(if self.score > 0 { moves } else { -moves })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Bound {
+    Exact,
+    Lower,
+    Upper,
+}
+
+#[derive(Clone, Copy)]
+struct TtEntry {
+    key: u64,
+    depth: u8,
+    score: i32,
+    bound: Bound,
+    best: Option<Turn>,
+}
+
+pub struct Searcher {
+    params: EvalParams,
+    table: Vec<Option<TtEntry>>,
+    stop: Arc<AtomicBool>,
+    killers: [[Option<Turn>; 2]; MAX_PLY],
+    history: [[i32; 64]; 64],
+    path: Vec<u64>,
+    nodes: u64,
+    limits: Limits,
+    start: Instant,
+    aborted: bool,
+}
+impl Default for Searcher {
+    fn default() -> Self {
+        Self::new(EvalParams::default(), DEFAULT_HASH_MB)
+    }
+}
+impl Searcher {
+    pub fn new(params: EvalParams, hash_mb: usize) -> Self {
+        let entry_size = std::mem::size_of::<Option<TtEntry>>();
+        let entries = (hash_mb.max(1) * 1024 * 1024 / entry_size).next_power_of_two() / 2;
+        Self {
+            params,
+            table: vec![None; entries.max(1)],
+            stop: Arc::new(AtomicBool::new(false)),
+            killers: [[None; 2]; MAX_PLY],
+            history: [[0; 64]; 64],
+            path: Vec::new(),
+            nodes: 0,
+            limits: Limits::default(),
+            start: Instant::now(),
+            aborted: false,
+        }
+    }
+
+    pub fn params(&self) -> &EvalParams {
+        &self.params
+    }
+
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        // Setting the flag makes a running search return its last completed iteration.
+        // The flag is not cleared by the searcher, so reset it before starting a new search.
+        self.stop.clone()
+    }
+
+    pub fn clear(&mut self) {
+        // Forget everything learned from previous searches (new game)
+        self.table.iter_mut().for_each(|entry| *entry = None);
+        self.history = [[0; 64]; 64];
+    }
+
+    pub fn search(&mut self, game_state: &GameState, past_keys: &[u64], limits: Limits) -> SearchResult {
+        self.search_with(game_state, past_keys, limits, |_| {})
+    }
+
+    pub fn search_with<F: FnMut(&SearchResult)>(&mut self, game_state: &GameState, past_keys: &[u64],
+        limits: Limits, mut report: F) -> SearchResult {
+        // Iterative deepening; `past_keys` are the position keys of the game so far (for repetitions)
+        self.limits = limits;
+        self.start = Instant::now();
+        self.nodes = 0;
+        self.aborted = false;
+        self.killers = [[None; 2]; MAX_PLY];
+        self.path = past_keys.to_vec();
+
+        let mut result = SearchResult::default();
+        let mut root_turns: Vec<Turn> = candidate_turns(game_state).into_iter()
+            .filter(|turn| apply_turn(game_state, *turn).is_some())
+            .collect();
+        if root_turns.is_empty() {
+            result.score = if game_state.is_check(game_state.white_active) { -MATE } else { 0 };
+            return result;
+        }
+        result.best = Some(root_turns[0]);
+        let max_depth = limits.depth.unwrap_or(MAX_PLY as u8 - 4).clamp(1, MAX_PLY as u8 - 4);
+        for depth in 1..=max_depth {
+            let (score, best) = self.root(game_state, &mut root_turns, depth);
+            if self.aborted {
+                break;
+            }
+            result.best = best;
+            result.score = score;
+            result.depth = depth;
+            result.nodes = self.nodes;
+            result.elapsed = self.start.elapsed();
+            result.pv = self.principal_variation(game_state, depth);
+            report(&result);
+
+            if root_turns.len() == 1 && limits.depth.is_none() {
+                break;
+            }
+            if score.abs() >= MATE - i32::from(depth) {
+                break;
+            }
+            if let Some(time) = limits.time {
+                // Another iteration will almost certainly not finish in time
+                if self.start.elapsed() * 2 > time {
+                    break;
+                }
+            }
+        }
+        result.nodes = self.nodes;
+        result.elapsed = self.start.elapsed();
+        result
+    }
+
+    fn root(&mut self, game_state: &GameState, root_turns: &mut [Turn], depth: u8) -> (i32, Option<Turn>) {
+        // Previous best move first, then the usual ordering
+        let tt_best = self.probe(position_key(game_state)).and_then(|entry| entry.best);
+        self.order(game_state, root_turns, tt_best, 0);
+        let mut alpha = -INFINITY;
+        let beta = INFINITY;
+        let mut best: Option<Turn> = None;
+        let key = position_key(game_state);
+        self.path.push(key);
+        for turn in root_turns.iter() {
+            let next_state = apply_turn(game_state, *turn).unwrap();
+            let score = if best.is_none() {
+                -self.negamax(&next_state, depth - 1, -beta, -alpha, 1)
+            } else {
+                let score = -self.negamax(&next_state, depth - 1, -alpha - 1, -alpha, 1);
+                if score > alpha && !self.aborted {
+                    -self.negamax(&next_state, depth - 1, -beta, -alpha, 1)
+                } else {
+                    score
+                }
+            };
+            if self.aborted {
+                break;
+            }
+            if score > alpha || best.is_none() {
+                alpha = score;
+                best = Some(*turn);
+            }
+        }
+        self.path.pop();
+        if !self.aborted {
+            self.store(key, depth, alpha, Bound::Exact, best);
+        }
+        (alpha, best)
+    }
+
+    fn negamax(&mut self, game_state: &GameState, depth: u8, mut alpha: i32, beta: i32, ply: usize) -> i32 {
+        if self.should_stop() {
+            return 0;
+        }
+        let key = position_key(game_state);
+        if self.is_draw(game_state, key) {
+            return 0;
+        }
+        if depth == 0 || ply >= MAX_PLY - 1 {
+            return self.quiesce(game_state, alpha, beta, ply);
+        }
+        self.nodes += 1;
+
+        let original_alpha = alpha;
+        let entry = self.probe(key);
+        if let Some(entry) = entry {
+            if entry.depth >= depth {
+                let score = score_from_table(entry.score, ply);
+                match entry.bound {
+                    Bound::Exact => return score,
+                    Bound::Lower if score >= beta => return score,
+                    Bound::Upper if score <= alpha => return score,
+                    _ => {}
+                }
+            }
+        }
+
+        let in_check = game_state.is_check(game_state.white_active);
+        let mut turns = candidate_turns(game_state);
+        self.order(game_state, &mut turns, entry.and_then(|entry| entry.best), ply);
+
+        self.path.push(key);
+        let mut best_score = -INFINITY;
+        let mut best: Option<Turn> = None;
+        let mut num_legal = 0;
+        for turn in turns {
+            let next_state = match apply_turn(game_state, turn) {
+                Some(next_state) => next_state,
+                None => continue,
+            };
+            num_legal += 1;
+            // Extend checks so forcing lines are not cut off at the horizon
+            let gives_check = next_state.is_check(next_state.white_active);
+            let next_depth = if gives_check { depth } else { depth - 1 };
+            let score = if num_legal == 1 {
+                -self.negamax(&next_state, next_depth, -beta, -alpha, ply + 1)
+            } else {
+                let score = -self.negamax(&next_state, next_depth, -alpha - 1, -alpha, ply + 1);
+                if score > alpha && score < beta && !self.aborted {
+                    -self.negamax(&next_state, next_depth, -beta, -alpha, ply + 1)
+                } else {
+                    score
+                }
+            };
+            if self.aborted {
+                self.path.pop();
+                return 0;
+            }
+            if score > best_score {
+                best_score = score;
+                best = Some(turn);
+            }
+            if score > alpha {
+                alpha = score;
+            }
+            if alpha >= beta {
+                if !is_capture(game_state, &turn) {
+                    self.remember_quiet(&turn, depth, ply);
+                }
+                break;
+            }
+        }
+        self.path.pop();
+
+        if num_legal == 0 {
+            return if in_check { -MATE + ply as i32 } else { 0 };
+        }
+        let bound = if best_score >= beta {
+            Bound::Lower
+        } else if best_score > original_alpha {
+            Bound::Exact
+        } else {
+            Bound::Upper
+        };
+        self.store(key, depth, score_to_table(best_score, ply), bound, best);
+        best_score
+    }
+
+    fn quiesce(&mut self, game_state: &GameState, mut alpha: i32, beta: i32, ply: usize) -> i32 {
+        // Only resolve captures and promotions so the static evaluation is taken in a quiet position
+        self.nodes += 1;
+        let stand_pat = evaluate(game_state, &self.params);
+        if stand_pat >= beta || ply >= MAX_PLY - 1 {
+            return stand_pat;
+        }
+        if stand_pat > alpha {
+            alpha = stand_pat;
+        }
+        let mut turns: Vec<Turn> = candidate_turns(game_state).into_iter()
+            .filter(|turn| is_capture(game_state, turn) || turn.piece() == Pieces::PToQ)
+            .collect();
+        self.order(game_state, &mut turns, None, ply);
+        for turn in turns {
+            let next_state = match apply_turn(game_state, turn) {
+                Some(next_state) => next_state,
+                None => continue,
+            };
+            let score = -self.quiesce(&next_state, -beta, -alpha, ply + 1);
+            if self.should_stop() {
+                return 0;
+            }
+            if score >= beta {
+                return score;
+            }
+            if score > alpha {
+                alpha = score;
+            }
+        }
+        alpha
+    }
+
+    fn order(&self, game_state: &GameState, turns: &mut [Turn], tt_best: Option<Turn>, ply: usize) {
+        let killers = self.killers[ply.min(MAX_PLY - 1)];
+        turns.sort_by_cached_key(|turn| {
+            let priority = if Some(*turn) == tt_best {
+                1_000_000
+            } else if is_capture(game_state, turn) {
+                // Most valuable victim, least valuable attacker
+                let victim = match game_state.piece_board[turn.to_rank()][turn.to_col()] {
+                    Pieces::Empty => self.params.pawn, // En passant
+                    victim => self.params.piece_value(victim),
+                };
+                let attacker = self.params.piece_value(board_piece(turn));
+                100_000 + victim * 10 - attacker / 10
+            } else if turn.piece() == Pieces::PToQ {
+                90_000
+            } else if Some(*turn) == killers[0] {
+                80_000
+            } else if Some(*turn) == killers[1] {
+                79_000
+            } else {
+                self.history[square(turn.from_rank(), turn.from_col())][square(turn.to_rank(), turn.to_col())]
+                    .min(70_000)
+            };
+            -priority
+        });
+    }
+
+    fn remember_quiet(&mut self, turn: &Turn, depth: u8, ply: usize) {
+        let ply = ply.min(MAX_PLY - 1);
+        if self.killers[ply][0] != Some(*turn) {
+            self.killers[ply][1] = self.killers[ply][0];
+            self.killers[ply][0] = Some(*turn);
+        }
+        let from = square(turn.from_rank(), turn.from_col());
+        let to = square(turn.to_rank(), turn.to_col());
+        self.history[from][to] += i32::from(depth) * i32::from(depth);
+    }
+
+    fn is_draw(&self, game_state: &GameState, key: u64) -> bool {
+        // Any repetition inside the search is scored as a draw, which is what a repeat would lead to
+        game_state.half_moves >= 100 ||
+            game_state.is_insufficient_mat() ||
+            self.path.iter().rev().take(usize::from(game_state.half_moves) + 1).any(|past| *past == key)
+    }
+
+    fn should_stop(&mut self) -> bool {
+        if self.aborted {
+            return true;
+        }
+        if self.nodes & (CHECK_EVERY_NODES - 1) == 0 {
+            let out_of_time = self.limits.time.is_some_and(|time| self.start.elapsed() >= time);
+            let out_of_nodes = self.limits.nodes.is_some_and(|nodes| self.nodes >= nodes);
+            if out_of_time || out_of_nodes || self.stop.load(Ordering::Relaxed) {
+                self.aborted = true;
+            }
+        }
+        self.aborted
+    }
+
+    fn probe(&self, key: u64) -> Option<TtEntry> {
+        let index = (key as usize) & (self.table.len() - 1);
+        self.table[index].filter(|entry| entry.key == key)
+    }
+
+    fn store(&mut self, key: u64, depth: u8, score: i32, bound: Bound, best: Option<Turn>) {
+        let index = (key as usize) & (self.table.len() - 1);
+        // Depth-preferred replacement, but always refresh the same position
+        let replace = match self.table[index] {
+            Some(entry) => entry.key == key || entry.depth <= depth,
+            None => true,
+        };
+        if replace {
+            self.table[index] = Some(TtEntry { key, depth, score, bound, best });
+        }
+    }
+
+    fn principal_variation(&self, game_state: &GameState, depth: u8) -> Vec<Turn> {
+        let mut pv = Vec::new();
+        let mut seen = Vec::new();
+        let mut state = game_state.clone();
+        while pv.len() < usize::from(depth) {
+            let key = position_key(&state);
+            if seen.contains(&key) {
+                break;
+            }
+            seen.push(key);
+            let turn = match self.probe(key).and_then(|entry| entry.best) {
+                Some(turn) => turn,
+                None => break,
+            };
+            state = match apply_turn(&state, turn) {
+                Some(next_state) => next_state,
+                None => break,
+            };
+            pv.push(turn);
+        }
+        pv
+    }
+}
+
+pub fn best_move(game_state: &GameState, limits: Limits) -> Turn {
+    // Returns Turn::default() when the side to move has no legal moves
+    Searcher::default().search(game_state, &[], limits).best.unwrap_or_default()
+}
+
+pub fn is_capture(game_state: &GameState, turn: &Turn) -> bool {
+    game_state.piece_board[turn.to_rank()][turn.to_col()] != Pieces::Empty ||
+        (turn.piece().is_pawn() && turn.from_col() != turn.to_col())
+}
+
+fn board_piece(turn: &Turn) -> Pieces {
+    if turn.piece().is_pawn() { Pieces::P } else { turn.piece() }
+}
+
+fn square(rank: usize, col: usize) -> usize {
+    rank*8+col
+}
+
+fn score_to_table(score: i32, ply: usize) -> i32 {
+    // Mate scores are stored relative to the node so they stay valid at other plies
+    if score >= MATE - MAX_PLY as i32 {
+        score + ply as i32
+    } else if score <= -MATE + MAX_PLY as i32 {
+        score - ply as i32
+    } else {
+        score
+    }
+}
+
+fn score_from_table(score: i32, ply: usize) -> i32 {
+    if score >= MATE - MAX_PLY as i32 {
+        score - ply as i32
+    } else if score <= -MATE + MAX_PLY as i32 {
+        score + ply as i32
+    } else {
+        score
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notation::{from_fen,START_FEN};
+
+    fn perft(game_state: &GameState, depth: u8) -> u64 {
+        if depth == 0 {
+            return 1;
+        }
+        candidate_turns(game_state).into_iter()
+            .filter_map(|turn| apply_turn(game_state, turn))
+            .map(|next_state| perft(&next_state, depth - 1))
+            .sum()
+    }
+
+    #[test]
+    fn perft_start_position() {
+        let game_state = from_fen(START_FEN).unwrap();
+        let counts: Vec<u64> = (1..=4).map(|depth| perft(&game_state, depth)).collect();
+        assert_eq!(counts, [20, 400, 8_902, 197_281]);
+    }
+
+    #[test]
+    fn perft_castling_and_en_passant() {
+        // "Kiwipete", which exercises castling, en passant and promotions
+        let game_state = from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1").unwrap();
+        assert_eq!(perft(&game_state, 1), 48);
+        assert_eq!(perft(&game_state, 2), 2_039);
+    }
+
+    #[test]
+    fn finds_mate_in_two() {
+        // 1. Kb6 Kb8 2. Rh8#
+        let game_state = from_fen("k7/8/2K5/8/8/8/8/7R w - - 0 1").unwrap();
+        let result = Searcher::default().search(&game_state, &[], Limits { depth: Some(5), ..Limits::default() });
+        assert_eq!(result.mate_in(), Some(2));
+        assert_eq!(result.pv.len(), 3);
+
+        let game_state = apply_turn(&game_state, result.best.unwrap()).unwrap();
+        let result = Searcher::default().search(&game_state, &[], Limits { depth: Some(4), ..Limits::default() });
+        assert_eq!(result.mate_in(), Some(-1));
+    }
+
+    #[test]
+    fn go_time_budget() {
+        let millis = |limits: Limits| limits.time.map(|time| time.as_millis());
+        // A thirtieth of the clock of the side to move, less the overhead
+        assert_eq!(millis(Limits::from_go(&["wtime", "60000", "btime", "30000"], true)), Some(1950));
+        assert_eq!(millis(Limits::from_go(&["wtime", "60000", "btime", "30000"], false)), Some(950));
+        // Three quarters of the increment on top
+        assert_eq!(millis(Limits::from_go(&["wtime", "60000", "winc", "2000"], true)), Some(3450));
+        // A single move to go may use at most half of the clock
+        assert_eq!(millis(Limits::from_go(&["wtime", "10000", "movestogo", "1"], true)), Some(4950));
+        // Almost out of time it still searches for a millisecond
+        assert_eq!(millis(Limits::from_go(&["wtime", "20"], true)), Some(1));
+        // A movetime wins over the clock, and without either there is no time limit
+        assert_eq!(millis(Limits::from_go(&["wtime", "60000", "movetime", "500"], true)), Some(500));
+        assert_eq!(millis(Limits::from_go(&["btime", "60000"], true)), None);
+
+        let limits = Limits::from_go(&["infinite", "depth", "300", "nodes", "1000"], true);
+        assert_eq!((limits.depth, limits.nodes, limits.time), (Some(u8::MAX), Some(1000), None));
+    }
+}
@@ -0,0 +1,877 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use chess_game::code_generator::{apply_turn,candidate_turns};
+use chess_game::game_state::GameState;
+use chess_game::helpers::{Pieces,Turn};
+use crate::polyglot::can_capture_en_passant;
+
+pub const MAX_PIECES: usize = 4; // Kings included
+
+// Table entries: DRAW, ILLEGAL, or the distance to mate in plies plus one.
+// An odd distance is a win for the side to move, an even one a loss (0 = checkmated).
+const DRAW: u8 = 0;
+const UNKNOWN: u8 = 254;
+const ILLEGAL: u8 = 255;
+const MAX_DTM: u8 = 252;
+
+const MAGIC: &[u8; 4] = b"MCTB";
+const VERSION: u8 = 1;
+const KIND_DTM: u8 = 0;
+const KIND_WDL: u8 = 1;
+const WDL_WIN: u8 = 1;
+const WDL_LOSS: u8 = 2;
+
+// Without pawns the white king is moved into the a1-d1-d4 triangle by symmetry
+const TRIANGLE: [(usize, usize); 10] = [
+    (0,0), (0,1), (0,2), (0,3), (1,1), (1,2), (1,3), (2,2), (2,3), (3,3),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wdl {
+    Loss,
+    Draw,
+    Win,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dtm {
+    Loss(u8), // Plies until the side to move is mated
+    Draw,
+    Win(u8), // Plies until the side to move mates
+}
+impl Dtm {
+    pub fn wdl(&self) -> Wdl {
+        match self {
+            Self::Loss(_) => Wdl::Loss,
+            Self::Draw => Wdl::Draw,
+            Self::Win(_) => Wdl::Win,
+        }
+    }
+    fn from_entry(entry: u8) -> Option<Self> {
+        match entry {
+            DRAW => Some(Self::Draw),
+            UNKNOWN|ILLEGAL => None,
+            _ if (entry - 1) % 2 == 1 => Some(Self::Win(entry - 1)),
+            _ => Some(Self::Loss(entry - 1)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TablebaseError {
+    InvalidMaterial(String),
+    BadFile(&'static str),
+    Io(io::Error),
+}
+impl fmt::Display for TablebaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidMaterial(name) => write!(f, "invalid material {} (e.g. KQvK, at most {} pieces)", name, MAX_PIECES),
+            Self::BadFile(reason) => write!(f, "bad tablebase file: {}", reason),
+            Self::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+impl std::error::Error for TablebaseError {}
+impl From<io::Error> for TablebaseError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Material {
+    white: Vec<Pieces>, // Pieces besides the king, strongest first
+    black: Vec<Pieces>,
+}
+impl Material {
+    pub fn parse(name: &str) -> Result<Self, TablebaseError> {
+        let invalid = || TablebaseError::InvalidMaterial(name.to_string());
+        let (white, black) = name.split_once('v').ok_or_else(invalid)?;
+        let side = |text: &str| -> Result<Vec<Pieces>, TablebaseError> {
+            let rest = text.strip_prefix('K').ok_or_else(invalid)?;
+            let mut pieces = rest.chars().map(|c| match c {
+                'Q' => Ok(Pieces::Q),
+                'R' => Ok(Pieces::R),
+                'B' => Ok(Pieces::B),
+                'N' => Ok(Pieces::N),
+                'P' => Ok(Pieces::P),
+                _ => Err(invalid()),
+            }).collect::<Result<Vec<Pieces>, TablebaseError>>()?;
+            pieces.sort_by_key(piece_order);
+            Ok(pieces)
+        };
+        let material = Self { white: side(white)?, black: side(black)? };
+        if material.num_pieces() > MAX_PIECES {
+            return Err(invalid());
+        }
+        Ok(material)
+    }
+
+    pub fn of(game_state: &GameState) -> Option<Self> {
+        let mut material = Self { white: Vec::new(), black: Vec::new() };
+        let mut kings = [0, 0];
+        for i in 0..8 {
+            for j in 0..8 {
+                let piece = game_state.piece_board[i][j];
+                let is_white = game_state.white_board[i][j];
+                match piece {
+                    Pieces::Empty => {}
+                    Pieces::K => kings[usize::from(!is_white)] += 1,
+                    _ if is_white => material.white.push(piece),
+                    _ => material.black.push(piece),
+                }
+            }
+        }
+        if kings != [1, 1] || material.num_pieces() > MAX_PIECES {
+            return None;
+        }
+        material.white.sort_by_key(piece_order);
+        material.black.sort_by_key(piece_order);
+        Some(material)
+    }
+
+    pub fn name(&self) -> String {
+        let side = |pieces: &[Pieces]| -> String {
+            std::iter::once('K').chain(pieces.iter().map(|piece| piece_letter(*piece))).collect()
+        };
+        format!("{}v{}", side(&self.white), side(&self.black))
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        2 + self.white.len() + self.black.len()
+    }
+
+    fn has_pawns(&self) -> bool {
+        self.white.iter().chain(self.black.iter()).any(|piece| *piece == Pieces::P)
+    }
+
+    fn flipped(&self) -> Self {
+        Self { white: self.black.clone(), black: self.white.clone() }
+    }
+
+    fn is_canonical(&self) -> bool {
+        // Tables are only built with the stronger side as white
+        side_key(&self.white) >= side_key(&self.black)
+    }
+
+    fn canonical(&self) -> (Self, bool) {
+        if self.is_canonical() {
+            (self.clone(), false)
+        } else {
+            (self.flipped(), true)
+        }
+    }
+
+    fn children(&self) -> Vec<Self> {
+        // Material reachable by one capture or promotion
+        let mut children = Vec::new();
+        for white_side in [true, false] {
+            let pieces = if white_side { &self.white } else { &self.black };
+            for (i, piece) in pieces.iter().enumerate() {
+                let mut captured = self.clone();
+                let side = if white_side { &mut captured.white } else { &mut captured.black };
+                side.remove(i);
+                children.push(captured);
+                if *piece == Pieces::P {
+                    for promotion in [Pieces::Q, Pieces::R, Pieces::B, Pieces::N] {
+                        let mut promoted = self.clone();
+                        let side = if white_side { &mut promoted.white } else { &mut promoted.black };
+                        side[i] = promotion;
+                        side.sort_by_key(piece_order);
+                        children.push(promoted);
+                    }
+                }
+            }
+        }
+        children
+    }
+
+    fn extra_pieces(&self) -> impl Iterator<Item = (Pieces, bool)> + '_ {
+        self.white.iter().map(|piece| (*piece, true)).chain(self.black.iter().map(|piece| (*piece, false)))
+    }
+
+    fn king_slots(&self) -> usize {
+        if self.has_pawns() { 32 } else { TRIANGLE.len() }
+    }
+
+    fn table_size(&self) -> usize {
+        // white king slot * black king * other pieces * side to move
+        self.king_slots() * 64usize.pow(self.num_pieces() as u32 - 1) * 2
+    }
+
+    fn decode(&self, index: usize) -> Option<GameState> {
+        let mut game_state = GameState {
+            piece_board: [[Pieces::Empty; 8]; 8],
+            white_board: [[false; 8]; 8],
+            en_passant: 64,
+            white_active: index & 1 == 0,
+            white_castle_king: false,
+            white_castle_queen: false,
+            black_castle_king: false,
+            black_castle_queen: false,
+            half_moves: 0,
+        };
+        let mut rest = index / 2;
+        let mut squares = Vec::with_capacity(self.num_pieces());
+        for _ in 1..self.num_pieces() {
+            squares.push(rest % 64);
+            rest /= 64;
+        }
+        squares.reverse();
+        let (king_rank, king_col) = if self.has_pawns() { (rest / 4, rest % 4) } else { TRIANGLE[rest] };
+        let pieces = std::iter::once((Pieces::K, true)).chain(std::iter::once((Pieces::K, false))).chain(self.extra_pieces());
+        for (square, (piece, is_white)) in std::iter::once(king_rank*8+king_col).chain(squares).zip(pieces) {
+            let (rank, col) = (square / 8, square % 8);
+            if game_state.piece_board[rank][col] != Pieces::Empty || (piece == Pieces::P && (rank == 0 || rank == 7)) {
+                return None;
+            }
+            game_state.piece_board[rank][col] = piece;
+            game_state.white_board[rank][col] = is_white;
+        }
+        // The side that just moved cannot be left in check (this also rules out touching kings)
+        if game_state.is_check(!game_state.white_active) {
+            return None;
+        }
+        Some(game_state)
+    }
+
+    fn encode(&self, game_state: &GameState) -> Option<usize> {
+        // None when the position does not have exactly this material, with white as in the table.
+        // The smallest index over the symmetries, so positions equal by symmetry share an index.
+        let kinds: Vec<(Pieces, bool)> = [(Pieces::K, true), (Pieces::K, false)].into_iter().chain(self.extra_pieces()).collect();
+        let mut squares = Vec::with_capacity(kinds.len());
+        for (piece, is_white) in kinds.iter().copied() {
+            let square = find_piece(game_state, piece, is_white, &squares)?;
+            squares.push(square);
+        }
+        let symmetries: &[u8] = if self.has_pawns() { &[0, 1] } else { &[0, 1, 2, 3, 4, 5, 6, 7] };
+        let index = symmetries.iter().filter_map(|symmetry| self.encode_with(&kinds, &squares, *symmetry)).min()?;
+        Some(index*2 + usize::from(!game_state.white_active))
+    }
+
+    fn encode_with(&self, kinds: &[(Pieces, bool)], squares: &[(usize, usize)], symmetry: u8) -> Option<usize> {
+        // The pieces of kinds on squares, seen through symmetry, white king first
+        let (king_rank, king_col) = transform(symmetry, squares[0]);
+        if !in_king_region((king_rank, king_col), self.has_pawns()) {
+            return None;
+        }
+        let king_index = if self.has_pawns() {
+            king_rank*4+king_col
+        } else {
+            TRIANGLE.iter().position(|square| *square == (king_rank, king_col)).unwrap()
+        };
+        // Identical pieces in square order, whichever was found first
+        let mut others: Vec<(usize, usize)> = kinds.iter().zip(squares).skip(1).map(|(kind, square)| {
+            let (rank, col) = transform(symmetry, *square);
+            (kinds.iter().position(|other| other == kind).unwrap(), rank*8+col)
+        }).collect();
+        others.sort_unstable();
+        Some(others.iter().fold(king_index, |index, (_, square)| index*64 + square))
+    }
+}
+
+fn piece_order(piece: &Pieces) -> usize {
+    match piece {
+        Pieces::Q => 0,
+        Pieces::R => 1,
+        Pieces::B => 2,
+        Pieces::N => 3,
+        _ => 4,
+    }
+}
+
+fn piece_letter(piece: Pieces) -> char {
+    match piece {
+        Pieces::Q => 'Q',
+        Pieces::R => 'R',
+        Pieces::B => 'B',
+        Pieces::N => 'N',
+        _ => 'P',
+    }
+}
+
+fn side_key(pieces: &[Pieces]) -> (u32, usize, String) {
+    let value = pieces.iter().map(|piece| match piece {
+        Pieces::Q => 9,
+        Pieces::R => 5,
+        Pieces::B|Pieces::N => 3,
+        _ => 1,
+    }).sum();
+    (value, pieces.len(), pieces.iter().map(|piece| piece_letter(*piece)).collect())
+}
+
+fn transform(symmetry: u8, (rank, col): (usize, usize)) -> (usize, usize) {
+    // bit 0 mirrors files, bit 1 mirrors ranks, bit 2 mirrors along the a1-h8 diagonal
+    let col = if symmetry & 1 != 0 { 7 - col } else { col };
+    let rank = if symmetry & 2 != 0 { 7 - rank } else { rank };
+    if symmetry & 4 != 0 { (col, rank) } else { (rank, col) }
+}
+
+fn in_king_region((rank, col): (usize, usize), has_pawns: bool) -> bool {
+    if has_pawns { col < 4 } else { col < 4 && rank <= col }
+}
+
+fn find_piece(game_state: &GameState, piece: Pieces, is_white: bool, used: &[(usize, usize)]) -> Option<(usize, usize)> {
+    for i in 0..8 {
+        for j in 0..8 {
+            if game_state.piece_board[i][j] == piece && game_state.white_board[i][j] == is_white && !used.contains(&(i, j)) {
+                return Some((i, j));
+            }
+        }
+    }
+    None
+}
+
+fn flip_colors(game_state: &GameState) -> GameState {
+    // Mirror the ranks and swap the colors, which keeps the value for the side to move
+    let mut flipped = game_state.clone();
+    for i in 0..8 {
+        for j in 0..8 {
+            flipped.piece_board[7-i][j] = game_state.piece_board[i][j];
+            flipped.white_board[7-i][j] = game_state.piece_board[i][j] != Pieces::Empty && !game_state.white_board[i][j];
+        }
+    }
+    flipped.white_active = !game_state.white_active;
+    flipped.en_passant = 64;
+    flipped
+}
+
+enum TableData {
+    Dtm(Vec<u8>),
+    Wdl(Vec<u8>), // Four entries per byte
+}
+
+struct Table {
+    material: Material,
+    data: TableData,
+}
+impl Table {
+    fn probe(&self, game_state: &GameState) -> Option<Dtm> {
+        match &self.data {
+            TableData::Dtm(entries) => Dtm::from_entry(entries[self.material.encode(game_state)?]),
+            TableData::Wdl(_) => None,
+        }
+    }
+
+    fn probe_wdl(&self, game_state: &GameState) -> Option<Wdl> {
+        let index = self.material.encode(game_state)?;
+        match &self.data {
+            TableData::Dtm(entries) => Dtm::from_entry(entries[index]).map(|dtm| dtm.wdl()),
+            TableData::Wdl(packed) => match (packed[index / 4] >> (2 * (index % 4))) & 0b11 {
+                WDL_WIN => Some(Wdl::Win),
+                WDL_LOSS => Some(Wdl::Loss),
+                _ => Some(Wdl::Draw),
+            },
+        }
+    }
+
+    fn pack_wdl(entries: &[u8]) -> Vec<u8> {
+        let mut packed = vec![0u8; entries.len().div_ceil(4)];
+        for (index, entry) in entries.iter().enumerate() {
+            let wdl = match Dtm::from_entry(*entry) {
+                Some(Dtm::Win(_)) => WDL_WIN,
+                Some(Dtm::Loss(_)) => WDL_LOSS,
+                _ => 0,
+            };
+            packed[index / 4] |= wdl << (2 * (index % 4));
+        }
+        packed
+    }
+
+    fn write(&self, dir: &Path) -> io::Result<()> {
+        let name = self.material.name();
+        if let TableData::Dtm(entries) = &self.data {
+            write_file(&dir.join(format!("{}.dtm", name)), KIND_DTM, &name, entries)?;
+            write_file(&dir.join(format!("{}.wdl", name)), KIND_WDL, &name, &Self::pack_wdl(entries))?;
+        } else if let TableData::Wdl(packed) = &self.data {
+            write_file(&dir.join(format!("{}.wdl", name)), KIND_WDL, &name, packed)?;
+        }
+        Ok(())
+    }
+
+    fn read(path: &Path) -> Result<Self, TablebaseError> {
+        // magic (4 bytes) | version | kind | name length | name | entries
+        let bytes = fs::read(path)?;
+        if bytes.len() < 7 || &bytes[0..4] != MAGIC || bytes[4] != VERSION {
+            return Err(TablebaseError::BadFile("unknown header"));
+        }
+        let name_end = 7 + usize::from(bytes[6]);
+        let name = bytes.get(7..name_end).and_then(|name| std::str::from_utf8(name).ok())
+            .ok_or(TablebaseError::BadFile("bad material name"))?;
+        let material = Material::parse(name)?;
+        let entries = bytes[name_end..].to_vec();
+        let data = match bytes[5] {
+            KIND_DTM if entries.len() == material.table_size() => TableData::Dtm(entries),
+            KIND_WDL if entries.len() == material.table_size().div_ceil(4) => TableData::Wdl(entries),
+            _ => return Err(TablebaseError::BadFile("size does not match material")),
+        };
+        Ok(Self { material, data })
+    }
+}
+
+fn write_file(path: &Path, kind: u8, name: &str, entries: &[u8]) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(7 + name.len() + entries.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&[VERSION, kind, name.len() as u8]);
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.extend_from_slice(entries);
+    fs::write(path, bytes)
+}
+
+#[derive(Default)]
+pub struct Tablebase {
+    tables: HashMap<Material, Table>,
+}
+impl Tablebase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load_dir<P: AsRef<Path>>(dir: P) -> Result<Self, TablebaseError> {
+        // Loads every .dtm and .wdl file, preferring DTM when both exist for the same material
+        let mut tablebase = Self::new();
+        let mut paths: Vec<_> = fs::read_dir(dir)?.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect();
+        paths.sort_by_key(|path| path.extension() != Some(OsStr::new("dtm")));
+        for path in paths {
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("dtm") | Some("wdl") => {
+                    let table = Table::read(&path)?;
+                    tablebase.tables.entry(table.material.clone()).or_insert(table);
+                }
+                _ => {}
+            }
+        }
+        Ok(tablebase)
+    }
+
+    pub fn write_dir<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        fs::create_dir_all(dir.as_ref())?;
+        for table in self.tables.values() {
+            table.write(dir.as_ref())?;
+        }
+        Ok(())
+    }
+
+    pub fn materials(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.tables.keys().map(|material| material.name()).collect();
+        names.sort();
+        names
+    }
+
+    pub fn generate(&mut self, name: &str) -> Result<(), TablebaseError> {
+        // Builds the table for `name` (e.g. "KRvK") and every smaller table it converts into
+        let (material, _) = Material::parse(name)?.canonical();
+        self.generate_material(&material);
+        Ok(())
+    }
+
+    fn generate_material(&mut self, material: &Material) {
+        // A table loaded from a .wdl file has no distances, so it cannot be used for retrograde
+        // analysis of its parents and is rebuilt
+        if matches!(self.tables.get(material), Some(Table { data: TableData::Dtm(_), .. })) {
+            return;
+        }
+        for child in material.children() {
+            let (child, _) = child.canonical();
+            if !is_trivial_draw(&child) {
+                self.generate_material(&child);
+            }
+        }
+        let entries = self.retrograde(material);
+        self.tables.insert(material.clone(), Table { material: material.clone(), data: TableData::Dtm(entries) });
+    }
+
+    fn retrograde(&self, material: &Material) -> Vec<u8> {
+        // Retrograde analysis: one forward scan finds the mates and counts the moves of every
+        // position, then positions are settled in order of distance, each one passing its
+        // result back to the positions it can be reached from
+        let size = material.table_size();
+        let scans = parallel_map(&(0..size as u32).collect::<Vec<u32>>(), |index| self.scan(material, index as usize));
+        let mut entries = vec![UNKNOWN; size];
+        let mut moves_left = vec![0u8; size];
+        let mut levels: Vec<Vec<usize>> = vec![Vec::new(); usize::from(MAX_DTM) + 1];
+        for (index, scan) in scans.iter().enumerate() {
+            match *scan {
+                Scan::Illegal => entries[index] = ILLEGAL,
+                Scan::Stalemate => entries[index] = DRAW,
+                Scan::Checkmated => levels[0].push(index),
+                Scan::Open { moves, best_exit, lose_exit } => {
+                    moves_left[index] = moves;
+                    if let Some(plies) = best_exit {
+                        levels[usize::from(plies)].push(index);
+                    } else if let (0, Some(plies)) = (moves, lose_exit) {
+                        levels[usize::from(plies)].push(index);
+                    }
+                }
+                Scan::Duplicate(_) => {}
+            }
+        }
+
+        for plies in 0..=MAX_DTM {
+            let level = std::mem::take(&mut levels[usize::from(plies)]);
+            for index in level {
+                if entries[index] != UNKNOWN {
+                    continue;
+                }
+                entries[index] = plies + 1;
+                if plies == MAX_DTM {
+                    continue;
+                }
+                for predecessor in predecessors(material, index) {
+                    if entries[predecessor] != UNKNOWN {
+                        continue;
+                    }
+                    if plies % 2 == 0 {
+                        // A move into a loss wins
+                        levels[usize::from(plies + 1)].push(predecessor);
+                        continue;
+                    }
+                    // Lost once every move reaches a win, at the distance of the longest one
+                    moves_left[predecessor] -= 1;
+                    if let (0, Scan::Open { lose_exit: Some(exit), .. }) = (moves_left[predecessor], scans[predecessor]) {
+                        levels[usize::from(exit.max(plies + 1))].push(predecessor);
+                    }
+                }
+            }
+        }
+        for entry in entries.iter_mut().filter(|entry| **entry == UNKNOWN) {
+            *entry = DRAW;
+        }
+        for (index, scan) in scans.iter().enumerate() {
+            if let Scan::Duplicate(canonical) = scan {
+                entries[index] = entries[*canonical];
+            }
+        }
+        entries
+    }
+
+    fn scan(&self, material: &Material, index: usize) -> Scan {
+        let game_state = match material.decode(index) {
+            Some(game_state) => game_state,
+            None => return Scan::Illegal,
+        };
+        match material.encode(&game_state) {
+            Some(canonical) if canonical != index => return Scan::Duplicate(canonical),
+            _ => {}
+        }
+        let mut children = Vec::new();
+        let mut has_move = false;
+        let (mut best_exit, mut lose_exit): (Option<u8>, Option<u8>) = (None, Some(0));
+        for next_state in candidate_turns(&game_state).into_iter().filter_map(|turn| apply_turn(&game_state, turn)) {
+            has_move = true;
+            if Material::of(&next_state).as_ref() == Some(material) {
+                children.extend(material.encode(&next_state));
+                continue;
+            }
+            // A capture or promotion, already solved in a smaller table
+            match self.probe_dtm(&next_state) {
+                Some(Dtm::Loss(plies)) => best_exit = Some(best_exit.map_or(plies + 1, |best| best.min(plies + 1))),
+                Some(Dtm::Win(plies)) => lose_exit = lose_exit.map(|longest| longest.max(plies + 1)),
+                _ => lose_exit = None,
+            }
+        }
+        if !has_move {
+            return if game_state.is_check(game_state.white_active) { Scan::Checkmated } else { Scan::Stalemate };
+        }
+        children.sort_unstable();
+        children.dedup();
+        Scan::Open { moves: children.len() as u8, best_exit, lose_exit }
+    }
+
+    pub fn probe_dtm(&self, game_state: &GameState) -> Option<Dtm> {
+        // None when the position is outside the loaded tables (castling rights and capturable
+        // en passant squares are not covered; the fifty-move rule is ignored)
+        if game_state.white_castle_king || game_state.white_castle_queen ||
+            game_state.black_castle_king || game_state.black_castle_queen ||
+            can_capture_en_passant(game_state) {
+            return None;
+        }
+        if game_state.is_insufficient_mat() {
+            return Some(Dtm::Draw);
+        }
+        let (material, flipped) = Material::of(game_state)?.canonical();
+        let table = self.tables.get(&material)?;
+        if flipped {
+            table.probe(&flip_colors(game_state))
+        } else {
+            table.probe(game_state)
+        }
+    }
+
+    pub fn probe_wdl(&self, game_state: &GameState) -> Option<Wdl> {
+        if game_state.white_castle_king || game_state.white_castle_queen ||
+            game_state.black_castle_king || game_state.black_castle_queen ||
+            can_capture_en_passant(game_state) {
+            return None;
+        }
+        if game_state.is_insufficient_mat() {
+            return Some(Wdl::Draw);
+        }
+        let (material, flipped) = Material::of(game_state)?.canonical();
+        let table = self.tables.get(&material)?;
+        if flipped {
+            table.probe_wdl(&flip_colors(game_state))
+        } else {
+            table.probe_wdl(game_state)
+        }
+    }
+
+    pub fn best_move(&self, game_state: &GameState) -> Option<Turn> {
+        // Perfect play: mate as fast as possible, hold the draw, or delay mate as long as possible
+        let mut best: Option<(Turn, i32)> = None;
+        for turn in candidate_turns(game_state) {
+            let next_state = match apply_turn(game_state, turn) {
+                Some(next_state) => next_state,
+                None => continue,
+            };
+            let score = match self.probe_dtm(&next_state)? {
+                Dtm::Loss(plies) => 1000 - i32::from(plies),
+                Dtm::Draw => 0,
+                Dtm::Win(plies) => -1000 + i32::from(plies),
+            };
+            match best {
+                Some((_, best_score)) if best_score >= score => {}
+                _ => best = Some((turn, score)),
+            }
+        }
+        best.map(|(turn, _)| turn)
+    }
+}
+
+// A position as found by the forward scan of retrograde analysis
+#[derive(Clone, Copy)]
+enum Scan {
+    Illegal,
+    Duplicate(usize), // the same position by symmetry as this index
+    Checkmated,
+    Stalemate,
+    // moves: distinct positions of the table reachable in one move. best_exit: shortest win by a
+    // capture or promotion. lose_exit: longest loss by one when every capture or promotion loses.
+    Open { moves: u8, best_exit: Option<u8>, lose_exit: Option<u8> },
+}
+
+fn predecessors(material: &Material, index: usize) -> Vec<usize> {
+    // Positions that reach this one by a move that neither captures nor promotes
+    let game_state = material.decode(index).unwrap();
+    let mut mover = game_state.clone();
+    mover.white_active = !game_state.white_active;
+    let mut unmoves: Vec<((usize, usize), (usize, usize))> = candidate_turns(&mover).into_iter()
+        .filter(|turn| !turn.piece().is_pawn() && (turn.piece() != Pieces::K || turn.from_col().abs_diff(turn.to_col()) != 2))
+        .filter(|turn| game_state.piece_board[turn.to_rank()][turn.to_col()] == Pieces::Empty)
+        .map(|turn| ((turn.from_rank(), turn.from_col()), (turn.to_rank(), turn.to_col())))
+        .collect();
+    // Pawns step back, or two steps back onto their starting rank
+    let (start, forward): (usize, isize) = if mover.white_active { (1, 1) } else { (6, -1) };
+    for rank in 1..7 {
+        for col in 0..8 {
+            if game_state.piece_board[rank][col] != Pieces::P || game_state.white_board[rank][col] != mover.white_active {
+                continue;
+            }
+            let back = |steps: isize| (rank as isize - forward * steps) as usize;
+            if rank != start && game_state.piece_board[back(1)][col] == Pieces::Empty {
+                unmoves.push(((rank, col), (back(1), col)));
+                if back(2) == start && game_state.piece_board[back(2)][col] == Pieces::Empty {
+                    unmoves.push(((rank, col), (back(2), col)));
+                }
+            }
+        }
+    }
+    let mut predecessors: Vec<usize> = unmoves.into_iter().filter_map(|((from_rank, from_col), (to_rank, to_col))| {
+        let mut previous = mover.clone();
+        previous.piece_board[to_rank][to_col] = previous.piece_board[from_rank][from_col];
+        previous.white_board[to_rank][to_col] = mover.white_active;
+        previous.piece_board[from_rank][from_col] = Pieces::Empty;
+        previous.white_board[from_rank][from_col] = false;
+        // The side to move here cannot have been left in check by its opponent
+        if previous.is_check(!previous.white_active) {
+            return None;
+        }
+        material.encode(&previous)
+    }).collect();
+    predecessors.sort_unstable();
+    predecessors.dedup();
+    predecessors
+}
+
+fn is_trivial_draw(material: &Material) -> bool {
+    // Bare kings or a single minor piece: no table needed, is_insufficient_mat covers them
+    material.num_pieces() == 2 ||
+        (material.num_pieces() == 3 && material.extra_pieces().all(|(piece, _)| piece == Pieces::B || piece == Pieces::N))
+}
+
+fn parallel_map<T: Send, F: Fn(u32) -> T + Sync>(items: &[u32], f: F) -> Vec<T> {
+    let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+    let chunk_size = (items.len() / threads).max(1024);
+    thread::scope(|scope| {
+        let handles: Vec<_> = items.chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| chunk.iter().map(|item| f(*item)).collect::<Vec<T>>()))
+            .collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+    use crate::notation::from_fen;
+
+    fn shared() -> &'static Tablebase {
+        // KPvK and the KQvK and KRvK tables its promotions convert into, generated once for all tests
+        static TABLEBASE: OnceLock<Tablebase> = OnceLock::new();
+        TABLEBASE.get_or_init(|| {
+            let mut tablebase = Tablebase::new();
+            tablebase.generate("KPvK").unwrap();
+            tablebase
+        })
+    }
+
+    fn entries<'a>(tablebase: &'a Tablebase, name: &str) -> &'a [u8] {
+        match &tablebase.tables[&Material::parse(name).unwrap()].data {
+            TableData::Dtm(entries) => entries,
+            TableData::Wdl(_) => panic!("{} has no distances", name),
+        }
+    }
+
+    fn longest_win(tablebase: &Tablebase, name: &str) -> Option<u8> {
+        entries(tablebase, name).iter().filter_map(|entry| match Dtm::from_entry(*entry) {
+            Some(Dtm::Win(plies)) => Some(plies),
+            _ => None,
+        }).max()
+    }
+
+    #[test]
+    fn kqvk_longest_mate() {
+        let tablebase = shared();
+        // Mate in 10 with white to move
+        assert_eq!(longest_win(tablebase, "KQvK"), Some(19));
+
+        let game_state = from_fen("k7/8/1QK5/8/8/8/8/8 b - - 0 1").unwrap();
+        assert_eq!(tablebase.probe_dtm(&game_state), Some(Dtm::Draw));
+        let game_state = from_fen("k7/8/2K5/8/8/8/8/7Q w - - 0 1").unwrap();
+        assert_eq!(tablebase.probe_dtm(&game_state), Some(Dtm::Win(3)));
+        // The same position with the colors swapped
+        let game_state = from_fen("7q/8/8/8/8/2k5/8/K7 b - - 0 1").unwrap();
+        assert_eq!(tablebase.probe_dtm(&game_state), Some(Dtm::Win(3)));
+    }
+
+    #[test]
+    fn krvk_longest_mate() {
+        // Mate in 16 with white to move
+        assert_eq!(longest_win(shared(), "KRvK"), Some(31));
+    }
+
+    #[test]
+    #[ignore] // Five million positions, run with --release -- --ignored
+    fn kbnvk_longest_mate() {
+        let mut tablebase = Tablebase::new();
+        tablebase.generate("KBNvK").unwrap();
+        // Mate in 33 with white to move
+        assert_eq!(longest_win(&tablebase, "KBNvK"), Some(65));
+    }
+
+    #[test]
+    fn kpvk_wins_and_draws() {
+        let tablebase = shared();
+        // The king on the sixth rank in front of its pawn wins whoever moves
+        let game_state = from_fen("4k3/8/4K3/4P3/8/8/8/8 w - - 0 1").unwrap();
+        assert_eq!(tablebase.probe_wdl(&game_state), Some(Wdl::Win));
+        let game_state = from_fen("4k3/8/4K3/4P3/8/8/8/8 b - - 0 1").unwrap();
+        assert_eq!(tablebase.probe_wdl(&game_state), Some(Wdl::Loss));
+        // The same position with the colors swapped
+        let game_state = from_fen("8/8/8/8/4p3/4k3/8/4K3 b - - 0 1").unwrap();
+        assert_eq!(tablebase.probe_wdl(&game_state), Some(Wdl::Win));
+        // The defending king in front of the pawn with the opposition
+        let game_state = from_fen("8/8/8/4k3/4P3/4K3/8/8 w - - 0 1").unwrap();
+        assert_eq!(tablebase.probe_dtm(&game_state), Some(Dtm::Draw));
+        // A rook pawn cannot drive the king out of the corner
+        let game_state = from_fen("7k/8/8/8/8/8/7P/7K w - - 0 1").unwrap();
+        assert_eq!(tablebase.probe_dtm(&game_state), Some(Dtm::Draw));
+        // Outside the square of the pawn, which wins by stepping two squares
+        let game_state = from_fen("7k/8/8/8/8/8/P7/K7 w - - 0 1").unwrap();
+        assert_eq!(tablebase.probe_wdl(&game_state), Some(Wdl::Win));
+    }
+
+    #[test]
+    fn kpvk_entries_follow_from_their_moves() {
+        // Every entry must be the best result over its moves, which fails if retrograde analysis
+        // misses predecessors (pawn steps back) or misjudges promotions
+        let tablebase = shared();
+        let material = Material::parse("KPvK").unwrap();
+        let entries = entries(tablebase, "KPvK");
+        for index in (0..entries.len()).step_by(13) {
+            let (Some(dtm), Some(game_state)) = (Dtm::from_entry(entries[index]), material.decode(index)) else {
+                continue;
+            };
+            let results: Vec<Dtm> = candidate_turns(&game_state).into_iter()
+                .filter_map(|turn| apply_turn(&game_state, turn))
+                .map(|next_state| tablebase.probe_dtm(&next_state).unwrap())
+                .collect();
+            let fastest_win = results.iter().filter_map(|result| match result {
+                Dtm::Loss(plies) => Some(plies + 1),
+                _ => None,
+            }).min();
+            let expected = if results.is_empty() {
+                if game_state.is_check(game_state.white_active) { Dtm::Loss(0) } else { Dtm::Draw }
+            } else if let Some(plies) = fastest_win {
+                Dtm::Win(plies)
+            } else if results.iter().all(|result| matches!(result, Dtm::Win(_))) {
+                Dtm::Loss(results.iter().filter_map(|result| match result {
+                    Dtm::Win(plies) => Some(plies + 1),
+                    _ => None,
+                }).max().unwrap())
+            } else {
+                Dtm::Draw
+            };
+            assert_eq!(dtm, expected, "index {}", index);
+        }
+    }
+
+    #[test]
+    fn tables_round_trip_through_files() {
+        let dir = std::env::temp_dir().join(format!("moon-chess-tablebase-{}", std::process::id()));
+        let tablebase = shared();
+        tablebase.write_dir(&dir).unwrap();
+        let loaded = Tablebase::load_dir(&dir).unwrap();
+        assert_eq!(loaded.materials(), tablebase.materials());
+        for name in tablebase.materials() {
+            assert_eq!(entries(&loaded, &name), entries(tablebase, &name));
+        }
+        let wdl = Table::read(&dir.join("KPvK.wdl")).unwrap();
+        assert!(matches!(wdl.data, TableData::Wdl(packed) if packed == Table::pack_wdl(entries(tablebase, "KPvK"))));
+
+        // A truncated file is rejected
+        let bytes = fs::read(dir.join("KRvK.dtm")).unwrap();
+        fs::write(dir.join("KRvK.dtm"), &bytes[..bytes.len() - 1]).unwrap();
+        assert!(matches!(Table::read(&dir.join("KRvK.dtm")), Err(TablebaseError::BadFile(_))));
+        fs::write(dir.join("KRvK.dtm"), b"KRvK").unwrap();
+        assert!(matches!(Tablebase::load_dir(&dir), Err(TablebaseError::BadFile(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wdl_only_tables_are_rebuilt() {
+        let mut tablebase = Tablebase::new();
+        tablebase.generate("KQvK").unwrap();
+        let material = Material::parse("KQvK").unwrap();
+        let table = tablebase.tables.get_mut(&material).unwrap();
+        if let TableData::Dtm(entries) = &table.data {
+            table.data = TableData::Wdl(Table::pack_wdl(entries));
+        }
+        let game_state = from_fen("k7/8/2K5/8/8/8/8/7Q w - - 0 1").unwrap();
+        assert_eq!(tablebase.probe_dtm(&game_state), None);
+        assert_eq!(tablebase.probe_wdl(&game_state), Some(Wdl::Win));
+        // A position with other material is not in the table
+        let rook = from_fen("k7/8/2K5/8/8/8/8/7R w - - 0 1").unwrap();
+        assert_eq!(tablebase.tables[&material].probe_wdl(&rook), None);
+
+        tablebase.generate("KQvK").unwrap();
+        assert_eq!(tablebase.probe_dtm(&game_state), Some(Dtm::Win(3)));
+    }
+}
@@ -0,0 +1,201 @@
+use anchor_lang::prelude::*;
+
+pub const MAX_PERIODS: usize = 3; // Periods after the first, e.g. 40 moves / 20 moves / rest of the game
+pub const DAY: i64 = 24 * 60 * 60;
+
+// Clock arithmetic, kept free of Clock::get() so it only depends on the elapsed seconds.
+// The per-move bonus of a game is an increment or a delay depending on the mode.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TimeControlMode {
+    Fischer, // bonus added after every move
+    Bronstein, // bonus added back, up to the time used for the move
+    SimpleDelay, // clock only starts after the bonus has passed
+    Hourglass, // time used by the mover is added to the opponent, the bonus is unused
+    // Bonus is the time per move. Unused time is banked up to max_bank_days, 0 = no bank.
+    Correspondence { max_bank_days: u16, vacation_days: u16 },
+}
+impl Default for TimeControlMode {
+    fn default() -> Self { TimeControlMode::Fischer }
+}
+impl TimeControlMode {
+    pub fn to_code(&self) -> (u8, u16, u16) {
+        // Kind and correspondence settings, as stored in the zero-copy Game
+        match self {
+            TimeControlMode::Fischer => (0, 0, 0),
+            TimeControlMode::Bronstein => (1, 0, 0),
+            TimeControlMode::SimpleDelay => (2, 0, 0),
+            TimeControlMode::Hourglass => (3, 0, 0),
+            TimeControlMode::Correspondence { max_bank_days, vacation_days } => (4, *max_bank_days, *vacation_days),
+        }
+    }
+
+    pub fn from_code(kind: u8, max_bank_days: u16, vacation_days: u16) -> Option<Self> {
+        match kind {
+            0 => Some(TimeControlMode::Fischer),
+            1 => Some(TimeControlMode::Bronstein),
+            2 => Some(TimeControlMode::SimpleDelay),
+            3 => Some(TimeControlMode::Hourglass),
+            4 => Some(TimeControlMode::Correspondence { max_bank_days, vacation_days }),
+            _ => None,
+        }
+    }
+
+    pub fn time_used(&self, elapsed: i64, bonus: u32) -> i64 {
+        // Seconds taken off the mover's clock before any increment
+        match self {
+            TimeControlMode::SimpleDelay => (elapsed - i64::from(bonus)).max(0),
+            _ => elapsed,
+        }
+    }
+
+    pub fn is_flagged(&self, time_left: i64, elapsed: i64, bonus: u32) -> bool {
+        self.time_used(elapsed, bonus) > time_left
+    }
+
+    pub fn after_move(&self, mover_left: i64, opponent_left: i64, elapsed: i64, bonus: u32) -> (i64, i64) {
+        // Returns the mover's and the opponent's time left after a move that took elapsed seconds
+        let used = self.time_used(elapsed, bonus);
+        match self {
+            TimeControlMode::Fischer => (mover_left - used + i64::from(bonus), opponent_left),
+            TimeControlMode::Bronstein => (mover_left - used + used.min(i64::from(bonus)), opponent_left),
+            TimeControlMode::SimpleDelay => (mover_left - used, opponent_left),
+            TimeControlMode::Hourglass => (mover_left - used, opponent_left + used),
+            TimeControlMode::Correspondence { max_bank_days, .. } => {
+                let bank = if *max_bank_days == 0 { i64::from(bonus) } else { i64::from(*max_bank_days) * DAY };
+                ((mover_left - used + i64::from(bonus)).min(bank), opponent_left)
+            }
+        }
+    }
+}
+
+// A later period of a classical control, e.g. "then 30 minutes for the rest of the game"
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct TimePeriod {
+    pub time: i64, // sec added to the clock when it starts
+    pub bonus: u32, // sec per move from then on
+    pub after_moves: u32, // starts once the player has made this many moves, 0 = unused slot
+}
+const _: () = assert!(std::mem::size_of::<TimePeriod>() == 16);
+impl TimePeriod {
+    pub fn list(periods: &[TimePeriod]) -> Option<[TimePeriod; MAX_PERIODS]> {
+        // Pads the periods to the fixed slots, None if there are too many or they are out of order
+        if periods.len() > MAX_PERIODS {
+            return None;
+        }
+        let mut previous = 0;
+        for period in periods {
+            if period.after_moves <= previous || period.time < 0 {
+                return None;
+            }
+            previous = period.after_moves;
+        }
+        let mut list = [TimePeriod::default(); MAX_PERIODS];
+        list[..periods.len()].copy_from_slice(periods);
+        Some(list)
+    }
+
+    pub fn starting(periods: &[TimePeriod; MAX_PERIODS], current: u8, moves_made: u16) -> Option<TimePeriod> {
+        // The period a player enters with their last move, current being 0 for the first one
+        let next = *periods.get(usize::from(current))?;
+        if next.after_moves != 0 && next.after_moves == u32::from(moves_made) {
+            Some(next)
+        } else {
+            None
+        }
+    }
+}
+
+// Vacation declared by a correspondence player, their clock stops while it lasts
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Vacation {
+    pub from: i64, // unix timestamp
+    pub until: i64,
+    pub days_left: u16, // still available to declare
+    _padding: [u8; 6],
+}
+const _: () = assert!(std::mem::size_of::<Vacation>() == 24);
+impl Vacation {
+    pub fn overlap(&self, since: i64, now: i64) -> i64 {
+        // Seconds of vacation between since and now
+        (self.until.min(now) - self.from.max(since)).max(0)
+    }
+
+    pub fn is_running(&self, now: i64) -> bool {
+        self.from <= now && now < self.until
+    }
+
+    pub fn declare(&mut self, days: u16, now: i64) -> bool {
+        // Starts a vacation now, false if there are not enough days left or one is running
+        if days == 0 || days > self.days_left || self.is_running(now) {
+            return false;
+        }
+        self.days_left -= days;
+        self.from = now;
+        self.until = now + i64::from(days) * DAY;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increments() {
+        // 100 s left, move took 8 s, 5 s bonus
+        assert_eq!(TimeControlMode::Fischer.after_move(100, 50, 8, 5), (97, 50));
+        assert_eq!(TimeControlMode::Bronstein.after_move(100, 50, 8, 5), (97, 50));
+        assert_eq!(TimeControlMode::Bronstein.after_move(100, 50, 3, 5), (100, 50));
+        assert_eq!(TimeControlMode::SimpleDelay.after_move(100, 50, 8, 5), (97, 50));
+        assert_eq!(TimeControlMode::SimpleDelay.after_move(100, 50, 3, 5), (100, 50));
+        assert_eq!(TimeControlMode::Hourglass.after_move(100, 50, 8, 5), (92, 58));
+    }
+
+    #[test]
+    fn flag_falls_after_the_delay() {
+        assert!(!TimeControlMode::SimpleDelay.is_flagged(10, 15, 5));
+        assert!(TimeControlMode::SimpleDelay.is_flagged(10, 16, 5));
+        assert!(TimeControlMode::Fischer.is_flagged(10, 11, 5));
+        assert!(!TimeControlMode::Bronstein.is_flagged(10, 10, 5));
+    }
+
+    #[test]
+    fn correspondence_bank() {
+        let per_move = 3 * DAY as u32;
+        let no_bank = TimeControlMode::Correspondence { max_bank_days: 0, vacation_days: 0 };
+        assert_eq!(no_bank.after_move(3 * DAY, 0, DAY, per_move), (3 * DAY, 0));
+        let banked = TimeControlMode::Correspondence { max_bank_days: 10, vacation_days: 0 };
+        assert_eq!(banked.after_move(3 * DAY, 0, DAY, per_move), (5 * DAY, 0));
+        assert_eq!(banked.after_move(9 * DAY, 0, DAY, per_move), (10 * DAY, 0));
+        assert!(banked.is_flagged(3 * DAY, 3 * DAY + 1, per_move));
+    }
+
+    #[test]
+    fn vacation() {
+        let mut vacation = Vacation { days_left: 5, ..Vacation::default() };
+        assert_eq!(vacation.overlap(0, 100), 0);
+        assert!(!vacation.declare(6, 1_000));
+        assert!(vacation.declare(2, 1_000));
+        assert!(!vacation.declare(1, 1_000 + DAY));
+        assert_eq!(vacation.overlap(0, 1_000 + DAY), DAY);
+        assert_eq!(vacation.overlap(1_000 + DAY, 1_000 + 5 * DAY), DAY);
+        assert!(vacation.declare(3, 1_000 + 2 * DAY));
+        assert_eq!(vacation.days_left, 0);
+    }
+
+    #[test]
+    fn periods() {
+        // 40 moves in 90 minutes, then 30 minutes with 30 seconds per move
+        let rest = TimePeriod { after_moves: 40, time: 1800, bonus: 30 };
+        let periods = TimePeriod::list(&[rest]).unwrap();
+        assert!(TimePeriod::starting(&periods, 0, 39).is_none());
+        assert!(TimePeriod::starting(&periods, 0, 40) == Some(rest));
+        assert!(TimePeriod::starting(&periods, 1, 41).is_none());
+        assert!(TimePeriod::starting(&periods, 1, 0).is_none());
+        assert!(TimePeriod::list(&[rest, rest]).is_none());
+        assert!(TimePeriod::list(&[TimePeriod { after_moves: 0, ..rest }]).is_none());
+        assert!(TimePeriod::list(&[rest; MAX_PERIODS + 1]).is_none());
+    }
+}
@@ -1,13 +1,30 @@
 use std::cmp;
-use crate::game_state::{update_loc,GameState};
+use crate::game_state::{in_board,update_loc,GameState};
 use crate::helpers::{Pieces,Turn};
 use anchor_lang::prelude::*;
 
 const MAX_HALFMOVES: u8 = 100; // 50 move rule
+pub const RECENT_STATES: usize = 128; // More than MAX_HALFMOVES
 // pub const MAX_MOVES: usize = 64*2;
 
-fn try_update_board(turn: &Turn, curr_game: &mut GameState) -> bool {
-    // Checks if the move is valid and updates the board
+// Why try_update_board rejected a move
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum IllegalMove {
+    WrongPiece, // the mover has no such piece on the from square
+    OwnPieceOnTarget,
+    WrongDirection, // the piece does not move that way
+    PathBlocked,
+    NothingToCapture, // pawn moving diagonally to an empty square
+    MissingPromotion, // pawn reaching the last rank without a promotion piece
+    PromotionTooEarly,
+    NoCastlingRight,
+    CastlingOutOfCheck,
+    CastlingThroughCheck,
+    LeavesKingInCheck,
+}
+
+fn try_update_board(turn: &Turn, curr_game: &mut GameState) -> core::result::Result<(), IllegalMove> {
+    // Checks if the move is valid and updates the board, or says why it is not
 
     // Recast rank and col as usize (needed for index)
     let piece = turn.piece();
@@ -16,38 +33,39 @@ fn try_update_board(turn: &Turn, curr_game: &mut GameState) -> bool {
     let to_rank: usize = turn.to_rank();
     let to_col: usize = turn.to_col();
 
-    // Check if piece at original location is right
-    if (curr_game.piece_board[from_rank][from_col] != piece) || 
+    // Check if piece at original location is right (promotions move a plain pawn)
+    let board_piece = if piece.is_pawn() { Pieces::P } else { piece };
+    if (curr_game.piece_board[from_rank][from_col] != board_piece) || 
         (curr_game.white_active != curr_game.white_board[from_rank][from_col]) {
-        return false;
+        return Err(IllegalMove::WrongPiece);
     }
 
     // Check no same color piece at final location
     if (curr_game.piece_board[to_rank][to_col] != Pieces::Empty) && 
         (curr_game.white_active == curr_game.white_board[to_rank][to_col]) {
-        return false;
+        return Err(IllegalMove::OwnPieceOnTarget);
     }
 
     // Check if piece can move to target square
     if !turn.is_valid_dir() {
-        return false;
+        return Err(IllegalMove::WrongDirection);
     }
     let rank_diff = cmp::max(from_rank,to_rank) - cmp::min(from_rank,to_rank);
     let col_diff = cmp::max(from_col,to_col) - cmp::min(from_col,to_col);
     if piece.is_pawn()  {
         // Check movement is valid
         if (curr_game.white_active && to_rank < from_rank) || (!curr_game.white_active && to_rank > from_rank) {
-            return false;
+            return Err(IllegalMove::WrongDirection);
         }
         if col_diff == 0 { // Going forward
             if rank_diff == 2 && ((curr_game.white_active && to_rank != 3) || (!curr_game.white_active && to_rank != 4)) {
-                return false;
+                return Err(IllegalMove::WrongDirection);
             }
             // Check the movement squares
             for i in 1..=rank_diff {
                 let new_rank = update_loc(from_rank,i,curr_game.white_active);
                 if curr_game.piece_board[new_rank][from_col] != Pieces::Empty {
-                    return false;
+                    return Err(IllegalMove::PathBlocked);
                 }
             }
             // Update board
@@ -62,9 +80,9 @@ fn try_update_board(turn: &Turn, curr_game: &mut GameState) -> bool {
                 };
             }
         } else { // Capture/en passant
-            if (curr_game.piece_board[to_rank][to_col] != Pieces::Empty) && 
+            if (curr_game.piece_board[to_rank][to_col] == Pieces::Empty) && 
                 (to_rank*8+to_col != usize::from(curr_game.en_passant)) {
-                return false;
+                return Err(IllegalMove::NothingToCapture);
             }
             // Update board
             if to_rank*8+to_col == usize::from(curr_game.en_passant) {
@@ -81,7 +99,7 @@ fn try_update_board(turn: &Turn, curr_game: &mut GameState) -> bool {
         // Handle promotions separately
         if (curr_game.white_active && (to_rank == 7)) || (!curr_game.white_active && (to_rank == 0)) {
             if piece == Pieces::P {
-                return false;
+                return Err(IllegalMove::MissingPromotion);
             }
             // Update board
             curr_game.piece_board[to_rank][to_col] = {
@@ -90,43 +108,47 @@ fn try_update_board(turn: &Turn, curr_game: &mut GameState) -> bool {
                     Pieces::PToN => Pieces::N,
                     Pieces::PToB => Pieces::B,
                     Pieces::PToQ => Pieces::Q,
-                    _ => return false,
+                    _ => return Err(IllegalMove::MissingPromotion),
                 }
             };
         }
         else if piece != Pieces::P {
-            return false;
+            return Err(IllegalMove::PromotionTooEarly);
         }
     } else if piece == Pieces::K {
         if col_diff == 2 { // Handle castling separately (by moving the king square by square)
+            if curr_game.is_check(curr_game.white_active) {
+                return Err(IllegalMove::CastlingOutOfCheck);
+            }
             let mut end_rook_loc = from_col+1;
             let mut start_rook_loc = 7;
             if to_col == 6 { // King-side
                 if (curr_game.white_active && !curr_game.white_castle_king) || (!curr_game.white_active && !curr_game.black_castle_king) {
-                    return false;
+                    return Err(IllegalMove::NoCastlingRight);
                 }
                 for i in 1..=2 {
                     if curr_game.piece_board[from_rank][from_col+i] != Pieces::Empty {
-                        return false;
+                        return Err(IllegalMove::PathBlocked);
                     }
                 }
             } else { // Queen-side
                 if (curr_game.white_active && !curr_game.white_castle_queen) || (!curr_game.white_active && !curr_game.black_castle_queen) {
-                    return false;
+                    return Err(IllegalMove::NoCastlingRight);
                 }
                 for i in 1..=3 {
                     if curr_game.piece_board[from_rank][from_col-i] != Pieces::Empty {
-                        return false;
+                        return Err(IllegalMove::PathBlocked);
                     }
                 }
                 end_rook_loc = from_col-1;
                 start_rook_loc = 0;
             }
             // Check the king does not move through check
+            curr_game.piece_board[from_rank][from_col] = Pieces::Empty;
             curr_game.piece_board[from_rank][end_rook_loc] = Pieces::K;
             curr_game.white_board[from_rank][end_rook_loc] = curr_game.white_active;
             if curr_game.is_check(curr_game.white_active) {
-                return false;
+                return Err(IllegalMove::CastlingThroughCheck);
             }
             default_update(turn,curr_game);
             // Move the rook
@@ -177,7 +199,7 @@ fn try_update_board(turn: &Turn, curr_game: &mut GameState) -> bool {
                 }
             };
             if curr_game.piece_board[new_rank][new_col] != Pieces::Empty {
-                return false;
+                return Err(IllegalMove::PathBlocked);
             }
         }
         // Update board
@@ -185,10 +207,13 @@ fn try_update_board(turn: &Turn, curr_game: &mut GameState) -> bool {
     }
 
     // Check if check
-    return !curr_game.is_check(curr_game.white_active);
+    if curr_game.is_check(curr_game.white_active) {
+        return Err(IllegalMove::LeavesKingInCheck);
+    }
+    Ok(())
 }
 
-fn default_update(turn: &Turn, curr_game: &mut GameState) -> () {
+fn default_update(turn: &Turn, curr_game: &mut GameState) {
     let from_rank: usize = turn.from_rank();
     let from_col: usize = turn.from_col();
     let to_rank: usize = turn.to_rank();
@@ -222,10 +247,134 @@ fn default_update(turn: &Turn, curr_game: &mut GameState) -> () {
     }
 }
 
+// (rank_change, col_change, rank_pos, col_pos)
+type Act = (usize, usize, bool, bool);
+const STRAIGHT: [Act; 4] = [(1,0,true,true),(1,0,false,true),(0,1,true,true),(0,1,true,false)];
+const DIAGONAL: [Act; 4] = [(1,1,true,true),(1,1,true,false),(1,1,false,true),(1,1,false,false)];
+const KNIGHT: [Act; 8] = [
+    (2,1,true,true),(2,1,true,false),(2,1,false,true),(2,1,false,false),
+    (1,2,true,true),(1,2,true,false),(1,2,false,true),(1,2,false,false)
+];
+const EVERY_WAY: [Act; 8] = [
+    (1,0,true,true),(1,0,false,true),(0,1,true,true),(0,1,true,false),
+    (1,1,true,true),(1,1,true,false),(1,1,false,true),(1,1,false,false)
+];
+
+pub fn visit_candidate_turns<F: FnMut(Turn) -> bool>(curr_game: &GameState, mut visit: F) -> bool {
+    // Passes the geometrically possible moves for the active color, not yet checked for
+    // legality, to visit until it returns true, and says whether it did. Allocates nothing,
+    // so the on-chain mate and stalemate checks fit the BPF heap.
+    let home_rank: usize = if curr_game.white_active { 0 } else { 7 };
+    let last_rank: usize = if curr_game.white_active { 7 } else { 0 };
+    for i in 0..8 {
+        for j in 0..8 {
+            let piece = curr_game.piece_board[i][j];
+            if piece == Pieces::Empty || curr_game.white_board[i][j] != curr_game.white_active {
+                continue;
+            }
+            let pawn_acts: [Act; 4] = [(1,1,curr_game.white_active,true),(1,1,curr_game.white_active,false),
+                (1,0,curr_game.white_active,true),(2,0,curr_game.white_active,true)];
+            let (acts, slides): (&[Act], bool) = match piece {
+                Pieces::R => (&STRAIGHT, true),
+                Pieces::N => (&KNIGHT, false),
+                Pieces::B => (&DIAGONAL, true),
+                Pieces::Q|Pieces::K => (&EVERY_WAY, piece == Pieces::Q),
+                _ => {
+                    let max_steps = if i == update_loc(home_rank,1,curr_game.white_active) { 2 } else { 1 };
+                    (&pawn_acts[..2 + max_steps], false)
+                }
+            };
+            for act in acts {
+                for dist in 1..8 {
+                    if !in_board(i,j,act.0*dist,act.2,act.1*dist,act.3) {
+                        break;
+                    }
+                    let to_rank = update_loc(i,act.0*dist,act.2);
+                    let to_col = update_loc(j,act.1*dist,act.3);
+                    if piece.is_pawn() && to_rank == last_rank {
+                        for promotion in [Pieces::PToQ, Pieces::PToN, Pieces::PToR, Pieces::PToB] {
+                            if visit(Turn::new(promotion, i, j, to_rank, to_col)) {
+                                return true;
+                            }
+                        }
+                    } else if visit(Turn::new(piece, i, j, to_rank, to_col)) {
+                        return true;
+                    }
+                    if !slides || curr_game.piece_board[to_rank][to_col] != Pieces::Empty {
+                        break;
+                    }
+                }
+            }
+            if piece == Pieces::K && i == home_rank && j == 4 {
+                let (castle_king, castle_queen) = if curr_game.white_active {
+                    (curr_game.white_castle_king, curr_game.white_castle_queen)
+                } else {
+                    (curr_game.black_castle_king, curr_game.black_castle_queen)
+                };
+                if castle_king && visit(Turn::new(Pieces::K, i, j, i, 6)) {
+                    return true;
+                }
+                if castle_queen && visit(Turn::new(Pieces::K, i, j, i, 2)) {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+pub fn candidate_turns(curr_game: &GameState) -> Vec<Turn> {
+    // The same moves as a list, for off-chain callers
+    let mut turns = Vec::new();
+    visit_candidate_turns(curr_game, |turn| {
+        turns.push(turn);
+        false
+    });
+    turns
+}
+
+pub fn apply_turn(curr_game: &GameState, turn: Turn) -> Option<GameState> {
+    // Returns the position after the move (with the other color to play), or None if illegal
+    let mut next_game = curr_game.clone();
+    try_update_board(&turn, &mut next_game).ok()?;
+    next_game.white_active = !next_game.white_active;
+    Some(next_game)
+}
+
+pub fn is_legal(curr_game: &mut GameState, turn: Turn) -> bool {
+    // Tries the move in place and puts back everything it can change, which is cheaper than
+    // copying the position for every candidate
+    let (from_rank, to_col) = (turn.from_rank(), turn.to_col());
+    // From, to, the pawn taken en passant and the castling rook squares
+    let squares = [(from_rank, turn.from_col()), (turn.to_rank(), to_col), (from_rank, to_col),
+        (from_rank, 0), (from_rank, 3), (from_rank, 5), (from_rank, 7)];
+    let saved = squares.map(|(rank, col)| (curr_game.piece_board[rank][col], curr_game.white_board[rank][col]));
+    let (en_passant, half_moves) = (curr_game.en_passant, curr_game.half_moves);
+    let castling = [curr_game.white_castle_king, curr_game.white_castle_queen, curr_game.black_castle_king, curr_game.black_castle_queen];
+    let is_legal = try_update_board(&turn, curr_game).is_ok();
+    for ((rank, col), (piece, white)) in squares.into_iter().zip(saved) {
+        curr_game.piece_board[rank][col] = piece;
+        curr_game.white_board[rank][col] = white;
+    }
+    curr_game.en_passant = en_passant;
+    curr_game.half_moves = half_moves;
+    curr_game.white_castle_king = castling[0];
+    curr_game.white_castle_queen = castling[1];
+    curr_game.black_castle_king = castling[2];
+    curr_game.black_castle_queen = castling[3];
+    is_legal
+}
+
+pub fn legal_turns(curr_game: &GameState) -> Vec<Turn> {
+    candidate_turns(curr_game).into_iter()
+        .filter(|turn| apply_turn(curr_game, *turn).is_some())
+        .collect()
+}
+
 #[derive(PartialEq, Eq, AnchorSerialize, AnchorDeserialize, Copy, Clone)]
 pub enum GameCodes {
     Active,
-    Invalid,
+    Invalid, // No longer stored, illegal moves are rejected with the reason
     WhiteWinCheckmate,
     WhiteWinResignation,
     WhiteWinTime,
@@ -236,84 +385,83 @@ pub enum GameCodes {
     DrawInsufficientMaterial,
     DrawFiftyMoves,
     DrawAgreement,
-    DrawMaxMoves,
+    DrawMaxMoves, // No longer reached, kept so the codes keep their values
     DrawRepetition,
+    Aborted, // Ended before both sides moved, no result
 }
 impl Default for GameCodes {
     fn default() -> Self { GameCodes::Active }
 }
-// impl GameCodes {
-//     fn is_draw(&self) -> bool {
-//         match self {
-//             Self::DrawStalemate|Self::DrawInsufficientMaterial|Self::DrawFiftyMoves|Self::DrawAgreement|Self::DrawMaxMoves => true,
-//             _ => false
-//         }
-//     }
-//     fn is_white_winner(&self) -> bool {
-//         match self {
-//             Self::WhiteWinCheckmate|Self::WhiteWinResignation|Self::WhiteWinTime => true,
-//             _ => false
-//         }
-//     }
-//     fn is_black_winner(&self) -> bool {
-//         match self {
-//             Self::BlackWinCheckmate|Self::BlackWinResignation|Self::BlackWinTime => true,
-//             _ => false
-//         }
-//     }
-// }
+impl GameCodes {
+    const ALL: [GameCodes; 15] = [GameCodes::Active, GameCodes::Invalid, GameCodes::WhiteWinCheckmate,
+        GameCodes::WhiteWinResignation, GameCodes::WhiteWinTime, GameCodes::BlackWinCheckmate,
+        GameCodes::BlackWinResignation, GameCodes::BlackWinTime, GameCodes::DrawStalemate,
+        GameCodes::DrawInsufficientMaterial, GameCodes::DrawFiftyMoves, GameCodes::DrawAgreement,
+        GameCodes::DrawMaxMoves, GameCodes::DrawRepetition, GameCodes::Aborted];
+    pub fn from_code(code: u8) -> Option<Self> {
+        // Inverse of `as u8`, for the code stored in the zero-copy Game
+        Self::ALL.get(usize::from(code)).copied()
+    }
+    pub fn is_draw(&self) -> bool {
+        matches!(self, Self::DrawStalemate|Self::DrawInsufficientMaterial|Self::DrawFiftyMoves|Self::DrawAgreement|Self::DrawMaxMoves|Self::DrawRepetition)
+    }
+    pub fn is_white_winner(&self) -> bool {
+        matches!(self, Self::WhiteWinCheckmate|Self::WhiteWinResignation|Self::WhiteWinTime)
+    }
+    pub fn is_black_winner(&self) -> bool {
+        matches!(self, Self::BlackWinCheckmate|Self::BlackWinResignation|Self::BlackWinTime)
+    }
+}
 
 pub fn active_game_code(game_state: &mut GameState, turn: u16, 
-    past_states: &mut [u32; 256], num_moves: usize) -> GameCodes {
-    if !try_update_board(&Turn {turn: turn},game_state) {
-        return GameCodes::Invalid;
-    }
+    recent_states: &mut [u32; 128], num_moves: usize) -> core::result::Result<GameCodes, IllegalMove> {
+    try_update_board(&Turn {turn},game_state)?;
     
     if !game_state.has_valid_move() {
-        msg!("Here");
         if game_state.is_check(!game_state.white_active) {
             if game_state.white_active {
-                return GameCodes::WhiteWinCheckmate;
+                return Ok(GameCodes::WhiteWinCheckmate);
             } else {
-                return GameCodes::BlackWinCheckmate;
+                return Ok(GameCodes::BlackWinCheckmate);
             }
         } else {
-            return GameCodes::DrawStalemate;
+            return Ok(GameCodes::DrawStalemate);
         }
     }
     if game_state.is_insufficient_mat() {
-        return GameCodes::DrawInsufficientMaterial;
+        return Ok(GameCodes::DrawInsufficientMaterial);
     }
     if game_state.half_moves >= MAX_HALFMOVES {
-        return GameCodes::DrawFiftyMoves;
+        return Ok(GameCodes::DrawFiftyMoves);
     }
 
     game_state.white_active = !game_state.white_active;
 
+    // Only positions since the last capture or pawn move can repeat, and the fifty move rule
+    // ends the game before there are more of them than recent_states holds
     let hash = game_state.small_hash();
+    let since = cmp::min(usize::from(game_state.half_moves), num_moves);
     let mut count = 0;
-    for i in 0..num_moves {
-        if hash == past_states[i] {
+    for back in 1..=since {
+        if hash == recent_states[(num_moves - back) % RECENT_STATES] {
             count += 1;
         }
     }
     if count >= 2 {
-        return GameCodes::DrawRepetition;
+        return Ok(GameCodes::DrawRepetition);
     } else {
-        past_states[num_moves] = hash;
+        recent_states[num_moves % RECENT_STATES] = hash;
     }
 
-    return GameCodes::Active;
+    Ok(GameCodes::Active)
 }
 pub fn timeout_game_code(game_state: &GameState) -> GameCodes {
     if game_state.only_king(!game_state.white_active) {
-        return GameCodes::DrawInsufficientMaterial;
+        GameCodes::DrawInsufficientMaterial
+    } else if game_state.white_active {
+        GameCodes::BlackWinTime
     } else {
-        if game_state.white_active {
-            return GameCodes::BlackWinTime;
-        } else {
-            return GameCodes::WhiteWinTime;
-        }
+        GameCodes::WhiteWinTime
     }
 }
 
@@ -342,3 +490,200 @@ pub fn timeout_game_code(game_state: &GameState) -> GameCodes {
 //         _ => println!("Sad"),
 //     };
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Position with only the given (piece, white, rank, col) entries and no castling rights
+    fn position(pieces: &[(Pieces, bool, usize, usize)], white_active: bool) -> GameState {
+        let mut state = GameState {
+            piece_board: [[Pieces::Empty; 8]; 8],
+            white_board: [[false; 8]; 8],
+            en_passant: 64,
+            white_active,
+            white_castle_king: false,
+            white_castle_queen: false,
+            black_castle_king: false,
+            black_castle_queen: false,
+            half_moves: 0,
+        };
+        for &(piece, white, rank, col) in pieces {
+            state.piece_board[rank][col] = piece;
+            state.white_board[rank][col] = white;
+        }
+        state
+    }
+
+    fn replay(turns: &[u16]) -> Option<GameState> {
+        // Position after the given moves from the start, or None if one is illegal
+        turns.iter().try_fold(GameState::default(), |state, turn| apply_turn(&state, Turn { turn: *turn }))
+    }
+
+    fn try_turn(state: &GameState, turn: Turn) -> Option<GameState> {
+        let mut next = state.clone();
+        try_update_board(&turn, &mut next).ok().map(|_| next)
+    }
+
+    fn end_code(state: &GameState, turn: Turn) -> GameCodes {
+        let mut next = state.clone();
+        active_game_code(&mut next, turn.turn, &mut [0; RECENT_STATES], 0).unwrap()
+    }
+
+    #[test]
+    fn pawn_captures() {
+        // 1. e4 d5
+        let state = replay(&[Turn::new(Pieces::P, 1, 4, 3, 4).turn, Turn::new(Pieces::P, 6, 3, 4, 3).turn]).unwrap();
+        let next = try_turn(&state, Turn::new(Pieces::P, 3, 4, 4, 3)).unwrap();
+        assert!(next.piece_board[4][3] == Pieces::P && next.white_board[4][3]);
+        assert!(next.piece_board[3][4] == Pieces::Empty);
+        assert!(try_turn(&state, Turn::new(Pieces::P, 3, 4, 4, 5)).is_none());
+
+        // 1. e4 e5, pawns cannot capture straight ahead
+        let state = replay(&[Turn::new(Pieces::P, 1, 4, 3, 4).turn, Turn::new(Pieces::P, 6, 4, 4, 4).turn]).unwrap();
+        assert!(try_turn(&state, Turn::new(Pieces::P, 3, 4, 4, 4)).is_none());
+
+        // En passant removes the pawn that moved past
+        let state = position(&[(Pieces::K, true, 0, 4), (Pieces::K, false, 7, 4),
+            (Pieces::P, true, 4, 4), (Pieces::P, false, 6, 3)], false);
+        let state = apply_turn(&state, Turn::new(Pieces::P, 6, 3, 4, 3)).unwrap();
+        let next = try_turn(&state, Turn::new(Pieces::P, 4, 4, 5, 3)).unwrap();
+        assert!(next.piece_board[5][3] == Pieces::P);
+        assert!(next.piece_board[4][3] == Pieces::Empty);
+    }
+
+    #[test]
+    fn check_by_king_pawn_knight() {
+        let king = (Pieces::K, true, 3, 3);
+        let far_king = (Pieces::K, false, 7, 7);
+        assert!(!position(&[king, far_king], true).is_check(true));
+        assert!(position(&[king, (Pieces::K, false, 4, 4)], true).is_check(true));
+
+        // Black pawns only attack downwards
+        assert!(position(&[king, far_king, (Pieces::P, false, 4, 4)], true).is_check(true));
+        assert!(!position(&[king, far_king, (Pieces::P, false, 2, 4)], true).is_check(true));
+        assert!(!position(&[king, far_king, (Pieces::P, false, 4, 3)], true).is_check(true));
+        assert!(position(&[(Pieces::K, false, 3, 3), (Pieces::K, true, 7, 7), (Pieces::P, true, 2, 2)], false).is_check(false));
+
+        assert!(position(&[king, far_king, (Pieces::N, false, 5, 4)], true).is_check(true));
+        assert!(position(&[king, far_king, (Pieces::N, false, 2, 1)], true).is_check(true));
+        assert!(!position(&[king, far_king, (Pieces::N, false, 5, 5)], true).is_check(true));
+        assert!(!position(&[king, far_king, (Pieces::N, true, 5, 4)], true).is_check(true));
+
+        // A king cannot move next to the other king
+        let state = position(&[(Pieces::K, true, 3, 3), (Pieces::K, false, 5, 3)], true);
+        assert!(try_turn(&state, Turn::new(Pieces::K, 3, 3, 4, 3)).is_none());
+    }
+
+    #[test]
+    fn castling_through_check() {
+        let mut state = position(&[(Pieces::K, true, 0, 4), (Pieces::R, true, 0, 7), (Pieces::R, true, 0, 0),
+            (Pieces::K, false, 7, 0)], true);
+        state.white_castle_king = true;
+        state.white_castle_queen = true;
+        let castle = Turn::new(Pieces::K, 0, 4, 0, 6);
+        let next = try_turn(&state, castle).unwrap();
+        assert!(next.piece_board[0][6] == Pieces::K && next.piece_board[0][5] == Pieces::R);
+        assert!(next.piece_board[0][4] == Pieces::Empty && next.piece_board[0][7] == Pieces::Empty);
+
+        // Rook on f8 covers f1
+        let mut through = state.clone();
+        through.piece_board[7][5] = Pieces::R;
+        assert!(try_turn(&through, castle).is_none());
+        // Queen-side is still fine
+        assert!(try_turn(&through, Turn::new(Pieces::K, 0, 4, 0, 2)).is_some());
+
+        // Rook on e8 gives check
+        let mut in_check = state.clone();
+        in_check.piece_board[7][4] = Pieces::R;
+        assert!(try_turn(&in_check, castle).is_none());
+
+        // Rook on g8 covers the landing square
+        let mut into_check = state.clone();
+        into_check.piece_board[7][6] = Pieces::R;
+        assert!(try_turn(&into_check, castle).is_none());
+
+        let mut moved = state.clone();
+        moved.white_castle_king = false;
+        assert!(try_turn(&moved, castle).is_none());
+    }
+
+    #[test]
+    fn rejection_reasons() {
+        let reason = |state: &GameState, turn: Turn| try_update_board(&turn, &mut state.clone()).unwrap_err();
+        let start = GameState::default();
+        assert_eq!(reason(&start, Turn::new(Pieces::N, 0, 2, 2, 3)), IllegalMove::WrongPiece);
+        assert_eq!(reason(&start, Turn::new(Pieces::N, 0, 1, 1, 3)), IllegalMove::OwnPieceOnTarget);
+        assert_eq!(reason(&start, Turn::new(Pieces::R, 0, 0, 2, 0)), IllegalMove::PathBlocked);
+        assert_eq!(reason(&start, Turn::new(Pieces::P, 1, 4, 2, 5)), IllegalMove::NothingToCapture);
+
+        let mut state = position(&[(Pieces::K, true, 0, 4), (Pieces::R, true, 0, 7), (Pieces::K, false, 7, 0),
+            (Pieces::P, true, 6, 1), (Pieces::P, true, 4, 2)], true);
+        let castle = Turn::new(Pieces::K, 0, 4, 0, 6);
+        assert_eq!(reason(&state, castle), IllegalMove::NoCastlingRight);
+        state.white_castle_king = true;
+        state.piece_board[7][5] = Pieces::R;
+        assert_eq!(reason(&state, castle), IllegalMove::CastlingThroughCheck);
+        state.piece_board[7][5] = Pieces::Empty;
+        state.piece_board[7][6] = Pieces::R;
+        assert_eq!(reason(&state, castle), IllegalMove::LeavesKingInCheck);
+        state.piece_board[7][6] = Pieces::Empty;
+        state.piece_board[7][4] = Pieces::R;
+        assert_eq!(reason(&state, castle), IllegalMove::CastlingOutOfCheck);
+
+        assert_eq!(reason(&state, Turn::new(Pieces::P, 6, 1, 7, 1)), IllegalMove::MissingPromotion);
+        assert_eq!(reason(&state, Turn::new(Pieces::PToQ, 4, 2, 5, 2)), IllegalMove::PromotionTooEarly);
+    }
+
+    #[test]
+    fn promotion() {
+        let state = position(&[(Pieces::K, true, 0, 4), (Pieces::K, false, 7, 7), (Pieces::P, true, 6, 0),
+            (Pieces::P, true, 5, 1)], true);
+        let next = try_turn(&state, Turn::new(Pieces::PToQ, 6, 0, 7, 0)).unwrap();
+        assert!(next.piece_board[7][0] == Pieces::Q && next.white_board[7][0]);
+        let next = try_turn(&state, Turn::new(Pieces::PToN, 6, 0, 7, 0)).unwrap();
+        assert!(next.piece_board[7][0] == Pieces::N);
+
+        assert!(try_turn(&state, Turn::new(Pieces::P, 6, 0, 7, 0)).is_none());
+        assert!(try_turn(&state, Turn::new(Pieces::PToQ, 5, 1, 6, 1)).is_none());
+        assert_eq!(legal_turns(&state).iter().filter(|turn| turn.from_rank() == 6).count(), 4);
+    }
+
+    #[test]
+    fn stalemate_and_mate() {
+        // Back-rank mate with Ra8
+        let state = position(&[(Pieces::K, true, 0, 4), (Pieces::R, true, 0, 0), (Pieces::K, false, 7, 7),
+            (Pieces::P, false, 6, 6), (Pieces::P, false, 6, 7)], true);
+        assert!(end_code(&state, Turn::new(Pieces::R, 0, 0, 7, 0)) == GameCodes::WhiteWinCheckmate);
+        assert!(end_code(&state, Turn::new(Pieces::R, 0, 0, 6, 0)) == GameCodes::Active);
+
+        // Qb6 leaves the king on a8 without a move
+        let state = position(&[(Pieces::K, true, 5, 2), (Pieces::Q, true, 0, 1), (Pieces::K, false, 7, 0)], true);
+        assert!(end_code(&state, Turn::new(Pieces::Q, 0, 1, 5, 1)) == GameCodes::DrawStalemate);
+        assert!(end_code(&state, Turn::new(Pieces::Q, 0, 1, 6, 1)) == GameCodes::WhiteWinCheckmate);
+
+        // Black mates too
+        let state = position(&[(Pieces::K, true, 0, 7), (Pieces::P, true, 1, 6), (Pieces::P, true, 1, 7),
+            (Pieces::R, false, 7, 0), (Pieces::K, false, 7, 4)], false);
+        assert!(end_code(&state, Turn::new(Pieces::R, 7, 0, 0, 0)) == GameCodes::BlackWinCheckmate);
+    }
+
+    #[test]
+    fn in_place_legality_check() {
+        // Castling (queen-side through check), en passant, promotions and a pinned bishop for white
+        let mut state = position(&[(Pieces::K, true, 0, 4), (Pieces::R, true, 0, 0), (Pieces::R, true, 0, 7),
+            (Pieces::P, true, 4, 4), (Pieces::P, true, 6, 1), (Pieces::B, true, 1, 4),
+            (Pieces::K, false, 7, 7), (Pieces::Q, false, 3, 0), (Pieces::R, false, 3, 4), (Pieces::P, false, 4, 3)], true);
+        state.white_castle_king = true;
+        state.white_castle_queen = true;
+        state.en_passant = 5*8+3;
+        state.half_moves = 7;
+        let before = state.clone();
+        let turns = candidate_turns(&state);
+        assert!(turns.iter().any(|turn| turn.piece() == Pieces::K && turn.to_col() == 6));
+        for turn in turns {
+            assert_eq!(is_legal(&mut state, turn), apply_turn(&before, turn).is_some());
+            assert!(state == before && state.half_moves == before.half_moves);
+        }
+    }
+}
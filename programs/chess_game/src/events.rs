@@ -0,0 +1,107 @@
+use anchor_lang::prelude::*;
+use crate::clock::{TimeControlMode,TimePeriod,MAX_PERIODS};
+use crate::code_generator::GameCodes;
+use crate::helpers::Turn;
+
+// Emitted with emit!, so clients can subscribe instead of polling the game account
+
+#[event]
+pub struct GameCreated {
+    pub game: Pubkey,
+    pub authority: Pubkey,
+    pub white_player: Pubkey,
+    pub black_player: Pubkey,
+    pub white_time: i64, // sec
+    pub black_time: i64, // sec
+    pub white_bonus: u32, // sec per move
+    pub black_bonus: u32,
+    pub time_mode: TimeControlMode,
+    pub periods: [TimePeriod; MAX_PERIODS], // after the first, unused slots are zero
+    pub rated: bool,
+}
+
+#[event]
+pub struct MovePlayed {
+    pub game: Pubkey,
+    pub move_number: u16, // half-moves, from 1
+    pub turn: Turn,
+    pub white_time_left: i64, // sec, after the bonus
+    pub black_time_left: i64,
+    pub white_period: u8, // time-control period each player is in, from 0
+    pub black_period: u8,
+}
+
+#[event]
+pub struct DrawOffered {
+    pub game: Pubkey,
+    pub white: bool, // side making the offer
+}
+
+#[event]
+pub struct DrawWithdrawn {
+    pub game: Pubkey,
+    pub white: bool,
+}
+
+#[event]
+pub struct TakebackRequested {
+    pub game: Pubkey,
+    pub white: bool, // side asking
+    pub half_moves: u8, // to undo
+}
+
+#[event]
+pub struct TakebackDeclined {
+    pub game: Pubkey,
+}
+
+#[event]
+pub struct MovesTakenBack {
+    pub game: Pubkey,
+    pub move_number: u16, // half-moves left
+}
+
+#[event]
+pub struct VacationDeclared {
+    pub game: Pubkey,
+    pub white: bool,
+    pub until: i64, // unix timestamp
+}
+
+#[event]
+pub struct GameEnded {
+    pub game: Pubkey,
+    pub status: GameCodes,
+    pub reason: EndReason,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum EndReason {
+    Checkmate,
+    Resignation,
+    Timeout,
+    Stalemate,
+    InsufficientMaterial,
+    FiftyMoves,
+    Agreement,
+    MaxMoves,
+    Repetition,
+    Aborted,
+}
+impl EndReason {
+    pub fn of(status: GameCodes) -> Option<Self> {
+        match status {
+            GameCodes::Active | GameCodes::Invalid => None,
+            GameCodes::WhiteWinCheckmate | GameCodes::BlackWinCheckmate => Some(EndReason::Checkmate),
+            GameCodes::WhiteWinResignation | GameCodes::BlackWinResignation => Some(EndReason::Resignation),
+            GameCodes::WhiteWinTime | GameCodes::BlackWinTime => Some(EndReason::Timeout),
+            GameCodes::DrawStalemate => Some(EndReason::Stalemate),
+            GameCodes::DrawInsufficientMaterial => Some(EndReason::InsufficientMaterial),
+            GameCodes::DrawFiftyMoves => Some(EndReason::FiftyMoves),
+            GameCodes::DrawAgreement => Some(EndReason::Agreement),
+            GameCodes::DrawMaxMoves => Some(EndReason::MaxMoves),
+            GameCodes::DrawRepetition => Some(EndReason::Repetition),
+            GameCodes::Aborted => Some(EndReason::Aborted),
+        }
+    }
+}
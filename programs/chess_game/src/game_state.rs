@@ -1,6 +1,7 @@
 use std::hash::{Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
 use crate::helpers::Pieces;
+use crate::code_generator::{visit_candidate_turns,is_legal};
 use anchor_lang::prelude::*;
 
 
@@ -75,6 +76,65 @@ impl PartialEq for GameState {
 }
 impl Eq for GameState {}
 
+// GameState in 35 bytes, as stored in the zero-copy Game account
+#[derive(Clone, Copy, Default, AnchorSerialize, AnchorDeserialize)]
+#[repr(C)]
+pub struct PackedBoard {
+    squares: [u8; 32], // two squares per byte, low nibble first: piece turn code + 1 (0 empty) | 8 if white
+    en_passant: u8,
+    flags: u8, // white_active, then castling: white king, white queen, black king, black queen
+    half_moves: u8,
+}
+const _: () = assert!(std::mem::size_of::<PackedBoard>() == 35);
+impl From<&GameState> for PackedBoard {
+    fn from(game_state: &GameState) -> Self {
+        let mut squares = [0; 32];
+        for square in 0..64 {
+            let (rank, col) = (square / 8, square % 8);
+            let piece = game_state.piece_board[rank][col];
+            let mut nibble = if piece == Pieces::Empty { 0 } else { piece.turn_code() as u8 + 1 };
+            if game_state.white_board[rank][col] {
+                nibble |= 8;
+            }
+            squares[square / 2] |= nibble << (4 * (square % 2));
+        }
+        let flags = [
+            game_state.white_active,
+            game_state.white_castle_king,
+            game_state.white_castle_queen,
+            game_state.black_castle_king,
+            game_state.black_castle_queen,
+        ].iter().enumerate().fold(0, |flags, (bit, set)| flags | (u8::from(*set) << bit));
+        Self { squares, en_passant: game_state.en_passant, flags, half_moves: game_state.half_moves }
+    }
+}
+impl From<&PackedBoard> for GameState {
+    fn from(packed: &PackedBoard) -> Self {
+        let mut piece_board = [[Pieces::Empty; 8]; 8];
+        let mut white_board = [[false; 8]; 8];
+        for square in 0..64 {
+            let nibble = (packed.squares[square / 2] >> (4 * (square % 2))) & 0xf;
+            let code = nibble & 7;
+            if code != 0 {
+                piece_board[square / 8][square % 8] = Pieces::from_turn_code(u16::from(code - 1));
+            }
+            white_board[square / 8][square % 8] = nibble & 8 != 0;
+        }
+        let flag = |bit: u8| packed.flags & (1 << bit) != 0;
+        Self {
+            piece_board,
+            white_board,
+            en_passant: packed.en_passant,
+            white_active: flag(0),
+            white_castle_king: flag(1),
+            white_castle_queen: flag(2),
+            black_castle_king: flag(3),
+            black_castle_queen: flag(4),
+            half_moves: packed.half_moves,
+        }
+    }
+}
+
 impl GameState {
     pub fn is_check(&self, white: bool) -> bool {
         // Check if white/black is in check
@@ -107,9 +167,11 @@ impl GameState {
                             break;
                         } else {
                             match self.piece_board[new_rank][new_col] {
-                                Pieces::B|Pieces::Q|Pieces::K => return true,
+                                Pieces::B|Pieces::Q => return true,
+                                Pieces::K if i==1 => return true,
                                 Pieces::P => {
-                                    if (i==1) && (move_up != white) {
+                                    // Enemy pawns attack towards our side of the board
+                                    if (i==1) && (move_up == white) {
                                         return true;
                                     } else {
                                         break;
@@ -142,7 +204,8 @@ impl GameState {
                             break;
                         } else {
                             match self.piece_board[new_rank][new_col] {
-                                Pieces::R|Pieces::Q|Pieces::K => return true,
+                                Pieces::R|Pieces::Q => return true,
+                                Pieces::K if i==1 => return true,
                                 _ => break,
                             }
                         }
@@ -156,7 +219,7 @@ impl GameState {
                 for col_pos in [false,true] {
                     let col_change = rank_change % 2 + 1;
                     if !in_board(king_rank,king_col,rank_change,rank_pos,col_change,col_pos) {
-                        break;
+                        continue;
                     }
                     let new_rank = update_loc(king_rank,rank_change,rank_pos);
                     let new_col = update_loc(king_col,col_change,col_pos);
@@ -166,68 +229,17 @@ impl GameState {
                 }
             }
         }
-        return false;
+        false
     }
     
     pub fn has_valid_move(&mut self) -> bool {    
         // For non-active color, see if any piece has a valid move
-        for i in 0..8 {
-            for j in 0..8 {
-                // Check to see if piece can move
-                let piece = self.piece_board[i][j];
-                if piece != Pieces::Empty && self.white_board[i][j] != self.white_active {
-                    let acts = match piece {
-                        Pieces::R => vec![(1,0,true,true),(1,0,false,true),(0,1,true,true),(0,1,true,false)],
-                        Pieces::N => vec![
-                            (2,1,true,true),(2,1,true,false),(2,1,false,true),(2,1,false,false),
-                            (1,2,true,true),(1,2,true,false),(1,2,false,true),(1,2,false,false)
-                        ],
-                        Pieces::B => vec![(1,1,true,true),(1,1,true,false),(1,1,false,true),(1,1,false,false)],
-                        Pieces::Q|Pieces::K => vec![
-                            (1,0,true,true),(1,0,false,true),(0,1,true,true),(0,1,true,false),
-                            (1,1,true,true),(1,1,true,false),(1,1,false,true),(1,1,false,false)
-                        ],
-                        _ => vec![
-                            (1,0,!self.white_active,true),(1,1,!self.white_active,true),
-                            (1,1,!self.white_active,false)
-                        ],
-                    };
-                    for act in acts {
-                        if in_board(i,j,act.0,act.2,act.1,act.3) {
-                            let test_rank = update_loc(i,act.0,act.2);
-                            let test_col = update_loc(j,act.1,act.3);
-                            if piece == Pieces::P {
-                                if act.1 == 0 && self.piece_board[test_rank][test_col] != Pieces::Empty {
-                                    continue;
-                                } else if (
-                                    self.piece_board[test_rank][test_col] == Pieces::Empty || 
-                                    self.white_board[test_rank][test_col] == self.white_active
-                                ) && test_rank*8+test_col != usize::from(self.en_passant) {
-                                    continue;
-                                }
-                            } else if self.piece_board[test_rank][test_col] != Pieces::Empty &&
-                                self.white_board[test_rank][test_col] != self.white_active {
-                                continue;
-                            }
-                            // Move and check
-                            let old_piece = self.piece_board[test_rank][test_col];
-                            let old_color = self.white_board[test_rank][test_col];
-                            self.piece_board[test_rank][test_col] = piece;
-                            self.white_board[test_rank][test_col] = !self.white_active;
-                            self.piece_board[i][j] = Pieces::Empty;
-                            let is_check = self.is_check(!self.white_active);
-                            self.piece_board[i][j] = piece;
-                            self.white_board[test_rank][test_col] = old_color;
-                            self.piece_board[test_rank][test_col] = old_piece;
-                            if !is_check {
-                                return true;
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        return false;
+        self.white_active = !self.white_active;
+        // Candidates come from a copy on the stack and are tried in place on self
+        let position = self.clone();
+        let has_move = visit_candidate_turns(&position, |turn| is_legal(self, turn));
+        self.white_active = !self.white_active;
+        has_move
     }
 
     pub fn is_insufficient_mat(&self) -> bool {
@@ -283,7 +295,7 @@ impl GameState {
             return false;
         }
 
-        return true;
+        true
     }
 
     pub fn only_king(&self, is_white: bool) -> bool {
@@ -299,7 +311,7 @@ impl GameState {
                 }
             }
         }
-        return true;
+        true
     }
 
     pub fn small_hash(&self) -> u32 {
@@ -309,14 +321,14 @@ impl GameState {
     }
 }
 
-fn in_board(rank:usize,col:usize,rank_change:usize,rank_pos:bool,col_change:usize,col_pos:bool) -> bool {
+pub fn in_board(rank:usize,col:usize,rank_change:usize,rank_pos:bool,col_change:usize,col_pos:bool) -> bool {
     if (rank_pos && (rank + rank_change > 7)) || (!rank_pos && (rank_change > rank)) {
         return false;
     }
     if (col_pos && (col + col_change > 7)) || (!col_pos && (col_change > col)) {
         return false;
     }
-    return true;
+    true
 }
 
 pub fn update_loc(loc:usize,change:usize,pos:bool) -> usize {
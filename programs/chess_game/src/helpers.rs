@@ -3,7 +3,7 @@ use std::cmp;
 use anchor_lang::prelude::*;
 
 
-#[derive(PartialEq, Hash, Clone, Copy, AnchorSerialize, AnchorDeserialize)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, AnchorSerialize, AnchorDeserialize)]
 pub enum Pieces {
     Empty,
     R,
@@ -22,33 +22,52 @@ impl Default for Pieces {
 }
 impl Pieces {
     pub fn is_pawn(&self) -> bool {
+        matches!(self, Self::P | Self::PToR | Self::PToN | Self::PToB | Self::PToQ)
+    }
+    pub fn turn_code(&self) -> u16 {
+        // Inverse of Turn::piece
         match self {
-            Self::P | Self::PToR | Self::PToN | Self::PToB | Self::PToQ => true,
-            _ => false,
+            Self::R => 0,
+            Self::N => 1,
+            Self::B => 2,
+            Self::Q => 3,
+            Self::K => 4,
+            Self::P => 5,
+            Self::PToR => 6,
+            Self::PToN => 7,
+            Self::PToB => 8,
+            Self::PToQ => 9,
+            Self::Empty => 0b1111,
+        }
+    }
+    pub fn from_turn_code(code: u16) -> Self {
+        match code {
+            0 => Self::R,
+            1 => Self::N,
+            2 => Self::B,
+            3 => Self::Q,
+            4 => Self::K,
+            5 => Self::P,
+            6 => Self::PToR,
+            7 => Self::PToN,
+            8 => Self::PToB,
+            9 => Self::PToQ,
+            _ => Self::Empty,
         }
     }
 }
 
-#[derive(PartialEq, AnchorSerialize, AnchorDeserialize, Default, Clone, Copy)]
+#[derive(PartialEq, Eq, Debug, AnchorSerialize, AnchorDeserialize, Default, Clone, Copy)]
 pub struct Turn {
     pub turn: u16, // piece (4 bits) | from_rank (3) | from_col (3) | to_rank (3) | to_col (3)
 }
 impl Turn {
+    pub fn new(piece: Pieces, from_rank: usize, from_col: usize, to_rank: usize, to_col: usize) -> Self {
+        let coords = (from_rank << 9) | (from_col << 6) | (to_rank << 3) | to_col;
+        Self { turn: (piece.turn_code() << 12) | (coords as u16 & 0xfff) }
+    }
     pub fn piece(&self) -> Pieces {
-        let piece_num = self.turn >> 12;
-        match piece_num {
-            0 => Pieces::R,
-            1 => Pieces::N,
-            2 => Pieces::B,
-            3 => Pieces::Q,
-            4 => Pieces::K,
-            5 => Pieces::P,
-            6 => Pieces::PToR,
-            7 => Pieces::PToN,
-            8 => Pieces::PToB,
-            9 => Pieces::PToQ,
-            _ => Pieces::Empty,
-        }
+        Pieces::from_turn_code(self.turn >> 12)
     }
     pub fn from_rank(&self) -> usize {
         ((self.turn >> 9) & 0b111).into()
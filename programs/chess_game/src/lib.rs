@@ -1,206 +1,2046 @@
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::hashv;
+use anchor_lang::solana_program::{bpf_loader_upgradeable, program::invoke, system_instruction};
+use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
 declare_id!("2G1pVxGS4p9jFFTVBVirZ2vdQMw22nKfbf6CupVEVZSg");
 
-mod code_generator;
-mod game_state;
-use game_state::{GameState};
+const PROFILE_SEED: &[u8] = b"PROFILE";
+const GAME_SEED: &[u8] = b"GAME";
+const HOUSE_SEED: &[u8] = b"HOUSE";
+const ESCROW_SEED: &[u8] = b"ESCROW";
+const PRIZE_SEED: &[u8] = b"PRIZE";
+const PLAYER_GAME_SEED: &[u8] = b"PLAYER_GAME";
+const RECORD_SEED: &[u8] = b"RECORD";
+const BPS: u16 = 10000; // Basis points in the whole pot
+const MAX_FEE_BPS: u16 = 1000; // The house takes at most 10% of a pot
+const CHALLENGE_LIFETIME: i64 = 7 * 24 * 60 * 60; // After that a challenge can only be cancelled
+const DRAW_OFFER_INTERVAL: u16 = 10; // Half-moves before a player can offer a draw again
+const MAX_PREMOVES: usize = 8; // Conditional moves a player can register at once
+const MAX_PREMOVE_REPLIES: usize = 4; // Premoves one move can set off, each costs a full move check
+const TAKEBACK_WINDOW: usize = 8; // Half-moves takebacks in a row can reach back
+const FIRST_MOVE_TIME: i64 = 60; // sec each side has for its first move before the game is aborted
+const HISTORY_CHUNK: usize = 64; // Half-moves of room added when the move list outgrows the account
+const MOVES_OFFSET: usize = 8 + std::mem::size_of::<Game>(); // The move list follows the Game struct
+
+pub mod clock;
+pub mod code_generator;
+pub mod events;
+pub mod game_state;
+use game_state::{GameState,PackedBoard};
 use code_generator::{GameCodes};
-use code_generator::{active_game_code,timeout_game_code};
+use code_generator::{active_game_code,timeout_game_code,IllegalMove,RECENT_STATES};
+use rating::{Rating,TimeCategory};
+use clock::{TimeControlMode,TimePeriod,Vacation,MAX_PERIODS};
+use events::{EndReason,GameCreated,MovePlayed,DrawOffered,DrawWithdrawn,GameEnded,VacationDeclared,TakebackRequested,TakebackDeclined,MovesTakenBack};
+use helpers::Turn;
 
-mod helpers;
+pub mod helpers;
+pub mod rating;
+pub mod tournament;
+use tournament::{Tournament,TournamentParams};
 
 
 #[program]
 pub mod chess_game {
     use super::*;
-    pub fn setup_game(ctx: Context<SetupGame>, white_player: Pubkey, black_player: Pubkey, white_time: i64, black_time: i64, white_bonus: u32, black_bonus: u32) -> Result<()> {
-        let game = &mut *ctx.accounts.game;
-        game.authority = ctx.accounts.authority.key();
-        game.white_player = white_player;
-        game.black_player = black_player;
-        game.white_time_left = white_time;
-        game.black_time_left = black_time;
-        game.white_bonus_time = white_bonus;
-        game.black_bonus_time = black_bonus;
-        game.curr_board = GameState::default();
-        game.past_states[0] = game.curr_board.small_hash();
-        game.last_move = Clock::get().unwrap().unix_timestamp;
-        msg!("{}",game.curr_board.white_active);
-        Ok(())
-    }
-    pub fn play(ctx: Context<Play>, turn: u16) -> Result<()> {
-        let game = &mut *ctx.accounts.game;    
-        game.play(turn)
-    }
-    pub fn update_draw(ctx: Context<UpdateDraw>, is_white: bool, is_draw: bool) -> Result<()> {
-        let game = &mut *ctx.accounts.game;
-        game.update_draw(is_white, is_draw)
-    }
-    pub fn resign(ctx: Context<Resign>, is_white: bool) -> Result<()> {
-        let game = &mut *ctx.accounts.game;
-        game.resign(is_white)
+    pub fn create_profile(ctx: Context<CreateProfile>) -> Result<()> {
+        let profile = &mut *ctx.accounts.profile;
+        profile.player = ctx.accounts.player.key();
+        profile.bump = *ctx.bumps.get("profile").unwrap();
+        Ok(())
+    }
+    #[allow(clippy::too_many_arguments)]
+    pub fn setup_game(ctx: Context<SetupGame>, white_player: Pubkey, black_player: Pubkey, white_time: i64, black_time: i64, white_bonus: u32, black_bonus: u32, wager: Option<WagerTerms>, rated: bool, time_mode: TimeControlMode, periods: Vec<TimePeriod>) -> Result<()> {
+        let periods = TimePeriod::list(&periods).ok_or(ChessError::InvalidTimeControl)?;
+        let game_key = ctx.accounts.game.key();
+        ctx.accounts.white_profile.list_game(&mut ctx.accounts.white_entry, game_key);
+        ctx.accounts.black_profile.list_game(&mut ctx.accounts.black_entry, game_key);
+        let game = &mut ctx.accounts.game.load_init()?;
+        game.setup(ctx.accounts.authority.key(), white_player, black_player, white_time, black_time, white_bonus, black_bonus, time_mode, wager.unwrap_or_default(), rated);
+        game.periods = periods;
+        game.payer = ctx.accounts.authority.key();
+        emit!(game.created_event(game_key));
+        Ok(())
+    }
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_challenge(ctx: Context<CreateChallenge>, opponent: Option<Pubkey>, color: ColorChoice, time: i64, bonus: u32, wager: Option<WagerTerms>, rated: bool, time_mode: TimeControlMode) -> Result<()> {
+        let challenge = &mut *ctx.accounts.challenge;
+        challenge.creator = ctx.accounts.creator.key();
+        challenge.opponent = opponent.unwrap_or_default();
+        challenge.color = color;
+        challenge.time = time;
+        challenge.bonus = bonus;
+        challenge.time_mode = time_mode;
+        challenge.wager = wager.unwrap_or_default();
+        challenge.rated = rated;
+        challenge.created_at = Clock::get().unwrap().unix_timestamp;
+        Ok(())
+    }
+    pub fn accept_challenge(ctx: Context<AcceptChallenge>) -> Result<()> {
+        let challenge = &ctx.accounts.challenge;
+        let acceptor = ctx.accounts.acceptor.key();
+        if challenge.is_expired(Clock::get().unwrap().unix_timestamp) {
+            return err!(ChessError::ChallengeExpired);
+        }
+        let creator_is_white = match challenge.color {
+            ColorChoice::White => true,
+            ColorChoice::Black => false,
+            ColorChoice::Random => {
+                // Not secure randomness (the slot is known when the transaction is built),
+                // only meant to keep the creator from choosing
+                let slot = Clock::get().unwrap().slot;
+                hashv(&[challenge.key().as_ref(), acceptor.as_ref(), &slot.to_le_bytes()]).to_bytes()[0] & 1 == 0
+            }
+        };
+        let (white_player, black_player) = if creator_is_white {
+            (challenge.creator, acceptor)
+        } else {
+            (acceptor, challenge.creator)
+        };
+        let game_key = ctx.accounts.game.key();
+        ctx.accounts.creator_profile.list_game(&mut ctx.accounts.creator_entry, game_key);
+        ctx.accounts.acceptor_profile.list_game(&mut ctx.accounts.acceptor_entry, game_key);
+        let game = &mut ctx.accounts.game.load_init()?;
+        game.setup(challenge.creator, white_player, black_player, challenge.time, challenge.time, challenge.bonus, challenge.bonus, challenge.time_mode, challenge.wager, challenge.rated);
+        game.payer = acceptor;
+        emit!(game.created_event(game_key));
+        Ok(())
+    }
+    pub fn cancel_challenge(_ctx: Context<CancelChallenge>) -> Result<()> {
+        // The close constraint returns the rent to the creator
+        Ok(())
+    }
+    pub fn play(ctx: Context<Play>, turn: u16, offer_draw: bool) -> Result<()> {
+        let game_key = ctx.accounts.game.key();
+        let (num_moves, played) = {
+            let game = &mut ctx.accounts.game.load_mut()?;
+            let offers = [game.white_draw_open != 0, game.black_draw_open != 0];
+            game.play(ctx.accounts.player.key(), turn, offer_draw)?;
+            game.emit_move(game_key, turn);
+            let curr_time = Clock::get().unwrap().unix_timestamp;
+            let played = game.play_premoves(game_key, turn, curr_time);
+            game.emit_draw_changes(game_key, offers);
+            game.emit_if_ended(game_key, true);
+            (usize::from(game.num_moves), played)
+        };
+        let offset = MOVES_OFFSET + (num_moves - played.len()) * std::mem::size_of::<u16>();
+        let game_info = ctx.accounts.game.to_account_info();
+        let paid = grow_account(game_info.clone(), MOVES_OFFSET + num_moves * std::mem::size_of::<u16>(), ctx.accounts.payer.to_account_info(), ctx.accounts.system_program.to_account_info())?;
+        if paid > 0 {
+            ctx.accounts.game.load_mut()?.add_rent(ctx.accounts.payer.key(), paid)?;
+        }
+        let bytes: Vec<u8> = played.iter().flat_map(|turn| turn.to_le_bytes()).collect();
+        game_info.try_borrow_mut_data()?[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        Ok(())
+    }
+    pub fn set_premoves(ctx: Context<SetPremoves>, premoves: Vec<Premove>) -> Result<()> {
+        let game = &mut ctx.accounts.game.load_mut()?;
+        game.set_premoves(ctx.accounts.player.key(), &premoves)
+    }
+    pub fn update_draw(ctx: Context<UpdateDraw>, is_draw: bool) -> Result<()> {
+        let game_key = ctx.accounts.game.key();
+        let game = &mut ctx.accounts.game.load_mut()?;
+        let offers = [game.white_draw_open != 0, game.black_draw_open != 0];
+        game.update_draw(ctx.accounts.player.key(), is_draw)?;
+        game.emit_draw_changes(game_key, offers);
+        game.emit_if_ended(game_key, true);
+        Ok(())
+    }
+    pub fn resign(ctx: Context<Resign>) -> Result<()> {
+        let game_key = ctx.accounts.game.key();
+        let game = &mut ctx.accounts.game.load_mut()?;
+        game.resign(ctx.accounts.player.key())?;
+        game.emit_if_ended(game_key, true);
+        Ok(())
+    }
+    pub fn request_takeback(ctx: Context<Takeback>) -> Result<()> {
+        let game_key = ctx.accounts.game.key();
+        let game = &mut ctx.accounts.game.load_mut()?;
+        let white = game.request_takeback(ctx.accounts.player.key())?;
+        emit!(TakebackRequested { game: game_key, white, half_moves: game.takeback });
+        Ok(())
+    }
+    pub fn accept_takeback(ctx: Context<Takeback>) -> Result<()> {
+        let game_key = ctx.accounts.game.key();
+        let game = &mut ctx.accounts.game.load_mut()?;
+        game.accept_takeback(ctx.accounts.player.key())?;
+        emit!(MovesTakenBack { game: game_key, move_number: game.num_moves });
+        Ok(())
+    }
+    pub fn decline_takeback(ctx: Context<Takeback>) -> Result<()> {
+        let game_key = ctx.accounts.game.key();
+        let game = &mut ctx.accounts.game.load_mut()?;
+        game.decline_takeback(ctx.accounts.player.key())?;
+        emit!(TakebackDeclined { game: game_key });
+        Ok(())
+    }
+    pub fn abort(ctx: Context<Abort>) -> Result<()> {
+        let game_key = ctx.accounts.game.key();
+        let game = &mut ctx.accounts.game.load_mut()?;
+        game.abort(ctx.accounts.player.key())?;
+        game.emit_if_ended(game_key, true);
+        Ok(())
+    }
+    pub fn authorize_session(ctx: Context<ManageSession>, session_key: Pubkey, expires_at: i64) -> Result<()> {
+        let game = &mut ctx.accounts.game.load_mut()?;
+        game.authorize_session(ctx.accounts.player.key(), SessionKey { key: session_key, expires_at })
+    }
+    pub fn revoke_session(ctx: Context<ManageSession>) -> Result<()> {
+        let game = &mut ctx.accounts.game.load_mut()?;
+        game.authorize_session(ctx.accounts.player.key(), SessionKey::default())
+    }
+    pub fn take_vacation(ctx: Context<TakeVacation>, days: u16) -> Result<()> {
+        let game_key = ctx.accounts.game.key();
+        let game = &mut ctx.accounts.game.load_mut()?;
+        let curr_time = Clock::get().unwrap().unix_timestamp;
+        let white = game.take_vacation(ctx.accounts.player.key(), days, curr_time)?;
+        let vacation = if white { game.white_vacation } else { game.black_vacation };
+        emit!(VacationDeclared { game: game_key, white, until: vacation.until });
+        Ok(())
     }
     pub fn claim_timeout(ctx: Context<Timeout>) -> Result<()> {
-        let game = &mut *ctx.accounts.game;
-        game.claim_timeout()
+        let game_key = ctx.accounts.game.key();
+        let game = &mut ctx.accounts.game.load_mut()?;
+        let was_active = game.is_active();
+        game.claim_timeout()?;
+        game.emit_if_ended(game_key, was_active);
+        Ok(())
+    }
+    pub fn finalize_game(ctx: Context<FinalizeGame>) -> Result<()> {
+        let game = &mut ctx.accounts.game.load_mut()?;
+        let white_score = game.finalize()?;
+        let category = game.category() as usize;
+        let white_rating = ctx.accounts.white_profile.ratings[category];
+        let black_rating = ctx.accounts.black_profile.ratings[category];
+        ctx.accounts.white_profile.ratings[category] = white_rating.after_game(&black_rating, white_score);
+        ctx.accounts.black_profile.ratings[category] = black_rating.after_game(&white_rating, 1.0 - white_score);
+        Ok(())
+    }
+    pub fn create_tournament(ctx: Context<CreateTournament>, params: TournamentParams) -> Result<()> {
+        let tournament = &mut *ctx.accounts.tournament;
+        tournament.setup(ctx.accounts.organizer.key(), params)
+    }
+    pub fn register_player(ctx: Context<RegisterPlayer>) -> Result<()> {
+        let tournament = &mut *ctx.accounts.tournament;
+        tournament.register(ctx.accounts.player.key())
+    }
+    pub fn start_round(ctx: Context<StartRound>) -> Result<()> {
+        let tournament = &mut *ctx.accounts.tournament;
+        tournament.start_round()
+    }
+    pub fn create_tournament_game(ctx: Context<CreateTournamentGame>, board: u8) -> Result<()> {
+        let tournament_key = ctx.accounts.tournament.key();
+        let game_key = ctx.accounts.game.key();
+        let tournament = &mut *ctx.accounts.tournament;
+        let next = tournament.next_game(board, game_key)?;
+        ctx.accounts.first_profile.list_game(&mut ctx.accounts.first_entry, game_key);
+        ctx.accounts.second_profile.list_game(&mut ctx.accounts.second_entry, game_key);
+        let game = &mut ctx.accounts.game.load_init()?;
+        game.setup(tournament_key, next.white_player, next.black_player, next.white_time, next.black_time,
+            next.bonus, next.bonus, TimeControlMode::Fischer, WagerTerms::default(), next.rated);
+        game.payer = ctx.accounts.payer.key();
+        game.result_pending = 1;
+        emit!(game.created_event(game_key));
+        Ok(())
+    }
+    pub fn record_tournament_result(ctx: Context<RecordTournamentResult>, board: u8) -> Result<()> {
+        let game_key = ctx.accounts.game.key();
+        let game = &mut ctx.accounts.game.load_mut()?;
+        let tournament = &mut *ctx.accounts.tournament;
+        tournament.record_result(board, game_key, game.status())?;
+        game.result_pending = 0;
+        Ok(())
+    }
+    pub fn close_game(ctx: Context<CloseGame>) -> Result<()> {
+        // The close constraint returns the rent to the payer, the record keeps the result
+        let game_key = ctx.accounts.game.key();
+        let (white_rent, black_rent) = {
+            let game = &ctx.accounts.game.load()?;
+            game.check_closable()?;
+            let record = &mut *ctx.accounts.record;
+            record.game = game_key;
+            record.white_player = game.white_player;
+            record.black_player = game.black_player;
+            record.status = game.status();
+            record.num_moves = game.num_moves;
+            record.final_hash = GameState::from(&game.board).small_hash();
+            (game.white_rent, game.black_rent)
+        };
+        // The players get back what they paid to grow the move list
+        let game_info = ctx.accounts.game.to_account_info();
+        for (player, rent) in [(&ctx.accounts.white_player, white_rent), (&ctx.accounts.black_player, black_rent)] {
+            **game_info.try_borrow_mut_lamports()? -= rent;
+            **player.try_borrow_mut_lamports()? += rent;
+        }
+        Ok(())
+    }
+    pub fn fund_prizes(ctx: Context<FundPrizes>, amount: u64) -> Result<()> {
+        let tournament = &mut *ctx.accounts.tournament;
+        tournament.fund_prizes(ctx.accounts.mint.key(), *ctx.bumps.get("prize_escrow").unwrap())?;
+        let transfer = Transfer {
+            from: ctx.accounts.funder_token.to_account_info(),
+            to: ctx.accounts.prize_escrow.to_account_info(),
+            authority: ctx.accounts.funder.to_account_info(),
+        };
+        token::transfer(CpiContext::new(ctx.accounts.token_program.to_account_info(), transfer), amount)
+    }
+    pub fn distribute_prizes<'info>(ctx: Context<'_, '_, '_, 'info, DistributePrizes<'info>>) -> Result<()> {
+        // The winners' token accounts are passed as remaining accounts, in final standing order
+        let tournament_key = ctx.accounts.tournament.key();
+        let tournament = &mut *ctx.accounts.tournament;
+        let pool = ctx.accounts.prize_escrow.amount;
+        let payouts = tournament.prize_payouts(pool)?;
+        let seeds: &[&[&[u8]]] = &[&[PRIZE_SEED, tournament_key.as_ref(), &[tournament.prize_bump]]];
+        let mut paid = 0;
+        for (index, (winner, amount)) in payouts.into_iter().enumerate() {
+            if amount == 0 {
+                continue;
+            }
+            let to = ctx.remaining_accounts.get(index).ok_or(ChessError::WrongPrizeAccount)?;
+            let winner_token = TokenAccount::try_deserialize(&mut &to.data.borrow()[..])?;
+            if *to.owner != token::ID || winner_token.owner != winner || winner_token.mint != tournament.prize_mint {
+                return err!(ChessError::WrongPrizeAccount);
+            }
+            let transfer = Transfer {
+                from: ctx.accounts.prize_escrow.to_account_info(),
+                to: to.clone(),
+                authority: ctx.accounts.prize_escrow.to_account_info(),
+            };
+            token::transfer(CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), transfer, seeds), amount)?;
+            paid += amount;
+        }
+        if pool > paid {
+            let transfer = Transfer {
+                from: ctx.accounts.prize_escrow.to_account_info(),
+                to: ctx.accounts.organizer_token.to_account_info(),
+                authority: ctx.accounts.prize_escrow.to_account_info(),
+            };
+            token::transfer(CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), transfer, seeds), pool - paid)?;
+        }
+        Ok(())
+    }
+    pub fn init_house(ctx: Context<InitHouse>, fee_bps: u16) -> Result<()> {
+        if fee_bps > MAX_FEE_BPS {
+            return err!(ChessError::InvalidHouseFee);
+        }
+        let house = &mut *ctx.accounts.house;
+        house.admin = ctx.accounts.admin.key();
+        house.fee_bps = fee_bps;
+        house.bump = *ctx.bumps.get("house").unwrap();
+        Ok(())
+    }
+    pub fn set_house_fee(ctx: Context<SetHouseFee>, fee_bps: u16) -> Result<()> {
+        if fee_bps > MAX_FEE_BPS {
+            return err!(ChessError::InvalidHouseFee);
+        }
+        ctx.accounts.house.fee_bps = fee_bps;
+        Ok(())
+    }
+    pub fn deposit_stake(ctx: Context<DepositStake>) -> Result<()> {
+        let game = &mut ctx.accounts.game.load_mut()?;
+        game.deposit_stake(ctx.accounts.player.key(), ctx.accounts.house.fee_bps, *ctx.bumps.get("escrow").unwrap())?;
+        let transfer = Transfer {
+            from: ctx.accounts.player_token.to_account_info(),
+            to: ctx.accounts.escrow.to_account_info(),
+            authority: ctx.accounts.player.to_account_info(),
+        };
+        token::transfer(CpiContext::new(ctx.accounts.token_program.to_account_info(), transfer), game.wager_stake)
+    }
+    pub fn withdraw_stake(ctx: Context<WithdrawStake>) -> Result<()> {
+        let game_key = ctx.accounts.game.key();
+        let game = &mut ctx.accounts.game.load_mut()?;
+        game.withdraw_stake(ctx.accounts.player.key())?;
+        let seeds: &[&[&[u8]]] = &[&[ESCROW_SEED, game_key.as_ref(), &[game.escrow_bump]]];
+        let transfer = Transfer {
+            from: ctx.accounts.escrow.to_account_info(),
+            to: ctx.accounts.player_token.to_account_info(),
+            authority: ctx.accounts.escrow.to_account_info(),
+        };
+        token::transfer(CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), transfer, seeds), game.wager_stake)
+    }
+    pub fn settle(ctx: Context<Settle>) -> Result<()> {
+        let game_key = ctx.accounts.game.key();
+        let game = &mut ctx.accounts.game.load_mut()?;
+        let (white_payout, black_payout, fee) = game.settle(ctx.accounts.escrow.amount)?;
+        let seeds: &[&[&[u8]]] = &[&[ESCROW_SEED, game_key.as_ref(), &[game.escrow_bump]]];
+        for (to, amount) in [
+            (ctx.accounts.white_token.to_account_info(), white_payout),
+            (ctx.accounts.black_token.to_account_info(), black_payout),
+            (ctx.accounts.house_token.to_account_info(), fee),
+        ] {
+            if amount == 0 {
+                continue;
+            }
+            let transfer = Transfer {
+                from: ctx.accounts.escrow.to_account_info(),
+                to,
+                authority: ctx.accounts.escrow.to_account_info(),
+            };
+            token::transfer(CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), transfer, seeds), amount)?;
+        }
+        Ok(())
     }
 }
 
 
-#[account]
+// Zero copy, so a move only touches the fields it uses. The move list is stored after the
+// struct, one Turn (2 bytes) per half-move, and the account grows to fit it.
+// Fields go from the widest alignment down, so the layout has no padding and matches the IDL.
+#[account(zero_copy)]
 pub struct Game {
     authority: Pubkey,             // 32
     white_player: Pubkey,          // 32
     black_player: Pubkey,          // 32
-    past_states: [u32; 256],       // 32*256 = 8192
-    curr_board: GameState,         // ~560
-    num_moves: u16, // half-moves  // 16
-    status: GameCodes,             // 4
-    white_draw_open: bool,         // 1
-    black_draw_open: bool,         // 1
-    white_time_left: i64, // sec   // 64
-    black_time_left: i64, // sec   // 64
-    white_bonus_time: u32, // sec  // 32
-    black_bonus_time: u32, // sec  // 32
-    last_move: i64, // sec         // 64
-}
-impl Default for Game {
-    fn default() -> Self {
-        Self {
-            authority: Default::default(),
-            white_player: Default::default(),
-            black_player: Default::default(),
-            past_states: [0; 256],
-            curr_board: Default::default(),
-            num_moves: 0,
-            status: GameCodes::Active,
-            white_draw_open: false,
-            black_draw_open: false,
-            white_time_left: 0,
-            black_time_left: 0,
-            white_bonus_time: 0,
-            black_bonus_time: 0,
-            last_move: 0,
-        }
+    payer: Pubkey, // paid the rent, gets it back on close // 32
+    wager_mint: Pubkey, // optional stake each player deposits in the game's escrow // 32
+    wager_stake: u64, // 0 = no wager // 8
+    white_time_left: i64, // sec   // 8
+    black_time_left: i64, // sec   // 8
+    last_move: i64, // sec         // 8
+    white_rent: u64, // lamports white added for the move list, refunded on close // 8
+    black_rent: u64,               // 8
+    white_session: SessionKey,     // 40
+    black_session: SessionKey,     // 40
+    white_vacation: Vacation, // correspondence only // 24
+    black_vacation: Vacation,      // 24
+    periods: [TimePeriod; MAX_PERIODS], // after the first, for classical controls // 3*16
+    snapshots: [MoveSnapshot; TAKEBACK_WINDOW], // before each of the last half-moves, by num_moves % TAKEBACK_WINDOW // 8*80
+    recent_states: [u32; 128], // position hashes by num_moves % 128 // 4*128
+    white_bonus_time: u32, // sec  // 4
+    black_bonus_time: u32, // sec  // 4
+    num_moves: u16, // half-moves  // 2
+    max_bank_days: u16, // correspondence settings of time_mode // 2
+    vacation_days: u16,            // 2
+    wager_max_fee_bps: u16, // highest house fee the players agree to pay // 2
+    house_fee_bps: u16, // fixed when both stakes are in // 2
+    white_next_draw_offer: u16, // num_moves from which white can offer a draw again // 2
+    black_next_draw_offer: u16,    // 2
+    white_premoves: [Premove; MAX_PREMOVES], // 8*6
+    black_premoves: [Premove; MAX_PREMOVES], // 8*6
+    board: PackedBoard,            // 35
+    status: u8, // GameCodes       // 1
+    white_draw_open: u8, // bool, until black moves // 1
+    black_draw_open: u8,           // 1
+    time_mode: u8, // TimeControlMode kind, how the bonus applies // 1
+    white_period: u8, // 0 = first period // 1
+    black_period: u8,              // 1
+    white_staked: u8, // bool      // 1
+    black_staked: u8,              // 1
+    escrow_bump: u8,               // 1
+    settled: u8, // bool           // 1
+    rated: u8, // bool             // 1
+    category: u8, // TimeCategory  // 1
+    finalized: u8, // bool, ratings updated // 1
+    result_pending: u8, // bool, tournament game not recorded yet // 1
+    takeback: u8, // half-moves the open takeback request undoes, 0 = none // 1
+    takeback_white: u8, // bool, white asked for it // 1
+    white_premove_root: u8, // entry the next premove follows, 0 = none // 1
+    black_premove_root: u8,        // 1
+    _padding: [u8; 5], // rounds the size up to the 8 byte alignment // 5
+}
+const _: () = assert!(std::mem::size_of::<Game>() == 1712);
+
+// Optional stake each player deposits in the game's escrow before the clock starts
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct WagerTerms {
+    pub mint: Pubkey,
+    pub stake: u64, // 0 = no wager
+    pub max_fee_bps: u16, // highest house fee the players agree to pay
+}
+
+// Position, clocks and draw offers before a half-move, so a takeback can restore them
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+#[repr(C)]
+pub struct MoveSnapshot {
+    white_time_left: i64,
+    black_time_left: i64,
+    white_bonus_time: u32,
+    black_bonus_time: u32,
+    replaced_state: u32, // recent_states entry the half-move overwrote
+    move_number: u16, // num_moves when it was taken
+    white_next_draw_offer: u16,
+    black_next_draw_offer: u16,
+    board: PackedBoard, // with castling rights, en passant square and half-move clock
+    white_period: u8,
+    black_period: u8,
+    white_draw_open: u8,
+    black_draw_open: u8,
+    _padding: [u8; 7],
+}
+const _: () = assert!(std::mem::size_of::<MoveSnapshot>() == 80);
+
+// Move played automatically when the opponent plays condition, with no time charged
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Premove {
+    pub after: u16, // 1-based entry this one follows, 0 = answers the opponent's next move
+    pub condition: u16, // opponent's move, 0 = any move
+    pub reply: u16,
+}
+const _: () = assert!(std::mem::size_of::<Premove>() == 6);
+
+// Ephemeral key allowed to play, offer draws and resign for one player in one game
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SessionKey {
+    key: Pubkey,
+    expires_at: i64, // unix timestamp
+}
+const _: () = assert!(std::mem::size_of::<SessionKey>() == 40);
+impl SessionKey {
+    fn authorizes(&self, signer: Pubkey, curr_time: i64) -> bool {
+        self.key != Pubkey::default() && self.key == signer && curr_time < self.expires_at
     }
 }
 impl Game {
+    #[allow(clippy::too_many_arguments)]
+    fn setup(&mut self, authority: Pubkey, white_player: Pubkey, black_player: Pubkey, white_time: i64, black_time: i64, white_bonus: u32, black_bonus: u32, time_mode: TimeControlMode, wager: WagerTerms, rated: bool) {
+        self.authority = authority;
+        self.white_player = white_player;
+        self.black_player = black_player;
+        self.white_time_left = white_time;
+        self.black_time_left = black_time;
+        self.white_bonus_time = white_bonus;
+        self.black_bonus_time = black_bonus;
+        self.set_time_mode(time_mode);
+        if let TimeControlMode::Correspondence { vacation_days, .. } = time_mode {
+            self.white_vacation.days_left = vacation_days;
+            self.black_vacation.days_left = vacation_days;
+        }
+        self.wager_mint = wager.mint;
+        self.wager_stake = wager.stake;
+        self.wager_max_fee_bps = wager.max_fee_bps;
+        // A key playing itself is never rated
+        self.rated = u8::from(rated && white_player != black_player);
+        self.category = TimeCategory::of(white_time.max(black_time), white_bonus.max(black_bonus)) as u8;
+        let board = GameState::default();
+        self.board = PackedBoard::from(&board);
+        self.recent_states[0] = board.small_hash();
+        self.last_move = Clock::get().unwrap().unix_timestamp;
+    }
+    // Enums are stored as their codes, so that any account data is a valid Game
+    fn status(&self) -> GameCodes {
+        GameCodes::from_code(self.status).unwrap_or(GameCodes::Invalid)
+    }
+    fn set_status(&mut self, status: GameCodes) {
+        self.status = status as u8;
+    }
+    fn time_mode(&self) -> TimeControlMode {
+        TimeControlMode::from_code(self.time_mode, self.max_bank_days, self.vacation_days).unwrap_or_default()
+    }
+    fn set_time_mode(&mut self, time_mode: TimeControlMode) {
+        let (kind, max_bank_days, vacation_days) = time_mode.to_code();
+        self.time_mode = kind;
+        self.max_bank_days = max_bank_days;
+        self.vacation_days = vacation_days;
+    }
+    fn category(&self) -> TimeCategory {
+        TimeCategory::from_code(self.category).unwrap_or_default()
+    }
     fn is_active(&self) -> bool {
-        self.status == GameCodes::Active
+        self.status() == GameCodes::Active
+    }
+    fn created_event(&self, game: Pubkey) -> GameCreated {
+        GameCreated {
+            game,
+            authority: self.authority,
+            white_player: self.white_player,
+            black_player: self.black_player,
+            white_time: self.white_time_left,
+            black_time: self.black_time_left,
+            white_bonus: self.white_bonus_time,
+            black_bonus: self.black_bonus_time,
+            time_mode: self.time_mode(),
+            periods: self.periods,
+            rated: self.rated != 0,
+        }
+    }
+    fn emit_move(&self, game: Pubkey, turn: u16) {
+        emit!(MovePlayed {
+            game,
+            move_number: self.num_moves,
+            turn: Turn { turn },
+            white_time_left: self.white_time_left,
+            black_time_left: self.black_time_left,
+            white_period: self.white_period,
+            black_period: self.black_period,
+        });
+    }
+    fn emit_draw_changes(&self, game: Pubkey, offers: [bool; 2]) {
+        // offers are white's and black's before the instruction
+        for (white, was_open, is_open) in [(true, offers[0], self.white_draw_open != 0), (false, offers[1], self.black_draw_open != 0)] {
+            if is_open && !was_open {
+                emit!(DrawOffered { game, white });
+            } else if was_open && !is_open {
+                emit!(DrawWithdrawn { game, white });
+            }
+        }
+    }
+    fn emit_if_ended(&self, game: Pubkey, was_active: bool) {
+        if let (true, Some(reason)) = (was_active, EndReason::of(self.status())) {
+            emit!(GameEnded { game, status: self.status(), reason });
+        }
+    }
+    fn is_started(&self) -> bool {
+        // Wagered games start when both stakes are in escrow
+        self.wager_stake == 0 || (self.white_staked != 0 && self.black_staked != 0)
     }
     fn is_timeout(&self, curr_time: i64) -> bool {
+        if !self.is_started() {
+            return false;
+        }
         let is_white: bool = self.num_moves % 2 == 0;
-        let time_diff = curr_time - self.last_move;
-        (is_white && time_diff > self.white_time_left.into()) || 
-        (!is_white && time_diff > self.black_time_left.into())
+        let time_diff = self.thinking_time(is_white, curr_time);
+        if is_white {
+            self.time_mode().is_flagged(self.white_time_left, time_diff, self.white_bonus_time)
+        } else {
+            self.time_mode().is_flagged(self.black_time_left, time_diff, self.black_bonus_time)
+        }
+    }
+    fn can_abort(&self) -> bool {
+        // Before both sides have moved. Tournament games have to be played out.
+        self.is_active() && self.num_moves < 2 && self.result_pending == 0
+    }
+    fn first_move_expired(&self, curr_time: i64) -> bool {
+        // A side that does not make its first move in time aborts the game instead of losing it.
+        // Correspondence players get their whole clock.
+        if !self.is_started() || !self.can_abort() {
+            return false;
+        }
+        let is_white: bool = self.num_moves % 2 == 0;
+        let time_left = if is_white { self.white_time_left } else { self.black_time_left };
+        let window = match self.time_mode() {
+            TimeControlMode::Correspondence { .. } => time_left,
+            _ => FIRST_MOVE_TIME.min(time_left),
+        };
+        self.thinking_time(is_white, curr_time) > window
+    }
+    fn thinking_time(&self, is_white: bool, curr_time: i64) -> i64 {
+        // Seconds since the last move, leaving out the vacation of the side to move
+        let vacation = if is_white { self.white_vacation } else { self.black_vacation };
+        curr_time - self.last_move - vacation.overlap(self.last_move, curr_time)
+    }
+    fn player_side(&self, signer: Pubkey, curr_time: i64) -> Result<bool> {
+        // Returns true for white. The signer is a player or one of their live session keys.
+        // If one key acts for both sides it acts for the side to move.
+        let white_to_move: bool = self.num_moves % 2 == 0;
+        let (white_session, black_session) = (self.white_session, self.black_session);
+        let is_white = signer == self.white_player || white_session.authorizes(signer, curr_time);
+        let is_black = signer == self.black_player || black_session.authorizes(signer, curr_time);
+        match (is_white, is_black) {
+            (true, true) => Ok(white_to_move),
+            (true, false) => Ok(true),
+            (false, true) => Ok(false),
+            (false, false) => err!(ChessError::NotAPlayer),
+        }
+    }
+    fn player_side_owner(&self, signer: Pubkey) -> Result<bool> {
+        // Same as player_side for the players' own keys only. Session keys can just play,
+        // offer draws and resign.
+        match (signer == self.white_player, signer == self.black_player) {
+            (true, true) => Ok(self.num_moves % 2 == 0),
+            (true, false) => Ok(true),
+            (false, true) => Ok(false),
+            (false, false) => err!(ChessError::NotAPlayer),
+        }
     }
-    fn resign(&mut self, is_white: bool) -> Result<()> {
+    fn authorize_session(&mut self, player: Pubkey, session: SessionKey) -> Result<()> {
+        // Only the player's own key can change their session key, never a session key
+        if player != self.white_player && player != self.black_player {
+            return err!(ChessError::NotAPlayer);
+        }
+        if player == self.white_player {
+            self.white_session = session;
+        }
+        if player == self.black_player {
+            self.black_session = session;
+        }
+        Ok(())
+    }
+    fn request_takeback(&mut self, player: Pubkey) -> Result<bool> {
+        // Returns true for white. Asks to undo the requester's last move, and the opponent's
+        // reply if there is one.
+        if !self.is_active() {
+            return err!(ChessError::GameAlreadyOver);
+        }
+        // Only casual games, a takeback would change a rating, a pot or a standing
+        if self.rated != 0 || self.wager_stake > 0 || self.result_pending != 0 {
+            return err!(ChessError::TakebacksNotAllowed);
+        }
+        let is_white = self.player_side_owner(player)?;
+        let half_moves = if is_white == (self.num_moves % 2 == 0) { 2 } else { 1 };
+        if self.num_moves < half_moves {
+            return err!(ChessError::NothingToTakeBack);
+        }
+        self.snapshot(self.num_moves - half_moves)?;
+        self.takeback = half_moves as u8;
+        self.takeback_white = u8::from(is_white);
+        Ok(is_white)
+    }
+    fn accept_takeback(&mut self, player: Pubkey) -> Result<()> {
+        if !self.is_active() {
+            return err!(ChessError::GameAlreadyOver);
+        }
+        if self.takeback == 0 || self.player_side_owner(player)? == (self.takeback_white != 0) {
+            return err!(ChessError::NoTakebackRequested);
+        }
+        let curr_time = Clock::get().unwrap().unix_timestamp;
+        self.take_back()?;
+        self.last_move = curr_time;
+        Ok(())
+    }
+    fn decline_takeback(&mut self, player: Pubkey) -> Result<()> {
+        // Either side can end the request, the opponent declining or the requester withdrawing
+        self.player_side_owner(player)?;
+        if self.takeback == 0 {
+            return err!(ChessError::NoTakebackRequested);
+        }
+        self.takeback = 0;
+        Ok(())
+    }
+    fn snapshot(&self, move_number: u16) -> Result<MoveSnapshot> {
+        // Snapshot taken when the game had move_number half-moves. Later moves overwrite the
+        // older slots, so takebacks in a row cannot reach back more than TAKEBACK_WINDOW.
+        let snapshots = self.snapshots;
+        let snapshot = snapshots[usize::from(move_number) % TAKEBACK_WINDOW];
+        if snapshot.move_number != move_number || move_number >= self.num_moves {
+            return err!(ChessError::TakebackTooFar);
+        }
+        Ok(snapshot)
+    }
+    fn take_back(&mut self) -> Result<()> {
+        // Undoes the requested half-moves from the snapshots taken before them
+        let num_moves = self.num_moves - u16::from(self.takeback);
+        let snapshot = self.snapshot(num_moves)?;
+        // Each undone half-move wrote its position hash over an older one
+        let mut recent_states = self.recent_states;
+        for move_number in (num_moves..self.num_moves).rev() {
+            recent_states[usize::from(move_number + 1) % RECENT_STATES] = self.snapshot(move_number)?.replaced_state;
+        }
+        self.recent_states = recent_states;
+        self.board = snapshot.board;
+        self.num_moves = num_moves;
+        self.white_time_left = snapshot.white_time_left;
+        self.black_time_left = snapshot.black_time_left;
+        self.white_bonus_time = snapshot.white_bonus_time;
+        self.black_bonus_time = snapshot.black_bonus_time;
+        self.white_period = snapshot.white_period;
+        self.black_period = snapshot.black_period;
+        self.white_draw_open = snapshot.white_draw_open;
+        self.black_draw_open = snapshot.black_draw_open;
+        self.white_next_draw_offer = snapshot.white_next_draw_offer;
+        self.black_next_draw_offer = snapshot.black_next_draw_offer;
+        self.takeback = 0;
+        // Premoves were answers to moves that are gone
+        self.white_premoves = [Premove::default(); MAX_PREMOVES];
+        self.black_premoves = [Premove::default(); MAX_PREMOVES];
+        self.white_premove_root = 0;
+        self.black_premove_root = 0;
+        Ok(())
+    }
+    fn abort(&mut self, player: Pubkey) -> Result<()> {
+        if !self.can_abort() {
+            return err!(ChessError::CannotAbort);
+        }
+        self.player_side_owner(player)?;
+        self.end_aborted();
+        Ok(())
+    }
+    fn end_aborted(&mut self) {
+        // No game: stakes are returned in full and ratings are left alone
+        self.set_status(GameCodes::Aborted);
+        self.rated = 0;
+    }
+    fn take_vacation(&mut self, player: Pubkey, days: u16, curr_time: i64) -> Result<bool> {
+        // Returns true for white. Stops the player's clock for the given number of days.
+        if !matches!(self.time_mode(), TimeControlMode::Correspondence { .. }) {
+            return err!(ChessError::NotCorrespondence);
+        }
+        if !self.is_active() {
+            return err!(ChessError::GameAlreadyOver);
+        }
+        let is_white = self.player_side_owner(player)?;
+        let mut vacation = if is_white { self.white_vacation } else { self.black_vacation };
+        if is_white == (self.num_moves % 2 == 0) {
+            // Keep the pause from an earlier vacation during this move
+            self.last_move += vacation.overlap(self.last_move, curr_time);
+        }
+        if !vacation.declare(days, curr_time) {
+            return err!(ChessError::VacationUnavailable);
+        }
+        if is_white {
+            self.white_vacation = vacation;
+        } else {
+            self.black_vacation = vacation;
+        }
+        Ok(is_white)
+    }
+    fn resign(&mut self, player: Pubkey) -> Result<()> {
         if !self.is_active() {
             return err!(ChessError::GameAlreadyOver);
         }
+        let curr_time = Clock::get().unwrap().unix_timestamp;
+        let is_white = self.player_side(player, curr_time)?;
         if is_white {
-            self.status = GameCodes::BlackWinResignation;
+            self.set_status(GameCodes::BlackWinResignation);
         } else {
-            self.status = GameCodes::WhiteWinResignation;
+            self.set_status(GameCodes::WhiteWinResignation);
         }
         Ok(())
     }
-    fn update_draw(&mut self, is_white: bool, is_draw: bool) -> Result<()> {
+    fn update_draw(&mut self, player: Pubkey, is_draw: bool) -> Result<()> {
         if !self.is_active() {
             return err!(ChessError::GameAlreadyOver);
         }
+        let curr_time = Clock::get().unwrap().unix_timestamp;
+        let is_white = self.player_side(player, curr_time)?;
+        if is_draw {
+            self.offer_draw(is_white)
+        } else {
+            if is_white {
+                self.white_draw_open = 0;
+            } else {
+                self.black_draw_open = 0;
+            }
+            Ok(())
+        }
+    }
+    fn offer_draw(&mut self, is_white: bool) -> Result<()> {
+        // The offer stands until the opponent moves. Accepting an offer is never rate limited.
+        let (own_open, opponent_open, next_offer) = if is_white {
+            (self.white_draw_open != 0, self.black_draw_open != 0, self.white_next_draw_offer)
+        } else {
+            (self.black_draw_open != 0, self.white_draw_open != 0, self.black_next_draw_offer)
+        };
+        if own_open {
+            return Ok(());
+        }
+        if !opponent_open && self.num_moves < next_offer {
+            return err!(ChessError::DrawOfferTooSoon);
+        }
         if is_white {
-            self.white_draw_open = is_draw;
+            self.white_draw_open = 1;
+            self.white_next_draw_offer = self.num_moves + DRAW_OFFER_INTERVAL;
         } else {
-            self.black_draw_open = is_draw;
+            self.black_draw_open = 1;
+            self.black_next_draw_offer = self.num_moves + DRAW_OFFER_INTERVAL;
         }
-        if self.white_draw_open && self.black_draw_open {
-            self.status = GameCodes::DrawAgreement;
+        if self.white_draw_open != 0 && self.black_draw_open != 0 {
+            self.set_status(GameCodes::DrawAgreement);
         }
         Ok(())
     }
-    fn play(&mut self, turn: u16) -> Result<()> {
+    fn play(&mut self, player: Pubkey, turn: u16, offer_draw: bool) -> Result<()> {
         if !self.is_active() {
             return err!(ChessError::GameAlreadyOver);
         }
+        if !self.is_started() {
+            return err!(ChessError::StakesNotDeposited);
+        }
         let is_white: bool = self.num_moves % 2 == 0;
         let curr_time = Clock::get().unwrap().unix_timestamp;
-        let time_diff = curr_time - self.last_move;
-        let game_code: GameCodes;
-        if self.is_timeout(curr_time) {
-            game_code = timeout_game_code(&self.curr_board);
-        } else {
-            self.num_moves += 1;
-            let num_moves: usize = self.num_moves.into();
-            if num_moves >= self.past_states.len()-1 {
-                self.status = GameCodes::DrawMaxMoves;
-                return Ok(());
+        if self.player_side(player, curr_time)? != is_white {
+            return err!(ChessError::NotYourTurn);
+        }
+        // The flag has to be claimed with claim_timeout, a late move is not played
+        if self.first_move_expired(curr_time) || self.is_timeout(curr_time) {
+            return err!(ChessError::FlagFell);
+        }
+        self.apply_move(is_white, turn, self.thinking_time(is_white, curr_time), curr_time)?;
+        if self.is_active() && offer_draw {
+            self.offer_draw(is_white)?;
+        }
+        Ok(())
+    }
+    fn apply_move(&mut self, is_white: bool, turn: u16, time_diff: i64, curr_time: i64) -> Result<()> {
+        // Plays a move of the side to move that took time_diff seconds. Nothing changes if it is illegal.
+        let mut board = GameState::from(&self.board);
+        // Fields of the packed account cannot be borrowed, so work on copies
+        let mut recent_states = self.recent_states;
+        let num_moves = self.num_moves + 1;
+        let status = active_game_code(&mut board, turn, &mut recent_states, num_moves.into())
+            .map_err(|reason| error!(ChessError::from(reason)))?;
+        self.set_status(status);
+        let mut snapshots = self.snapshots;
+        snapshots[usize::from(self.num_moves) % TAKEBACK_WINDOW] = MoveSnapshot {
+            white_time_left: self.white_time_left,
+            black_time_left: self.black_time_left,
+            white_bonus_time: self.white_bonus_time,
+            black_bonus_time: self.black_bonus_time,
+            replaced_state: self.recent_states[usize::from(num_moves) % RECENT_STATES],
+            move_number: self.num_moves,
+            white_next_draw_offer: self.white_next_draw_offer,
+            black_next_draw_offer: self.black_next_draw_offer,
+            board: self.board,
+            white_period: self.white_period,
+            black_period: self.black_period,
+            white_draw_open: self.white_draw_open,
+            black_draw_open: self.black_draw_open,
+            _padding: [0; 7],
+        };
+        self.snapshots = snapshots;
+        self.takeback = 0;
+        self.num_moves = num_moves;
+        self.recent_states = recent_states;
+        self.board = PackedBoard::from(&board);
+        if is_white {
+            let (white_left, black_left) = self.time_mode().after_move(self.white_time_left, self.black_time_left, time_diff, self.white_bonus_time);
+            self.white_time_left = white_left;
+            self.black_time_left = black_left;
+        } else {
+            let (black_left, white_left) = self.time_mode().after_move(self.black_time_left, self.white_time_left, time_diff, self.black_bonus_time);
+            self.white_time_left = white_left;
+            self.black_time_left = black_left;
+        }
+        if self.is_active() {
+            self.enter_period(is_white);
+            // Moving declines the opponent's offer
+            if is_white {
+                self.black_draw_open = 0;
+            } else {
+                self.white_draw_open = 0;
+            }
+        }
+        self.last_move = curr_time;
+        Ok(())
+    }
+    fn set_premoves(&mut self, player: Pubkey, premoves: &[Premove]) -> Result<()> {
+        // Replaces the player's premoves, an empty list clears them. Each entry answers the
+        // opponent's next move, or the move after the entry it follows.
+        if !self.is_active() {
+            return err!(ChessError::GameAlreadyOver);
+        }
+        if premoves.len() > MAX_PREMOVES {
+            return err!(ChessError::InvalidPremoves);
+        }
+        for (index, premove) in premoves.iter().enumerate() {
+            if usize::from(premove.after) > index || premove.reply == 0 {
+                return err!(ChessError::InvalidPremoves);
             }
-            msg!("Turn #{}: {}",num_moves,turn);
-            game_code = active_game_code(&mut self.curr_board, turn, &mut self.past_states, num_moves);
         }
-        if game_code == GameCodes::Invalid {
-            return err!(ChessError::InvalidMove);
+        let is_white = self.player_side_owner(player)?;
+        let mut list = [Premove::default(); MAX_PREMOVES];
+        list[..premoves.len()].copy_from_slice(premoves);
+        if is_white {
+            self.white_premoves = list;
+            self.white_premove_root = 0;
         } else {
-            self.status = game_code;
+            self.black_premoves = list;
+            self.black_premove_root = 0;
         }
+        Ok(())
+    }
+    fn play_premoves(&mut self, game_key: Pubkey, turn: u16, curr_time: i64) -> Vec<u16> {
+        // Premoves answering turn, which can trigger the other side's premoves in turn. Stops
+        // after MAX_PREMOVE_REPLIES to stay within the compute budget, and the player to move
+        // goes on by hand. Returns turn and the replies played.
+        let mut played = vec![turn];
+        while played.len() <= MAX_PREMOVE_REPLIES {
+            match self.apply_premove(*played.last().unwrap(), curr_time) {
+                Some(reply) => {
+                    self.emit_move(game_key, reply);
+                    played.push(reply);
+                }
+                None => break,
+            }
+        }
+        played
+    }
+    fn apply_premove(&mut self, opponent_turn: u16, curr_time: i64) -> Option<u16> {
+        // Plays the side to move's premove answering opponent_turn, charging no time.
+        // Returns the move played. Premoves that do not match or are illegal are dropped.
+        if !self.is_active() {
+            return None;
+        }
+        let is_white: bool = self.num_moves % 2 == 0;
+        let (premoves, root) = if is_white {
+            (self.white_premoves, self.white_premove_root)
+        } else {
+            (self.black_premoves, self.black_premove_root)
+        };
+        let found = premoves.iter().position(|premove| {
+            premove.reply != 0 && premove.after == u16::from(root) && (premove.condition == 0 || premove.condition == opponent_turn)
+        });
+        let played = match found {
+            Some(index) => {
+                let reply = premoves[index].reply;
+                self.apply_move(is_white, reply, 0, curr_time).ok().map(|_| (index, reply))
+            }
+            None => None,
+        };
+        // Later premoves follow the one played
+        let (premoves, root) = match played {
+            Some((index, _)) => (premoves, index as u8 + 1),
+            None => ([Premove::default(); MAX_PREMOVES], 0),
+        };
         if is_white {
-            self.white_time_left = self.white_time_left - time_diff + i64::from(self.white_bonus_time);
+            self.white_premoves = premoves;
+            self.white_premove_root = root;
         } else {
-            self.black_time_left = self.black_time_left - time_diff + i64::from(self.black_bonus_time);
+            self.black_premoves = premoves;
+            self.black_premove_root = root;
+        }
+        played.map(|(_, reply)| reply)
+    }
+    fn enter_period(&mut self, is_white: bool) {
+        // Adds the next period's time once the mover reaches its move count
+        let periods = self.periods;
+        if is_white {
+            if let Some(period) = TimePeriod::starting(&periods, self.white_period, (self.num_moves + 1) / 2) {
+                self.white_time_left += period.time;
+                self.white_bonus_time = period.bonus;
+                self.white_period += 1;
+            }
+        } else if let Some(period) = TimePeriod::starting(&periods, self.black_period, self.num_moves / 2) {
+            self.black_time_left += period.time;
+            self.black_bonus_time = period.bonus;
+            self.black_period += 1;
+        }
+    }
+    fn deposit_stake(&mut self, player: Pubkey, house_fee_bps: u16, escrow_bump: u8) -> Result<()> {
+        if !self.is_active() {
+            return err!(ChessError::GameAlreadyOver);
+        }
+        if self.wager_stake == 0 {
+            return err!(ChessError::NoWager);
+        }
+        // The admin may have raised the fee since the game was set up
+        if house_fee_bps > self.wager_max_fee_bps {
+            return err!(ChessError::HouseFeeTooHigh);
+        }
+        // Only the players' own keys hold their tokens, so session keys are not accepted
+        if player == self.white_player && self.white_staked == 0 {
+            self.white_staked = 1;
+        } else if player == self.black_player && self.black_staked == 0 {
+            self.black_staked = 1;
+        } else if player == self.white_player || player == self.black_player {
+            return err!(ChessError::AlreadyStaked);
+        } else {
+            return err!(ChessError::NotAPlayer);
+        }
+        self.escrow_bump = escrow_bump;
+        if self.is_started() {
+            self.house_fee_bps = house_fee_bps;
+            self.last_move = Clock::get().unwrap().unix_timestamp;
+        }
+        Ok(())
+    }
+    fn withdraw_stake(&mut self, player: Pubkey) -> Result<()> {
+        // Lets a player take their stake back while the opponent has not deposited theirs
+        if self.is_started() {
+            return err!(ChessError::GameAlreadyStarted);
+        }
+        if player != self.white_player && player != self.black_player {
+            return err!(ChessError::NotAPlayer);
+        }
+        if player == self.white_player && self.white_staked != 0 {
+            self.white_staked = 0;
+        } else if player == self.black_player && self.black_staked != 0 {
+            self.black_staked = 0;
+        } else {
+            return err!(ChessError::NothingStaked);
+        }
+        Ok(())
+    }
+    fn settle(&mut self, pot: u64) -> Result<(u64, u64, u64)> {
+        // Returns the white, black and house shares of the escrow
+        if self.wager_stake == 0 || !self.is_started() {
+            return err!(ChessError::NoWager);
+        }
+        if self.is_active() {
+            return err!(ChessError::GameNotOver);
+        }
+        if self.settled != 0 {
+            return err!(ChessError::AlreadySettled);
+        }
+        self.settled = 1;
+        let fee = (u128::from(pot) * u128::from(self.house_fee_bps) / u128::from(BPS)) as u64;
+        let prize = pot - fee;
+        if self.status() == GameCodes::Aborted {
+            Ok((pot / 2, pot - pot / 2, 0))
+        } else if self.status().is_white_winner() {
+            Ok((prize, 0, fee))
+        } else if self.status().is_black_winner() {
+            Ok((0, prize, fee))
+        } else {
+            Ok((prize / 2, prize - prize / 2, fee))
+        }
+    }
+    fn finalize(&mut self) -> Result<f64> {
+        // Returns white's score for the rating update
+        if self.is_active() {
+            return err!(ChessError::GameNotOver);
+        }
+        if self.rated == 0 {
+            return err!(ChessError::GameNotRated);
+        }
+        if self.finalized != 0 {
+            return err!(ChessError::AlreadyFinalized);
+        }
+        self.finalized = 1;
+        if self.status().is_white_winner() {
+            Ok(1.0)
+        } else if self.status().is_black_winner() {
+            Ok(0.0)
+        } else {
+            Ok(0.5)
+        }
+    }
+    fn add_rent(&mut self, payer: Pubkey, amount: u64) -> Result<()> {
+        // Records who paid to grow the move list. Only the players are refunded separately,
+        // the game's payer gets the whole balance on close anyway
+        if payer == self.payer {
+            return Ok(());
+        }
+        if payer == self.white_player {
+            self.white_rent += amount;
+        } else if payer == self.black_player {
+            self.black_rent += amount;
+        } else {
+            return err!(ChessError::WrongRentPayer);
+        }
+        Ok(())
+    }
+    fn check_closable(&self) -> Result<()> {
+        // Closing must not lose anything still owed: stakes, ratings or a tournament result
+        if self.is_active() {
+            return err!(ChessError::GameNotOver);
+        }
+        if self.wager_stake > 0 && (self.white_staked != 0 || self.black_staked != 0) && self.settled == 0 {
+            return err!(ChessError::StakesNotSettled);
+        }
+        if self.rated != 0 && self.finalized == 0 {
+            return err!(ChessError::RatingsNotUpdated);
+        }
+        if self.result_pending != 0 {
+            return err!(ChessError::TournamentResultPending);
         }
         Ok(())
     }
     fn claim_timeout(&mut self) -> Result<()> {
+        if !self.is_active() {
+            return err!(ChessError::GameAlreadyOver);
+        }
         let curr_time = Clock::get().unwrap().unix_timestamp;
-        if self.is_timeout(curr_time) {
-            let game_code = timeout_game_code(&self.curr_board);
-            self.status = game_code;
+        if self.first_move_expired(curr_time) {
+            self.end_aborted();
+        } else if self.is_timeout(curr_time) {
+            let game_code = timeout_game_code(&GameState::from(&self.board));
+            self.set_status(game_code);
+        } else {
+            return err!(ChessError::NoTimeout);
         }
         Ok(())
     }
 }
 
+fn grow_account<'info>(account: AccountInfo<'info>, len: usize, payer: AccountInfo<'info>, system_program: AccountInfo<'info>) -> Result<u64> {
+    // Reallocates to len plus room for HISTORY_CHUNK more moves, topping up the rent from the payer.
+    // Returns the lamports paid.
+    if account.data_len() >= len {
+        return Ok(0);
+    }
+    let new_len = len + HISTORY_CHUNK * std::mem::size_of::<u16>();
+    let shortfall = Rent::get()?.minimum_balance(new_len).saturating_sub(account.lamports());
+    if shortfall > 0 {
+        invoke(&system_instruction::transfer(payer.key, account.key, shortfall), &[payer, account.clone(), system_program])?;
+    }
+    account.realloc(new_len, false)?;
+    Ok(shortfall)
+}
+
+// One per key, at [PROFILE_SEED, player]. num_games counts the games the player takes part
+// in, the n-th (counting from 0) is listed at [PLAYER_GAME_SEED, player, n as little endian u64].
+#[account]
+#[derive(Default)]
+pub struct Profile {
+    player: Pubkey,                // 32
+    num_games: u64,                // 64
+    bump: u8,                      // 8
+    ratings: [Rating; 4], // by TimeCategory // 4*288
+}
+impl Profile {
+    fn list_game(&mut self, entry: &mut PlayerGame, game: Pubkey) {
+        // entry must be at the player's next index
+        entry.game = game;
+        self.num_games += 1;
+    }
+}
+
+// Entry of a player's game index, see Profile
+#[account]
+#[derive(Default)]
+pub struct PlayerGame {
+    game: Pubkey,                  // 32
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    White,
+    Black,
+    Random,
+}
+impl Default for ColorChoice {
+    fn default() -> Self { ColorChoice::Random }
+}
+
+// Game offer waiting in the lobby; accepting it creates the Game
+#[account]
+#[derive(Default)]
+pub struct Challenge {
+    creator: Pubkey,               // 32
+    opponent: Pubkey, // default = anyone // 32
+    color: ColorChoice, // creator's  // 1
+    time: i64, // sec each         // 64
+    bonus: u32, // sec per move    // 32
+    time_mode: TimeControlMode,    // 1
+    created_at: i64,               // 64
+    wager: WagerTerms,             // 42
+    rated: bool,                   // 1
+}
+
+// What is left of a closed game, at [RECORD_SEED, game]
+#[account]
+#[derive(Default)]
+pub struct GameRecord {
+    game: Pubkey,                  // 32
+    white_player: Pubkey,          // 32
+    black_player: Pubkey,          // 32
+    status: GameCodes,             // 4
+    num_moves: u16, // half-moves  // 16
+    final_hash: u32, // GameState::small_hash of the last position // 32
+}
+
+// Program-wide settings for wagered games, at [HOUSE_SEED]
+#[account]
+#[derive(Default)]
+pub struct House {
+    admin: Pubkey, // receives fees // 32
+    fee_bps: u16, // of the pot    // 16
+    bump: u8,                      // 8
+}
+impl Challenge {
+    fn can_accept(&self, acceptor: Pubkey) -> bool {
+        acceptor != self.creator && (self.opponent == Pubkey::default() || self.opponent == acceptor)
+    }
+    fn is_expired(&self, curr_time: i64) -> bool {
+        curr_time > self.created_at + CHALLENGE_LIFETIME
+    }
+}
+
 #[derive(Accounts)]
+pub struct CreateProfile<'info> {
+    #[account(init, seeds = [PROFILE_SEED, player.key().as_ref()], bump, payer = player)]
+    pub profile: Box<Account<'info, Profile>>,
+    #[account(mut)]
+    pub player: Signer<'info>,
+    pub system_program: Program<'info, System>
+}
+
+// The game is at the white player's next index, so white and black must differ
+#[derive(Accounts)]
+#[instruction(white_player: Pubkey, black_player: Pubkey)]
 pub struct SetupGame<'info> {
-    #[account(init, payer = authority)]
-    pub game: Box<Account<'info, Game>>,
+    #[account(mut, seeds = [PROFILE_SEED, white_player.as_ref()], bump = white_profile.bump)]
+    pub white_profile: Box<Account<'info, Profile>>,
+    #[account(mut, seeds = [PROFILE_SEED, black_player.as_ref()], bump = black_profile.bump)]
+    pub black_profile: Box<Account<'info, Profile>>,
+    #[account(
+        init,
+        seeds = [GAME_SEED, white_player.as_ref(), white_profile.num_games.to_le_bytes().as_ref()],
+        bump,
+        payer = authority,
+        space = MOVES_OFFSET, // Header only, play grows the move list
+    )]
+    pub game: AccountLoader<'info, Game>,
+    #[account(
+        init,
+        seeds = [PLAYER_GAME_SEED, white_player.as_ref(), white_profile.num_games.to_le_bytes().as_ref()],
+        bump,
+        payer = authority,
+    )]
+    pub white_entry: Box<Account<'info, PlayerGame>>,
+    #[account(
+        init,
+        seeds = [PLAYER_GAME_SEED, black_player.as_ref(), black_profile.num_games.to_le_bytes().as_ref()],
+        bump,
+        payer = authority,
+    )]
+    pub black_entry: Box<Account<'info, PlayerGame>>,
+    // Both players agree to the game, so nobody can fill someone else's game list
+    #[account(address = white_player)]
+    pub white: Signer<'info>,
+    #[account(address = black_player)]
+    pub black: Signer<'info>,
     #[account(mut)]
     pub authority: Signer<'info>,
     pub system_program: Program<'info, System>
 }
 
 
+#[derive(Accounts)]
+pub struct CreateChallenge<'info> {
+    #[account(init, payer = creator)]
+    pub challenge: Box<Account<'info, Challenge>>,
+    #[account(mut)]
+    pub creator: Signer<'info>,
+    pub system_program: Program<'info, System>
+}
+
+#[derive(Accounts)]
+pub struct AcceptChallenge<'info> {
+    #[account(
+        mut,
+        has_one = creator,
+        close = creator,
+        constraint = challenge.can_accept(acceptor.key()) @ ChessError::CannotAcceptChallenge,
+    )]
+    pub challenge: Box<Account<'info, Challenge>>,
+    /// CHECK: only receives the challenge rent, checked by has_one
+    #[account(mut)]
+    pub creator: AccountInfo<'info>,
+    #[account(mut, seeds = [PROFILE_SEED, creator.key().as_ref()], bump = creator_profile.bump)]
+    pub creator_profile: Box<Account<'info, Profile>>,
+    #[account(mut, seeds = [PROFILE_SEED, acceptor.key().as_ref()], bump = acceptor_profile.bump)]
+    pub acceptor_profile: Box<Account<'info, Profile>>,
+    #[account(
+        init,
+        seeds = [GAME_SEED, creator.key().as_ref(), creator_profile.num_games.to_le_bytes().as_ref()],
+        bump,
+        payer = acceptor,
+        space = MOVES_OFFSET, // Header only, play grows the move list
+    )]
+    pub game: AccountLoader<'info, Game>,
+    #[account(
+        init,
+        seeds = [PLAYER_GAME_SEED, creator.key().as_ref(), creator_profile.num_games.to_le_bytes().as_ref()],
+        bump,
+        payer = acceptor,
+    )]
+    pub creator_entry: Box<Account<'info, PlayerGame>>,
+    #[account(
+        init,
+        seeds = [PLAYER_GAME_SEED, acceptor.key().as_ref(), acceptor_profile.num_games.to_le_bytes().as_ref()],
+        bump,
+        payer = acceptor,
+    )]
+    pub acceptor_entry: Box<Account<'info, PlayerGame>>,
+    #[account(mut)]
+    pub acceptor: Signer<'info>,
+    pub system_program: Program<'info, System>
+}
+
+#[derive(Accounts)]
+pub struct CancelChallenge<'info> {
+    #[account(mut, has_one = creator, close = creator)]
+    pub challenge: Box<Account<'info, Challenge>>,
+    #[account(mut)]
+    pub creator: Signer<'info>,
+}
+
 #[derive(Accounts)]
 pub struct Play<'info> {
-    #[account(mut, has_one = authority)]
-    pub game: Box<Account<'info, Game>>,
-    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub game: AccountLoader<'info, Game>,
+    pub player: Signer<'info>,
+    // Pays the rent when the move list needs a bigger account: the game's payer or a player
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>
+}
+
+#[derive(Accounts)]
+pub struct SetPremoves<'info> {
+    #[account(mut)]
+    pub game: AccountLoader<'info, Game>,
+    pub player: Signer<'info>,
 }
 
 #[derive(Accounts)]
 pub struct UpdateDraw<'info> {
-    #[account(mut, has_one = authority)]
-    pub game: Box<Account<'info, Game>>,
-    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub game: AccountLoader<'info, Game>,
+    pub player: Signer<'info>,
 }
 
 #[derive(Accounts)]
 pub struct Resign<'info> {
-    #[account(mut, has_one = authority)]
-    pub game: Box<Account<'info, Game>>,
-    pub authority: Signer<'info>,
+    #[account(mut)]
+    pub game: AccountLoader<'info, Game>,
+    pub player: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Takeback<'info> {
+    #[account(mut)]
+    pub game: AccountLoader<'info, Game>,
+    pub player: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Abort<'info> {
+    #[account(mut)]
+    pub game: AccountLoader<'info, Game>,
+    pub player: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ManageSession<'info> {
+    #[account(mut)]
+    pub game: AccountLoader<'info, Game>,
+    pub player: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct TakeVacation<'info> {
+    #[account(mut)]
+    pub game: AccountLoader<'info, Game>,
+    pub player: Signer<'info>,
 }
 
 #[derive(Accounts)]
 pub struct Timeout<'info> {
     #[account(mut)]
-    pub game: Box<Account<'info, Game>>,
+    pub game: AccountLoader<'info, Game>,
     pub reporter: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct CreateTournament<'info> {
+    #[account(init, payer = organizer)]
+    pub tournament: Box<Account<'info, Tournament>>,
+    #[account(mut)]
+    pub organizer: Signer<'info>,
+    pub system_program: Program<'info, System>
+}
+
+#[derive(Accounts)]
+pub struct RegisterPlayer<'info> {
+    #[account(mut)]
+    pub tournament: Box<Account<'info, Tournament>>,
+    pub player: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct StartRound<'info> {
+    #[account(mut, has_one = organizer)]
+    pub tournament: Box<Account<'info, Tournament>>,
+    pub organizer: Signer<'info>,
+}
+
+// Anyone can pay to create a paired game
+#[derive(Accounts)]
+#[instruction(board: u8)]
+pub struct CreateTournamentGame<'info> {
+    #[account(mut)]
+    pub tournament: Box<Account<'info, Tournament>>,
+    #[account(
+        init,
+        seeds = [GAME_SEED, tournament.key().as_ref(), &[tournament.current_round()], &[board], &[tournament.game_number(board)]],
+        bump,
+        payer = payer,
+        space = MOVES_OFFSET, // Header only, play grows the move list
+    )]
+    pub game: AccountLoader<'info, Game>,
+    #[account(mut, seeds = [PROFILE_SEED, tournament.board_player(board, false).as_ref()], bump = first_profile.bump)]
+    pub first_profile: Box<Account<'info, Profile>>,
+    #[account(mut, seeds = [PROFILE_SEED, tournament.board_player(board, true).as_ref()], bump = second_profile.bump)]
+    pub second_profile: Box<Account<'info, Profile>>,
+    #[account(
+        init,
+        seeds = [PLAYER_GAME_SEED, tournament.board_player(board, false).as_ref(), first_profile.num_games.to_le_bytes().as_ref()],
+        bump,
+        payer = payer,
+    )]
+    pub first_entry: Box<Account<'info, PlayerGame>>,
+    #[account(
+        init,
+        seeds = [PLAYER_GAME_SEED, tournament.board_player(board, true).as_ref(), second_profile.num_games.to_le_bytes().as_ref()],
+        bump,
+        payer = payer,
+    )]
+    pub second_entry: Box<Account<'info, PlayerGame>>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>
+}
+
+#[derive(Accounts)]
+pub struct RecordTournamentResult<'info> {
+    #[account(mut)]
+    pub tournament: Box<Account<'info, Tournament>>,
+    #[account(mut)]
+    pub game: AccountLoader<'info, Game>,
+}
+
+// Only the account that paid for a finished game can close it
+#[derive(Accounts)]
+pub struct CloseGame<'info> {
+    #[account(mut, has_one = payer, has_one = white_player, has_one = black_player, close = payer)]
+    pub game: AccountLoader<'info, Game>,
+    #[account(init, seeds = [RECORD_SEED, game.key().as_ref()], bump, payer = payer)]
+    pub record: Box<Account<'info, GameRecord>>,
+    /// CHECK: only receives the rent white paid for the move list, checked by has_one
+    #[account(mut)]
+    pub white_player: AccountInfo<'info>,
+    /// CHECK: only receives the rent black paid for the move list, checked by has_one
+    #[account(mut)]
+    pub black_player: AccountInfo<'info>,
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>
+}
+
+// Anyone can add to the prize pool, in the mint of the first funding
+#[derive(Accounts)]
+pub struct FundPrizes<'info> {
+    #[account(mut)]
+    pub tournament: Box<Account<'info, Tournament>>,
+    pub mint: Box<Account<'info, Mint>>,
+    #[account(
+        init_if_needed,
+        seeds = [PRIZE_SEED, tournament.key().as_ref()],
+        bump,
+        payer = funder,
+        token::mint = mint,
+        token::authority = prize_escrow,
+    )]
+    pub prize_escrow: Box<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = funder_token.owner == funder.key() && funder_token.mint == mint.key())]
+    pub funder_token: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub funder: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+// Anyone can distribute the prizes of a finished tournament
+#[derive(Accounts)]
+pub struct DistributePrizes<'info> {
+    #[account(mut)]
+    pub tournament: Box<Account<'info, Tournament>>,
+    #[account(mut, seeds = [PRIZE_SEED, tournament.key().as_ref()], bump = tournament.prize_bump)]
+    pub prize_escrow: Box<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = organizer_token.owner == tournament.organizer && organizer_token.mint == tournament.prize_mint)]
+    pub organizer_token: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Anyone can finalize a finished rated game
+#[derive(Accounts)]
+pub struct FinalizeGame<'info> {
+    #[account(mut)]
+    pub game: AccountLoader<'info, Game>,
+    #[account(mut, seeds = [PROFILE_SEED, game.load()?.white_player.as_ref()], bump = white_profile.bump)]
+    pub white_profile: Box<Account<'info, Profile>>,
+    #[account(mut, seeds = [PROFILE_SEED, game.load()?.black_player.as_ref()], bump = black_profile.bump)]
+    pub black_profile: Box<Account<'info, Profile>>,
+}
+
+// Only the program's upgrade authority can set up the house
+#[derive(Accounts)]
+pub struct InitHouse<'info> {
+    #[account(init, seeds = [HOUSE_SEED], bump, payer = admin)]
+    pub house: Box<Account<'info, House>>,
+    #[account(
+        constraint = program_data.key() == Pubkey::find_program_address(&[crate::ID.as_ref()], &bpf_loader_upgradeable::ID).0,
+        constraint = program_data.upgrade_authority_address == Some(admin.key()) @ ChessError::NotUpgradeAuthority)]
+    pub program_data: Box<Account<'info, ProgramData>>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>
+}
+
+#[derive(Accounts)]
+pub struct SetHouseFee<'info> {
+    #[account(mut, seeds = [HOUSE_SEED], bump = house.bump, has_one = admin)]
+    pub house: Box<Account<'info, House>>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DepositStake<'info> {
+    #[account(mut)]
+    pub game: AccountLoader<'info, Game>,
+    #[account(seeds = [HOUSE_SEED], bump = house.bump)]
+    pub house: Box<Account<'info, House>>,
+    #[account(constraint = mint.key() == game.load()?.wager_mint)]
+    pub mint: Box<Account<'info, Mint>>,
+    #[account(
+        init_if_needed,
+        seeds = [ESCROW_SEED, game.key().as_ref()],
+        bump,
+        payer = player,
+        token::mint = mint,
+        token::authority = escrow,
+    )]
+    pub escrow: Box<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = player_token.owner == player.key() && player_token.mint == mint.key())]
+    pub player_token: Box<Account<'info, TokenAccount>>,
+    #[account(mut)]
+    pub player: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawStake<'info> {
+    #[account(mut)]
+    pub game: AccountLoader<'info, Game>,
+    #[account(mut, seeds = [ESCROW_SEED, game.key().as_ref()], bump = game.load()?.escrow_bump)]
+    pub escrow: Box<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = player_token.owner == player.key() && player_token.mint == game.load()?.wager_mint)]
+    pub player_token: Box<Account<'info, TokenAccount>>,
+    pub player: Signer<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+// Anyone can settle a finished game; the payouts only go to the players and the house
+#[derive(Accounts)]
+pub struct Settle<'info> {
+    #[account(mut)]
+    pub game: AccountLoader<'info, Game>,
+    #[account(mut, seeds = [ESCROW_SEED, game.key().as_ref()], bump = game.load()?.escrow_bump)]
+    pub escrow: Box<Account<'info, TokenAccount>>,
+    #[account(seeds = [HOUSE_SEED], bump = house.bump)]
+    pub house: Box<Account<'info, House>>,
+    #[account(mut, constraint = white_token.owner == game.load()?.white_player && white_token.mint == game.load()?.wager_mint)]
+    pub white_token: Box<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = black_token.owner == game.load()?.black_player && black_token.mint == game.load()?.wager_mint)]
+    pub black_token: Box<Account<'info, TokenAccount>>,
+    #[account(mut, constraint = house_token.owner == house.admin && house_token.mint == game.load()?.wager_mint)]
+    pub house_token: Box<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
 #[error_code]
 pub enum ChessError {
-    InvalidMove,
+    InvalidMove, // No longer returned, illegal moves report why. Kept so the codes keep their values
     GameAlreadyOver,
-}
\ No newline at end of file
+    NotAPlayer,
+    NotYourTurn,
+    CannotAcceptChallenge,
+    #[msg("The challenge is more than a week old, it can only be cancelled")]
+    ChallengeExpired,
+    InvalidHouseFee,
+    NoWager,
+    AlreadyStaked,
+    StakesNotDeposited,
+    GameAlreadyStarted,
+    GameNotOver,
+    AlreadySettled,
+    #[msg("Only the program's upgrade authority can set up the house")]
+    NotUpgradeAuthority,
+    #[msg("The house fee is above the most the wager terms accept")]
+    HouseFeeTooHigh,
+    #[msg("There is no stake to withdraw")]
+    NothingStaked,
+    GameNotRated,
+    AlreadyFinalized,
+    InvalidTournament,
+    RegistrationClosed,
+    TournamentFull,
+    AlreadyRegistered,
+    NotEnoughPlayers,
+    RoundNotFinished,
+    TournamentOver,
+    InvalidBoard,
+    WrongGame,
+    ResultAlreadyRecorded,
+    WrongPrizeMint,
+    TournamentNotFinished,
+    PrizesAlreadyPaid,
+    WrongPrizeAccount,
+    #[msg("Only the game's payer or a player can pay to grow the move list")]
+    WrongRentPayer,
+    StakesNotSettled,
+    RatingsNotUpdated,
+    TournamentResultPending,
+    #[msg("At most three later periods, each starting after more moves than the one before")]
+    InvalidTimeControl,
+    NotCorrespondence,
+    #[msg("Not enough vacation days left, or a vacation is already running")]
+    VacationUnavailable,
+    #[msg("Only games outside tournaments can be aborted, before both players have moved")]
+    CannotAbort,
+    #[msg("Takebacks are only for unrated games without a wager outside tournaments")]
+    TakebacksNotAllowed,
+    NothingToTakeBack,
+    #[msg("There is no takeback request from the opponent")]
+    NoTakebackRequested,
+    #[msg("A player can offer a draw again only ten half-moves after their last offer")]
+    DrawOfferTooSoon,
+    #[msg("The mover's time has run out, the game can only be ended with claim_timeout")]
+    FlagFell,
+    #[msg("The side to move still has time")]
+    NoTimeout,
+    #[msg("Illegal move: there is no such piece of the mover on the from square")]
+    WrongPiece,
+    #[msg("Illegal move: the target square holds one of the mover's pieces")]
+    OwnPieceOnTarget,
+    #[msg("Illegal move: the piece does not move that way")]
+    WrongDirection,
+    #[msg("Illegal move: the path is blocked")]
+    PathBlocked,
+    #[msg("Illegal move: a pawn can only move diagonally to capture")]
+    NothingToCapture,
+    #[msg("Illegal move: a pawn reaching the last rank has to promote")]
+    MissingPromotion,
+    #[msg("Illegal move: a pawn can only promote on the last rank")]
+    PromotionTooEarly,
+    #[msg("Illegal move: the king or that rook has moved")]
+    NoCastlingRight,
+    #[msg("Illegal move: cannot castle out of check")]
+    CastlingOutOfCheck,
+    #[msg("Illegal move: cannot castle through an attacked square")]
+    CastlingThroughCheck,
+    #[msg("Illegal move: it leaves the king in check")]
+    LeavesKingInCheck,
+    #[msg("At most eight premoves, each with a move and following an earlier entry")]
+    InvalidPremoves,
+    #[msg("No Swiss pairing covers every player this round")]
+    PairingFailed,
+    #[msg("Takebacks in a row can only reach back eight half-moves")]
+    TakebackTooFar,
+}
+impl From<IllegalMove> for ChessError {
+    fn from(reason: IllegalMove) -> Self {
+        match reason {
+            IllegalMove::WrongPiece => ChessError::WrongPiece,
+            IllegalMove::OwnPieceOnTarget => ChessError::OwnPieceOnTarget,
+            IllegalMove::WrongDirection => ChessError::WrongDirection,
+            IllegalMove::PathBlocked => ChessError::PathBlocked,
+            IllegalMove::NothingToCapture => ChessError::NothingToCapture,
+            IllegalMove::MissingPromotion => ChessError::MissingPromotion,
+            IllegalMove::PromotionTooEarly => ChessError::PromotionTooEarly,
+            IllegalMove::NoCastlingRight => ChessError::NoCastlingRight,
+            IllegalMove::CastlingOutOfCheck => ChessError::CastlingOutOfCheck,
+            IllegalMove::CastlingThroughCheck => ChessError::CastlingThroughCheck,
+            IllegalMove::LeavesKingInCheck => ChessError::LeavesKingInCheck,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::DAY;
+
+    impl Game {
+        // Casual game from the start position with ten minutes each
+        fn new_casual() -> Self {
+            // Same as a freshly created account, all fields are integers or byte arrays
+            let mut game: Game = anchor_lang::__private::bytemuck::Zeroable::zeroed();
+            game.white_player = Pubkey::new_unique();
+            game.black_player = Pubkey::new_unique();
+            game.board = PackedBoard::from(&GameState::default());
+            game.recent_states[0] = GameState::default().small_hash();
+            game.white_time_left = 600;
+            game.black_time_left = 600;
+            game
+        }
+
+        fn replay(&mut self, turns: &[u16]) {
+            // Sets the position reached by playing turns from the start position
+            let mut board = GameState::default();
+            let mut recent_states = [0; 128];
+            recent_states[0] = board.small_hash();
+            for (index, turn) in turns.iter().enumerate() {
+                let _ = active_game_code(&mut board, *turn, &mut recent_states, index + 1);
+            }
+            self.board = PackedBoard::from(&board);
+            self.recent_states = recent_states;
+            self.num_moves = turns.len() as u16;
+        }
+    }
+
+    fn ruy_lopez() -> Vec<u16> {
+        // Closed Ruy Lopez, Chigorin Defense, to 22. Nf1 Na5
+        let moves: [(u16, u16, u16, u16, u16); 44] = [(5, 1, 4, 3, 4), (5, 6, 4, 4, 4), (1, 0, 6, 2, 5), (1, 7, 1, 5, 2),
+            (2, 0, 5, 4, 1), (5, 6, 0, 5, 0), (2, 4, 1, 3, 0), (1, 7, 6, 5, 5), (4, 0, 4, 0, 6), (2, 7, 5, 6, 4),
+            (0, 0, 5, 0, 4), (5, 6, 1, 4, 1), (2, 3, 0, 2, 1), (5, 6, 3, 5, 3), (5, 1, 2, 2, 2), (4, 7, 4, 7, 6),
+            (5, 1, 7, 2, 7), (1, 5, 2, 4, 0), (2, 2, 1, 1, 2), (5, 6, 2, 4, 2), (5, 1, 3, 3, 3), (3, 7, 3, 6, 2),
+            (1, 0, 1, 1, 3), (5, 4, 2, 3, 3), (5, 2, 2, 3, 3), (1, 4, 0, 5, 2), (1, 1, 3, 2, 1), (5, 5, 0, 4, 0),
+            (2, 0, 2, 2, 4), (5, 4, 0, 3, 0), (1, 2, 1, 1, 3), (2, 7, 2, 6, 3), (0, 0, 0, 0, 2), (3, 6, 2, 6, 1),
+            (3, 0, 3, 1, 4), (0, 7, 5, 7, 4), (2, 1, 2, 2, 3), (0, 7, 0, 7, 2), (5, 1, 0, 2, 0), (5, 6, 7, 5, 7),
+            (2, 2, 3, 0, 1), (2, 6, 4, 7, 5), (1, 1, 3, 0, 5), (1, 5, 2, 4, 0)];
+        moves.iter()
+            .map(|(piece, from_rank, from_col, to_rank, to_col)| (piece << 12) + (from_rank << 9) + (from_col << 6) + (to_rank << 3) + to_col)
+            .collect()
+    }
+
+    fn fails_with<T>(result: Result<T>, error: ChessError) -> bool {
+        match result {
+            Err(anchor_lang::error::Error::AnchorError(e)) => e.error_code_number == u32::from(error),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn enums_are_stored_as_codes() {
+        let mut game = Game::new_casual();
+        assert!(game.status() == GameCodes::Active && game.time_mode() == TimeControlMode::Fischer);
+        let correspondence = TimeControlMode::Correspondence { max_bank_days: 3, vacation_days: 14 };
+        game.set_time_mode(correspondence);
+        assert!(game.time_mode() == correspondence);
+        game.set_status(GameCodes::Aborted);
+        assert!(game.status() == GameCodes::Aborted);
+        game.status = 200; // Never written by the program
+        assert!(game.status() == GameCodes::Invalid && !game.is_active());
+    }
+
+    #[test]
+    fn challenge_expiry() {
+        let creator = Pubkey::new_unique();
+        let acceptor = Pubkey::new_unique();
+        let challenge = Challenge { creator, created_at: 1_000, ..Challenge::default() };
+        assert!(challenge.can_accept(acceptor));
+        assert!(!challenge.can_accept(creator));
+        assert!(!challenge.is_expired(1_000));
+        assert!(!challenge.is_expired(1_000 + CHALLENGE_LIFETIME));
+        assert!(challenge.is_expired(1_001 + CHALLENGE_LIFETIME));
+    }
+
+    #[test]
+    fn withdrawing_needs_a_stake() {
+        let mut game = Game::new_casual();
+        game.wager_stake = 100;
+        game.white_staked = 1;
+        assert!(fails_with(game.withdraw_stake(Pubkey::new_unique()), ChessError::NotAPlayer));
+        assert!(fails_with(game.withdraw_stake(game.black_player), ChessError::NothingStaked));
+        assert!(game.withdraw_stake(game.white_player).is_ok());
+        assert!(fails_with(game.withdraw_stake(game.white_player), ChessError::NothingStaked));
+    }
+
+    #[test]
+    fn move_list_rent_is_recorded_per_player() {
+        let mut game = Game::new_casual();
+        game.payer = Pubkey::new_unique();
+        game.add_rent(game.payer, 100).unwrap();
+        game.add_rent(game.white_player, 200).unwrap();
+        game.add_rent(game.black_player, 300).unwrap();
+        game.add_rent(game.white_player, 400).unwrap();
+        let (white_rent, black_rent) = (game.white_rent, game.black_rent);
+        assert_eq!((white_rent, black_rent), (600, 300));
+        assert!(fails_with(game.add_rent(Pubkey::new_unique(), 100), ChessError::WrongRentPayer));
+    }
+
+    #[test]
+    fn session_keys_only_play_draw_and_resign() {
+        let mut game = Game::new_casual();
+        let session = Pubkey::new_unique();
+        game.white_session = SessionKey { key: session, expires_at: i64::MAX };
+        game.set_time_mode(TimeControlMode::Correspondence { max_bank_days: 0, vacation_days: 0 });
+        game.white_vacation.days_left = 7;
+        assert!(game.player_side(session, 0).unwrap());
+        assert!(fails_with(game.abort(session), ChessError::NotAPlayer));
+        assert!(fails_with(game.take_vacation(session, 1, 0), ChessError::NotAPlayer));
+        assert!(fails_with(game.set_premoves(session, &[]), ChessError::NotAPlayer));
+        assert!(fails_with(game.request_takeback(session), ChessError::NotAPlayer));
+        game.takeback = 1; // Black asked
+        assert!(fails_with(game.accept_takeback(session), ChessError::NotAPlayer));
+        assert!(fails_with(game.decline_takeback(session), ChessError::NotAPlayer));
+        game.set_premoves(game.white_player, &[]).unwrap();
+    }
+
+    #[test]
+    fn vacation_stops_the_clock() {
+        let mut game = Game::new_casual();
+        game.set_time_mode(TimeControlMode::Correspondence { max_bank_days: 0, vacation_days: 0 });
+        game.white_time_left = 3 * DAY;
+        game.white_vacation.days_left = 7;
+        assert!(fails_with(game.take_vacation(Pubkey::new_unique(), 1, 0), ChessError::NotAPlayer));
+        assert!(fails_with(game.take_vacation(game.white_player, 8, 0), ChessError::VacationUnavailable));
+        assert!(game.take_vacation(game.white_player, 5, DAY).unwrap());
+        assert!(!game.is_timeout(8 * DAY));
+        assert!(game.is_timeout(9 * DAY));
+        // A second vacation during the same move keeps the first one's pause
+        assert!(game.take_vacation(game.white_player, 2, 7 * DAY).unwrap());
+        assert!(!game.is_timeout(10 * DAY));
+        assert!(game.is_timeout(11 * DAY));
+    }
+
+    #[test]
+    fn slow_first_move_aborts() {
+        let mut game = Game::new_casual();
+        game.rated = 1;
+        assert!(!game.first_move_expired(FIRST_MOVE_TIME));
+        assert!(game.first_move_expired(FIRST_MOVE_TIME + 1));
+        game.num_moves = 2;
+        assert!(!game.first_move_expired(FIRST_MOVE_TIME + 1));
+        game.num_moves = 1;
+        game.result_pending = 1;
+        assert!(!game.first_move_expired(FIRST_MOVE_TIME + 1));
+        game.result_pending = 0;
+        game.end_aborted();
+        assert!(!game.can_abort());
+        let rated = game.rated != 0;
+        assert!(!rated);
+    }
+
+    #[test]
+    fn aborted_wager_is_refunded() {
+        let mut game = Game::new_casual();
+        game.wager_stake = 100;
+        game.white_staked = 1;
+        game.black_staked = 1;
+        game.house_fee_bps = 500;
+        game.end_aborted();
+        assert!(game.settle(200).unwrap() == (100, 100, 0));
+    }
+
+    #[test]
+    fn takeback_restores_the_snapshot() {
+        let e4 = (5 << 12) + (1 << 9) + (4 << 6) + (3 << 3) + 4;
+        let e5 = (5 << 12) + (6 << 9) + (4 << 6) + (4 << 3) + 4;
+        let mut game = Game::new_casual();
+        game.apply_move(true, e4, 10, 0).unwrap();
+        let after_e4 = (GameState::from(&game.board).small_hash(), game.recent_states);
+        game.apply_move(false, e5, 20, 0).unwrap();
+        game.takeback = 1;
+        game.take_back().unwrap();
+        assert_eq!((GameState::from(&game.board).small_hash(), game.recent_states), after_e4);
+        let (num_moves, white_left, black_left, takeback) = (game.num_moves, game.white_time_left, game.black_time_left, game.takeback);
+        assert_eq!((num_moves, white_left, black_left, takeback), (1, 590, 600, 0));
+    }
+
+    #[test]
+    fn takebacks_in_a_row() {
+        let e4 = (5 << 12) + (1 << 9) + (4 << 6) + (3 << 3) + 4;
+        let e5 = (5 << 12) + (6 << 9) + (4 << 6) + (4 << 3) + 4;
+        let nf3 = (1 << 12) + (6 << 6) + (2 << 3) + 5;
+        let nc6 = (1 << 12) + (7 << 9) + (1 << 6) + (5 << 3) + 2;
+        let turns = [e4, e5, nf3, nc6];
+        let mut game = Game::new_casual();
+        game.apply_move(true, e4, 10, 0).unwrap();
+        game.offer_draw(true).unwrap();
+        for (index, turn) in turns.iter().enumerate().skip(1) {
+            game.apply_move(index % 2 == 0, *turn, 5, 0).unwrap();
+        }
+        game.takeback = 2;
+        game.take_back().unwrap();
+        let (num_moves, white_left, black_left) = (game.num_moves, game.white_time_left, game.black_time_left);
+        assert_eq!((num_moves, white_left, black_left), (2, 590, 595));
+        // The second takeback restores white's offer, which black had not answered yet
+        game.takeback = 1;
+        game.take_back().unwrap();
+        let (num_moves, white_left, black_left) = (game.num_moves, game.white_time_left, game.black_time_left);
+        assert_eq!((num_moves, white_left, black_left), (1, 590, 600));
+        let (white_open, next_offer) = (game.white_draw_open, game.white_next_draw_offer);
+        assert_eq!((white_open, next_offer), (1, 1 + DRAW_OFFER_INTERVAL));
+        game.takeback = 1;
+        game.take_back().unwrap();
+        let (num_moves, white_open, next_offer) = (game.num_moves, game.white_draw_open, game.white_next_draw_offer);
+        assert_eq!((num_moves, white_open, next_offer), (0, 0, 0));
+    }
+
+    #[test]
+    fn takebacks_stop_at_the_window() {
+        let knight_moves = [(1 << 12) + (6 << 6) + (2 << 3) + 5, (1 << 12) + (7 << 9) + (6 << 6) + (5 << 3) + 5,
+            (1 << 12) + (2 << 9) + (5 << 6) + 6, (1 << 12) + (5 << 9) + (5 << 6) + (7 << 3) + 6];
+        let turns: Vec<u16> = knight_moves.iter().cycle().take(TAKEBACK_WINDOW + 2).copied().collect();
+        let mut game = Game::new_casual();
+        for (index, turn) in turns.iter().enumerate() {
+            game.apply_move(index % 2 == 0, *turn, 1, 0).unwrap();
+        }
+        assert!(fails_with(game.snapshot(1), ChessError::TakebackTooFar));
+        while game.num_moves > 2 {
+            game.takeback = 2;
+            game.take_back().unwrap();
+        }
+        game.takeback = 2;
+        assert!(fails_with(game.take_back(), ChessError::TakebackTooFar));
+    }
+
+    #[test]
+    fn takeback_after_a_long_game() {
+        let turns = ruy_lopez();
+        let mut game = Game::new_casual();
+        for (index, turn) in turns.iter().enumerate() {
+            game.apply_move(index % 2 == 0, *turn, 1, 0).unwrap();
+        }
+        assert!(game.is_active());
+        game.takeback = 2;
+        game.take_back().unwrap();
+        let restored = (game.num_moves, GameState::from(&game.board), game.recent_states);
+        game.replay(&turns[..42]);
+        assert!(restored == (game.num_moves, GameState::from(&game.board), game.recent_states));
+    }
+
+    #[test]
+    fn premove_chains_stop_at_the_cap() {
+        let turns = ruy_lopez();
+        let mut game = Game::new_casual();
+        // Both sides premove the line, each entry following the side's previous one
+        for index in 0..MAX_PREMOVES {
+            game.black_premoves[index] = Premove { after: index as u16, condition: turns[2 * index], reply: turns[2 * index + 1] };
+            game.white_premoves[index] = Premove { after: index as u16, condition: turns[2 * index + 1], reply: turns[2 * index + 2] };
+        }
+        game.apply_move(true, turns[0], 1, 0).unwrap();
+        let played = game.play_premoves(Pubkey::default(), turns[0], 10);
+        assert_eq!(played, turns[..=MAX_PREMOVE_REPLIES]);
+        assert_eq!(usize::from(game.num_moves), 1 + MAX_PREMOVE_REPLIES);
+    }
+
+    #[test]
+    fn draw_offers_are_rate_limited() {
+        let mut game = Game::new_casual();
+        game.offer_draw(true).unwrap();
+        game.white_draw_open = 0; // Black moved
+        game.num_moves = DRAW_OFFER_INTERVAL - 1;
+        assert!(fails_with(game.offer_draw(true), ChessError::DrawOfferTooSoon));
+        game.num_moves = DRAW_OFFER_INTERVAL;
+        game.offer_draw(true).unwrap();
+        game.offer_draw(true).unwrap();
+        game.black_next_draw_offer = 100;
+        game.offer_draw(false).unwrap();
+        assert!(game.status() == GameCodes::DrawAgreement);
+    }
+
+    #[test]
+    fn premoves_follow_the_tree() {
+        let e4 = (5 << 12) + (1 << 9) + (4 << 6) + (3 << 3) + 4;
+        let d4 = (5 << 12) + (1 << 9) + (3 << 6) + (3 << 3) + 3;
+        let e5 = (5 << 12) + (6 << 9) + (4 << 6) + (4 << 3) + 4;
+        let d5 = (5 << 12) + (6 << 9) + (3 << 6) + (4 << 3) + 3;
+        let nf3 = (1 << 12) + (6 << 6) + (2 << 3) + 5;
+        let nc6 = (1 << 12) + (7 << 9) + (1 << 6) + (5 << 3) + 2;
+        let mut game = Game::new_casual();
+        let mut premoves = [Premove::default(); MAX_PREMOVES];
+        premoves[0] = Premove { after: 0, condition: d4, reply: d5 };
+        premoves[1] = Premove { after: 0, condition: e4, reply: e5 };
+        premoves[2] = Premove { after: 2, condition: nf3, reply: nc6 };
+        game.black_premoves = premoves;
+        game.replay(&[e4]);
+        assert_eq!(game.apply_premove(e4, 10), Some(e5));
+        let (num_moves, root, last_move) = (game.num_moves, game.black_premove_root, game.last_move);
+        assert_eq!((num_moves, root, last_move), (2, 2, 10));
+        // White has none, and black's tree waits for the next white move
+        assert_eq!(game.apply_premove(e5, 10), None);
+        game.replay(&[e4, e5, nf3]);
+        assert_eq!(game.apply_premove(nf3, 10), Some(nc6));
+        game.replay(&[e4, e5, nf3, nc6, d4]);
+        assert_eq!(game.apply_premove(d4, 10), None);
+        let (root, premoves) = (game.black_premove_root, game.black_premoves);
+        assert_eq!(root, 0);
+        assert!(premoves.iter().all(|premove| premove.reply == 0));
+    }
+
+    #[test]
+    fn periods_start_per_player() {
+        let mut game = Game::new_casual();
+        game.periods = TimePeriod::list(&[TimePeriod { after_moves: 2, time: 300, bonus: 10 }]).unwrap();
+        game.num_moves = 3; // White's second move
+        game.enter_period(true);
+        game.num_moves = 4;
+        game.enter_period(false);
+        let (white_left, black_left, white_bonus) = (game.white_time_left, game.black_time_left, game.white_bonus_time);
+        assert_eq!((white_left, black_left, white_bonus), (900, 900, 10));
+        assert_eq!((game.white_period, game.black_period), (1, 1));
+        game.num_moves = 5;
+        game.enter_period(true);
+        assert_eq!(game.white_period, 1);
+    }
+}
@@ -0,0 +1,200 @@
+use anchor_lang::prelude::*;
+use std::f64::consts::PI;
+
+// Glicko-2 (http://www.glicko.net/glicko/glicko2.pdf) with one game per rating period
+const INITIAL_RATING: f64 = 1500.0;
+const INITIAL_DEVIATION: f64 = 350.0;
+const INITIAL_VOLATILITY: f64 = 0.06;
+const SCALE: f64 = 173.7178;
+const TAU: f64 = 0.5; // Constrains the volatility change
+const EPSILON: f64 = 0.000001;
+const MAX_ITERATIONS: usize = 30; // Illinois converges to EPSILON in far fewer
+const MAX_BRACKET_STEPS: f64 = 20.0; // Steps of TAU searched below ln(volatility^2)
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TimeCategory {
+    Bullet,
+    Blitz,
+    Rapid,
+    Classical,
+}
+impl Default for TimeCategory {
+    fn default() -> Self { TimeCategory::Blitz }
+}
+impl TimeCategory {
+    pub const COUNT: usize = 4;
+    pub fn from_code(code: u8) -> Option<Self> {
+        // Inverse of `as u8`, for the code stored in the zero-copy Game
+        [TimeCategory::Bullet, TimeCategory::Blitz, TimeCategory::Rapid, TimeCategory::Classical].get(usize::from(code)).copied()
+    }
+    pub fn of(time: i64, bonus: u32) -> Self {
+        // Estimated duration of a 40 move game per player, in seconds
+        let duration = time + 40 * i64::from(bonus);
+        if duration < 180 {
+            TimeCategory::Bullet
+        } else if duration < 480 {
+            TimeCategory::Blitz
+        } else if duration < 1500 {
+            TimeCategory::Rapid
+        } else {
+            TimeCategory::Classical
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub struct Rating {
+    pub rating: f64,     // 64
+    pub deviation: f64,  // 64
+    pub volatility: f64, // 64
+    pub wins: u32,       // 32
+    pub losses: u32,     // 32
+    pub draws: u32,      // 32
+}
+impl Default for Rating {
+    fn default() -> Self {
+        Self {
+            rating: INITIAL_RATING,
+            deviation: INITIAL_DEVIATION,
+            volatility: INITIAL_VOLATILITY,
+            wins: 0,
+            losses: 0,
+            draws: 0,
+        }
+    }
+}
+impl Rating {
+    pub fn after_game(&self, opponent: &Rating, score: f64) -> Self {
+        // score is 1 for a win, 0.5 for a draw and 0 for a loss
+        let mut next = self.updated(&[(*opponent, score)]);
+        next.wins = self.wins + u32::from(score > 0.5);
+        next.losses = self.losses + u32::from(score < 0.5);
+        next.draws = self.draws + u32::from(score == 0.5);
+        next
+    }
+
+    pub fn updated(&self, results: &[(Rating, f64)]) -> Self {
+        // Steps 2-8 of the Glicko-2 paper for one rating period
+        let mu = (self.rating - INITIAL_RATING) / SCALE;
+        let phi = self.deviation / SCALE;
+        if results.is_empty() {
+            let deviation = (phi.powi(2) + self.volatility.powi(2)).sqrt() * SCALE;
+            return Self { deviation: deviation.min(INITIAL_DEVIATION), ..*self };
+        }
+        let mut v_inverse = 0.0;
+        let mut improvement = 0.0;
+        for (opponent, score) in results {
+            let mu_j = (opponent.rating - INITIAL_RATING) / SCALE;
+            let g = g(opponent.deviation / SCALE);
+            let expected = 1.0 / (1.0 + (-g * (mu - mu_j)).exp());
+            v_inverse += g.powi(2) * expected * (1.0 - expected);
+            improvement += g * (score - expected);
+        }
+        let v = 1.0 / v_inverse;
+        let delta = v * improvement;
+
+        let volatility = new_volatility(self.volatility, phi, v, delta);
+        let phi_star = (phi.powi(2) + volatility.powi(2)).sqrt();
+        let new_phi = 1.0 / (1.0 / phi_star.powi(2) + 1.0 / v).sqrt();
+        let new_mu = mu + new_phi.powi(2) * improvement;
+        Self {
+            rating: new_mu * SCALE + INITIAL_RATING,
+            deviation: (new_phi * SCALE).min(INITIAL_DEVIATION),
+            volatility,
+            ..*self
+        }
+    }
+}
+
+fn g(phi: f64) -> f64 {
+    1.0 / (1.0 + 3.0 * phi.powi(2) / PI.powi(2)).sqrt()
+}
+
+fn new_volatility(volatility: f64, phi: f64, v: f64, delta: f64) -> f64 {
+    // Step 5: solve for the new volatility with the Illinois algorithm
+    let a = volatility.powi(2).ln();
+    let f = |x: f64| {
+        let ex = x.exp();
+        ex * (delta.powi(2) - phi.powi(2) - v - ex) / (2.0 * (phi.powi(2) + v + ex).powi(2)) - (x - a) / TAU.powi(2)
+    };
+    let mut lower = a;
+    let mut upper = if delta.powi(2) > phi.powi(2) + v {
+        (delta.powi(2) - phi.powi(2) - v).ln()
+    } else {
+        let mut k = 1.0;
+        while f(a - k * TAU) < 0.0 {
+            // f(a - k * TAU) >= k / TAU - 1/2 here, so this only guards against a larger TAU
+            if k >= MAX_BRACKET_STEPS {
+                return volatility;
+            }
+            k += 1.0;
+        }
+        a - k * TAU
+    };
+    let mut f_lower = f(lower);
+    let mut f_upper = f(upper);
+    for _ in 0..MAX_ITERATIONS {
+        if (upper - lower).abs() <= EPSILON {
+            break;
+        }
+        let next = lower + (lower - upper) * f_lower / (f_upper - f_lower);
+        let f_next = f(next);
+        if f_next * f_upper <= 0.0 {
+            lower = upper;
+            f_lower = f_upper;
+        } else {
+            f_lower /= 2.0;
+        }
+        upper = next;
+        f_upper = f_next;
+    }
+    (lower / 2.0).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(rating: f64, deviation: f64) -> Rating {
+        Rating { rating, deviation, ..Rating::default() }
+    }
+
+    #[test]
+    fn glickman_example() {
+        // Section 3 of the Glicko-2 paper
+        let player = rating(1500.0, 200.0);
+        let results = [(rating(1400.0, 30.0), 1.0), (rating(1550.0, 100.0), 0.0), (rating(1700.0, 300.0), 0.0)];
+        let next = player.updated(&results);
+        assert!((next.rating - 1464.06).abs() < 0.01, "{}", next.rating);
+        assert!((next.deviation - 151.52).abs() < 0.01, "{}", next.deviation);
+        assert!((next.volatility - 0.05999).abs() < 0.00001, "{}", next.volatility);
+    }
+
+    #[test]
+    fn inactive_period_widens_deviation() {
+        let next = rating(1500.0, 50.0).updated(&[]);
+        assert!(next.deviation > 50.0);
+        assert_eq!(next.rating, 1500.0);
+        assert_eq!(rating(1500.0, INITIAL_DEVIATION).updated(&[]).deviation, INITIAL_DEVIATION);
+    }
+
+    #[test]
+    fn volatility_bracket_is_bounded() {
+        for (phi, v, delta) in [(1e-9, 1e-12, 0.0), (2.0, 1e6, 1e3), (1e-3, 1e-3, 1e3)] {
+            let volatility = new_volatility(INITIAL_VOLATILITY, phi, v, delta);
+            assert!(volatility.is_finite() && volatility > 0.0, "{}", volatility);
+        }
+        let lopsided = rating(3000.0, 30.0).after_game(&rating(500.0, 350.0), 0.0);
+        assert!(lopsided.rating.is_finite() && lopsided.volatility.is_finite());
+        assert_eq!(lopsided.losses, 1);
+    }
+
+    #[test]
+    fn categories() {
+        assert!(TimeCategory::of(60, 0) == TimeCategory::Bullet);
+        assert!(TimeCategory::of(180, 2) == TimeCategory::Blitz);
+        assert!(TimeCategory::of(600, 5) == TimeCategory::Rapid);
+        assert!(TimeCategory::of(5400, 30) == TimeCategory::Classical);
+        assert_eq!(TimeCategory::Classical as usize + 1, TimeCategory::COUNT);
+    }
+}
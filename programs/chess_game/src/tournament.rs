@@ -0,0 +1,697 @@
+use anchor_lang::prelude::*;
+use crate::code_generator::GameCodes;
+use crate::ChessError;
+
+// Array lengths are repeated as literals in the account types for the IDL
+pub const MAX_PLAYERS: usize = 32;
+pub const MAX_ROUNDS: usize = 16;
+pub const MAX_BOARDS: usize = MAX_PLAYERS / 2;
+const PAIRING_BUDGET: u32 = 2000; // Candidates checked per Swiss round, across all attempts
+const NO_PLAYER: u8 = 255;
+const BPS: u32 = 10000;
+
+// RoundRecord::color
+const NO_COLOR: u8 = 0;
+const WHITE: u8 = 1;
+const BLACK: u8 = 2;
+// RoundRecord::result
+const PENDING: u8 = 0;
+const LOSS: u8 = 1;
+const DRAW: u8 = 2;
+const WIN: u8 = 3;
+const BYE: u8 = 4;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TournamentStatus {
+    Registration,
+    Running,
+    Finished,
+}
+impl Default for TournamentStatus {
+    fn default() -> Self { TournamentStatus::Registration }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TournamentFormat {
+    Swiss,
+    RoundRobin, // Berger tables
+    DoubleRoundRobin, // Colors reversed in the second cycle
+    Knockout, // Single elimination matches
+}
+impl Default for TournamentFormat {
+    fn default() -> Self { TournamentFormat::Swiss }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct TournamentParams {
+    pub format: TournamentFormat,
+    pub max_players: u8,
+    pub num_rounds: u8, // Swiss only, the other formats need a fixed number
+    pub time: i64, // sec each
+    pub bonus: u32, // sec per move
+    pub rated: bool, // Tiebreak games are never rated
+    // Knockout matches: match_games at the main time control, then if tied rapid_games rapid
+    // games, then one Armageddon game where a draw counts as a win for black. Without an
+    // Armageddon time the higher seed goes through when the rapid games are tied too.
+    pub match_games: u8,
+    pub rapid_games: u8,
+    pub rapid_time: i64,
+    pub rapid_bonus: u32,
+    pub armageddon_white_time: i64,
+    pub armageddon_black_time: i64,
+    pub prize_shares_bps: [u16; 4], // Of the prize pool, by final place
+}
+
+enum MatchStage {
+    Main,
+    Rapid,
+    Armageddon,
+}
+
+impl TournamentParams {
+    fn stage(&self, game_number: u8) -> MatchStage {
+        if game_number < self.match_games {
+            MatchStage::Main
+        } else if game_number - self.match_games < self.rapid_games {
+            MatchStage::Rapid
+        } else {
+            MatchStage::Armageddon
+        }
+    }
+
+    fn first_is_white(&self, game_number: u8) -> bool {
+        // Colors alternate within each stage; the lower seed gets white in Armageddon
+        match self.stage(game_number) {
+            MatchStage::Main => game_number & 1 == 0,
+            MatchStage::Rapid => (game_number - self.match_games) & 1 == 0,
+            MatchStage::Armageddon => false,
+        }
+    }
+
+    fn match_result(&self, pairing: &mut Pairing, status: GameCodes) -> Option<bool> {
+        // Counts the latest game of a knockout match. Returns whether the higher seed won the
+        // match, or None while it goes on.
+        let game_number = pairing.games - 1;
+        let first_white = self.first_is_white(game_number);
+        let white_points = if status.is_white_winner() {
+            2
+        } else if status.is_black_winner() {
+            0
+        } else {
+            match self.stage(game_number) {
+                MatchStage::Armageddon => 0,
+                _ => 1,
+            }
+        };
+        let first_points = if first_white { white_points } else { 2 - white_points };
+        pairing.white_points += first_points;
+        pairing.black_points += 2 - first_points;
+
+        let games = pairing.games;
+        let main_end = self.match_games;
+        let rapid_end = main_end + self.rapid_games;
+        let has_armageddon = self.armageddon_white_time > 0 || self.armageddon_black_time > 0;
+        if (games == main_end || games == rapid_end) && pairing.white_points != pairing.black_points {
+            Some(pairing.white_points > pairing.black_points)
+        } else if games > rapid_end {
+            Some(first_points == 2)
+        } else if games == rapid_end && !has_armageddon {
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct RoundRecord {
+    opponent: u8, // Index in Tournament::players, only set with a color
+    color: u8,
+    result: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct TournamentPlayer {
+    key: Pubkey,                   // 32
+    score: u16, // half points     // 16
+    buchholz: u16, // half points  // 16
+    sonneborn_berger: u16, // quarter points // 16
+    rounds: [RoundRecord; 16],     // 16*3 bytes
+}
+impl TournamentPlayer {
+    fn has_played(&self, opponent: usize, round: usize) -> bool {
+        self.rounds[..round].iter().any(|record| record.color != NO_COLOR && usize::from(record.opponent) == opponent)
+    }
+    fn had_bye(&self, round: usize) -> bool {
+        self.rounds[..round].iter().any(|record| record.result == BYE)
+    }
+    fn color_preference(&self, round: usize) -> (u8, u8) {
+        // Returns the wanted color and how strongly: 3 absolute, 2 strong, 1 mild, 0 none
+        let mut difference = 0i32;
+        let (mut last, mut before_last) = (NO_COLOR, NO_COLOR);
+        for color in self.rounds[..round].iter().map(|record| record.color).filter(|color| *color != NO_COLOR) {
+            difference += if color == WHITE { 1 } else { -1 };
+            before_last = last;
+            last = color;
+        }
+        let repeated = last != NO_COLOR && last == before_last;
+        let other = |color: u8| if color == WHITE { BLACK } else { WHITE };
+        if repeated {
+            (other(last), 3)
+        } else if difference.abs() >= 2 {
+            (if difference > 0 { BLACK } else { WHITE }, 3)
+        } else if difference != 0 {
+            (if difference > 0 { BLACK } else { WHITE }, 2)
+        } else if last != NO_COLOR {
+            (other(last), 1)
+        } else {
+            (NO_COLOR, 0)
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct Pairing {
+    white: u8, // Higher seed in knockout matches // 8
+    black: u8, // NO_PLAYER for a knockout bye // 8
+    game: Pubkey, // default until created // 32
+    recorded: bool,                // 1
+    games: u8,                     // 8
+    white_points: u8, // knockout match score in half points // 8
+    black_points: u8,              // 8
+    winner: u8,                    // 8
+    finished: bool,                // 1
+}
+
+// Time control and players of the next game of a pairing
+pub struct TournamentGame {
+    pub white_player: Pubkey,
+    pub black_player: Pubkey,
+    pub white_time: i64,
+    pub black_time: i64,
+    pub bonus: u32,
+    pub rated: bool,
+}
+
+// Games are at [GAME_SEED, tournament, round, board, game number], all counted from 0
+// except rounds, which count from 1.
+#[account]
+#[derive(Default)]
+pub struct Tournament {
+    pub(crate) organizer: Pubkey,  // 32
+    params: TournamentParams,      // 54 bytes
+    num_players: u8,               // 8
+    num_rounds: u8,                // 8
+    current_round: u8,             // 8
+    status: TournamentStatus,      // 1
+    players: [TournamentPlayer; 32], // 32*86 bytes
+    pairings: [Pairing; 16], // current round // 16*40 bytes
+    num_pairings: u8,              // 8
+    standings: [u8; 32], // player indices, best first // 32*8
+    pub(crate) prize_mint: Pubkey, // 32
+    pub(crate) prize_bump: u8,     // 8
+    prizes_paid: bool,             // 1
+}
+impl Tournament {
+    pub fn setup(&mut self, organizer: Pubkey, params: TournamentParams) -> Result<()> {
+        let max_players = usize::from(params.max_players);
+        let max_players_for_format = match params.format {
+            TournamentFormat::Swiss | TournamentFormat::Knockout => MAX_PLAYERS,
+            // Every round needs a slot in TournamentPlayer::rounds
+            TournamentFormat::RoundRobin => MAX_ROUNDS,
+            TournamentFormat::DoubleRoundRobin => MAX_ROUNDS / 2,
+        };
+        let valid = (2..=max_players_for_format).contains(&max_players) &&
+            (params.format != TournamentFormat::Swiss || (1..=MAX_ROUNDS).contains(&usize::from(params.num_rounds))) &&
+            (params.format != TournamentFormat::Knockout || params.match_games > 0) &&
+            params.prize_shares_bps.iter().map(|share| u32::from(*share)).sum::<u32>() <= BPS;
+        if !valid {
+            return err!(ChessError::InvalidTournament);
+        }
+        self.organizer = organizer;
+        self.params = params;
+        Ok(())
+    }
+
+    pub fn current_round(&self) -> u8 {
+        self.current_round
+    }
+
+    pub fn game_number(&self, board: u8) -> u8 {
+        self.pairings.get(usize::from(board)).map(|pairing| pairing.games).unwrap_or(0)
+    }
+
+    pub fn board_player(&self, board: u8, second: bool) -> &Pubkey {
+        // Key of the board's first (white, or higher seed) or second player, for the game
+        // index seeds. next_game rejects the invalid boards that fall back to the organizer
+        let player = self.pairings.get(usize::from(board))
+            .map(|pairing| if second { pairing.black } else { pairing.white });
+        match player.and_then(|player| self.players.get(usize::from(player))) {
+            Some(player) => &player.key,
+            None => &self.organizer,
+        }
+    }
+
+    pub fn register(&mut self, player: Pubkey) -> Result<()> {
+        if self.status != TournamentStatus::Registration {
+            return err!(ChessError::RegistrationClosed);
+        }
+        if self.num_players >= self.params.max_players {
+            return err!(ChessError::TournamentFull);
+        }
+        if self.players[..self.num_players.into()].iter().any(|entry| entry.key == player) {
+            return err!(ChessError::AlreadyRegistered);
+        }
+        self.players[usize::from(self.num_players)] = TournamentPlayer { key: player, ..Default::default() };
+        self.num_players += 1;
+        Ok(())
+    }
+
+    pub fn start_round(&mut self) -> Result<()> {
+        match self.status {
+            TournamentStatus::Finished => return err!(ChessError::TournamentOver),
+            TournamentStatus::Registration if self.num_players < 2 => return err!(ChessError::NotEnoughPlayers),
+            TournamentStatus::Running if !self.round_finished() => return err!(ChessError::RoundNotFinished),
+            _ => {}
+        }
+        let num_players = usize::from(self.num_players);
+        if self.status == TournamentStatus::Registration {
+            // Registration order is the seeding
+            let cycle = (num_players + num_players % 2 - 1) as u8;
+            self.num_rounds = match self.params.format {
+                TournamentFormat::Swiss => self.params.num_rounds,
+                TournamentFormat::RoundRobin => cycle,
+                TournamentFormat::DoubleRoundRobin => 2 * cycle,
+                TournamentFormat::Knockout => num_players.next_power_of_two().trailing_zeros() as u8,
+            };
+        }
+        if self.current_round >= self.num_rounds {
+            return err!(ChessError::TournamentOver);
+        }
+        let round = usize::from(self.current_round);
+        let (pairs, byes) = match self.params.format {
+            TournamentFormat::Swiss => {
+                let (pairs, bye) = pair_swiss(&self.players[..num_players], round)?;
+                (pairs, bye.into_iter().collect())
+            }
+            TournamentFormat::RoundRobin | TournamentFormat::DoubleRoundRobin => pair_round_robin(num_players, round),
+            TournamentFormat::Knockout => self.pair_knockout(round),
+        };
+        for bye in byes {
+            self.players[bye].rounds[round] = RoundRecord { opponent: 0, color: NO_COLOR, result: BYE };
+            self.players[bye].score += points(BYE);
+        }
+        self.pairings = Default::default();
+        for (board, (white, black)) in pairs.iter().enumerate() {
+            self.pairings[board] = Pairing { white: *white as u8, black: *black as u8, ..Default::default() };
+            if *black == usize::from(NO_PLAYER) {
+                // Knockout bye, the higher seed advances without playing
+                self.pairings[board].finished = true;
+                self.pairings[board].winner = *white as u8;
+                self.players[*white].rounds[round] = RoundRecord { opponent: 0, color: NO_COLOR, result: BYE };
+                self.players[*white].score += points(BYE);
+                continue;
+            }
+            self.players[*white].rounds[round] = RoundRecord { opponent: *black as u8, color: WHITE, result: PENDING };
+            self.players[*black].rounds[round] = RoundRecord { opponent: *white as u8, color: BLACK, result: PENDING };
+        }
+        self.num_pairings = pairs.len() as u8;
+        self.current_round += 1;
+        self.status = TournamentStatus::Running;
+        Ok(())
+    }
+
+    pub fn next_game(&mut self, board: u8, game: Pubkey) -> Result<TournamentGame> {
+        // Links the board to its next game account and returns how to set that game up
+        let params = self.params;
+        let pairing = self.pairing_mut(board)?;
+        if pairing.finished || (pairing.game != Pubkey::default() && !pairing.recorded) {
+            return err!(ChessError::WrongGame);
+        }
+        let game_number = pairing.games;
+        pairing.game = game;
+        pairing.recorded = false;
+        pairing.games += 1;
+        let (first, second) = (usize::from(pairing.white), usize::from(pairing.black));
+        let (first, second) = (self.players[first].key, self.players[second].key);
+        if params.format != TournamentFormat::Knockout {
+            return Ok(TournamentGame {
+                white_player: first,
+                black_player: second,
+                white_time: params.time,
+                black_time: params.time,
+                bonus: params.bonus,
+                rated: params.rated,
+            });
+        }
+        let first_white = params.first_is_white(game_number);
+        let (white_player, black_player) = if first_white { (first, second) } else { (second, first) };
+        let (white_time, black_time, bonus, rated) = match params.stage(game_number) {
+            MatchStage::Main => (params.time, params.time, params.bonus, params.rated),
+            MatchStage::Rapid => (params.rapid_time, params.rapid_time, params.rapid_bonus, false),
+            MatchStage::Armageddon => (params.armageddon_white_time, params.armageddon_black_time, 0, false),
+        };
+        Ok(TournamentGame { white_player, black_player, white_time, black_time, bonus, rated })
+    }
+
+    pub fn record_result(&mut self, board: u8, game: Pubkey, status: GameCodes) -> Result<()> {
+        let params = self.params;
+        let pairing = self.pairing_mut(board)?;
+        if pairing.game != game || game == Pubkey::default() {
+            return err!(ChessError::WrongGame);
+        }
+        if pairing.recorded {
+            return err!(ChessError::ResultAlreadyRecorded);
+        }
+        if status == GameCodes::Active {
+            return err!(ChessError::GameNotOver);
+        }
+        pairing.recorded = true;
+        let (white, black) = (usize::from(pairing.white), usize::from(pairing.black));
+        let (white_result, black_result) = if params.format == TournamentFormat::Knockout {
+            match params.match_result(pairing, status) {
+                Some(true) => (WIN, LOSS),
+                Some(false) => (LOSS, WIN),
+                None => return Ok(()), // More games to play
+            }
+        } else if status.is_white_winner() {
+            (WIN, LOSS)
+        } else if status.is_black_winner() {
+            (LOSS, WIN)
+        } else {
+            (DRAW, DRAW)
+        };
+        pairing.finished = true;
+        pairing.winner = if white_result == WIN { pairing.white } else { pairing.black };
+        let round = usize::from(self.current_round) - 1;
+        for (player, result) in [(white, white_result), (black, black_result)] {
+            self.players[player].rounds[round].result = result;
+            self.players[player].score += points(result);
+        }
+        if self.round_finished() {
+            self.update_standings();
+            if self.current_round >= self.num_rounds {
+                self.status = TournamentStatus::Finished;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn fund_prizes(&mut self, mint: Pubkey, prize_bump: u8) -> Result<()> {
+        if self.prizes_paid {
+            return err!(ChessError::PrizesAlreadyPaid);
+        }
+        if self.prize_mint != Pubkey::default() && self.prize_mint != mint {
+            return err!(ChessError::WrongPrizeMint);
+        }
+        self.prize_mint = mint;
+        self.prize_bump = prize_bump;
+        Ok(())
+    }
+
+    pub fn prize_payouts(&mut self, pool: u64) -> Result<Vec<(Pubkey, u64)>> {
+        // Shares of the pool by final place; what is left goes back to the organizer
+        if self.status != TournamentStatus::Finished {
+            return err!(ChessError::TournamentNotFinished);
+        }
+        if self.prizes_paid {
+            return err!(ChessError::PrizesAlreadyPaid);
+        }
+        self.prizes_paid = true;
+        Ok(self.params.prize_shares_bps.iter().zip(self.standings.iter())
+            .take(self.num_players.into())
+            .map(|(share, index)| {
+                let amount = u128::from(pool) * u128::from(*share) / u128::from(BPS);
+                (self.players[usize::from(*index)].key, amount as u64)
+            })
+            .collect())
+    }
+
+    fn pairing_mut(&mut self, board: u8) -> Result<&mut Pairing> {
+        if self.status != TournamentStatus::Running || board >= self.num_pairings {
+            return err!(ChessError::InvalidBoard);
+        }
+        Ok(&mut self.pairings[usize::from(board)])
+    }
+
+    fn round_finished(&self) -> bool {
+        self.pairings[..self.num_pairings.into()].iter().all(|pairing| pairing.finished)
+    }
+
+    fn pair_knockout(&self, round: usize) -> (Vec<(usize, usize)>, Vec<usize>) {
+        // The first round follows the seeded bracket, with byes for the top seeds when the
+        // field is not a power of two. Later rounds pair the winners of neighbouring matches.
+        let num_players = usize::from(self.num_players);
+        let entrants: Vec<usize> = if round == 0 {
+            bracket_order(num_players.next_power_of_two()).into_iter()
+                .map(|seed| if seed < num_players { seed } else { usize::from(NO_PLAYER) })
+                .collect()
+        } else {
+            self.pairings[..self.num_pairings.into()].iter().map(|pairing| usize::from(pairing.winner)).collect()
+        };
+        let pairs = entrants.chunks(2)
+            .map(|pair| (pair[0].min(pair[1]), pair[0].max(pair[1])))
+            .collect();
+        (pairs, Vec::new())
+    }
+
+    fn update_standings(&mut self) {
+        // Buchholz: sum of the opponents' scores. Sonneborn-Berger: the scores of beaten
+        // opponents plus half the scores of drawn ones. Byes add to neither.
+        let num_players = usize::from(self.num_players);
+        let scores: Vec<u16> = self.players[..num_players].iter().map(|entry| entry.score).collect();
+        for entry in self.players[..num_players].iter_mut() {
+            let mut buchholz = 0;
+            let mut sonneborn_berger = 0;
+            for record in entry.rounds.iter().filter(|record| record.color != NO_COLOR) {
+                let opponent_score = scores[usize::from(record.opponent)];
+                buchholz += opponent_score;
+                sonneborn_berger += match record.result {
+                    WIN => 2 * opponent_score,
+                    DRAW => opponent_score,
+                    _ => 0,
+                };
+            }
+            entry.buchholz = buchholz;
+            entry.sonneborn_berger = sonneborn_berger;
+        }
+        // Knockout players are ranked by matches won, so by how far they got
+        let format = self.params.format;
+        let mut order: Vec<usize> = (0..num_players).collect();
+        order.sort_by_key(|index| {
+            let entry = &self.players[*index];
+            let tiebreaks = match format {
+                TournamentFormat::Swiss => (entry.buchholz, entry.sonneborn_berger),
+                TournamentFormat::RoundRobin | TournamentFormat::DoubleRoundRobin => (entry.sonneborn_berger, entry.buchholz),
+                TournamentFormat::Knockout => (0, 0),
+            };
+            (std::cmp::Reverse((entry.score, tiebreaks)), *index)
+        });
+        for (rank, index) in order.into_iter().enumerate() {
+            self.standings[rank] = index as u8;
+        }
+    }
+}
+
+fn points(result: u8) -> u16 {
+    match result {
+        WIN | BYE => 2,
+        DRAW => 1,
+        _ => 0,
+    }
+}
+
+fn bracket_order(size: usize) -> Vec<usize> {
+    // Seeds (from 0) in bracket order, so that the top two seeds can only meet in the final:
+    // 0 7 3 4 1 6 2 5 for 8 players
+    let mut order = vec![0];
+    while order.len() < size {
+        let next_size = order.len() * 2;
+        order = order.into_iter().flat_map(|seed| [seed, next_size - 1 - seed]).collect();
+    }
+    order
+}
+
+fn pair_round_robin(num_players: usize, round: usize) -> (Vec<(usize, usize)>, Vec<usize>) {
+    // Berger tables, with players numbered from 1 in registration order. An odd field gets a
+    // dummy last player whose opponent has the bye. In round r, players i and j < n meet when
+    // i + j = r + 1 (mod n - 1), and a player with no such partner meets n.
+    let n = num_players + num_players % 2;
+    let cycle = n - 1;
+    let cycle_round = round % cycle + 1;
+    let second_cycle = round >= cycle;
+    let mut pairs = Vec::new();
+    let mut byes = Vec::new();
+    for i in 1..n {
+        let mut j = (cycle_round + 1 + cycle - i % cycle) % cycle;
+        if j == 0 {
+            j = cycle;
+        }
+        let (white, black) = if j == i {
+            // Player n has white in even rounds
+            if cycle_round % 2 == 0 { (n, i) } else { (i, n) }
+        } else if i < j {
+            if (j - i) % 2 == 1 { (i, j) } else { (j, i) }
+        } else {
+            continue;
+        };
+        let (white, black) = if second_cycle { (black, white) } else { (white, black) };
+        if white > num_players {
+            byes.push(black - 1);
+        } else if black > num_players {
+            byes.push(white - 1);
+        } else {
+            pairs.push((white - 1, black - 1));
+        }
+    }
+    (pairs, byes)
+}
+
+type SwissRound = (Vec<(usize, usize)>, Option<usize>); // Pairs and the player with the bye
+
+fn pair_swiss(players: &[TournamentPlayer], round: usize) -> Result<SwissRound> {
+    // Simplified Dutch system: players are ranked by score, then registration order. In each
+    // score group the top half meets the bottom half in order, and the search moves on to the
+    // next candidate when that would repeat a pairing or give someone a third game in a row with
+    // the same color. Unpaired players float down to the next group. When no such pairing exists
+    // the color rule, then the repeat rule, are dropped. The bye goes to the lowest ranked
+    // player without one. All attempts share PAIRING_BUDGET, once it is spent the players are
+    // paired in order without restrictions.
+    let mut order: Vec<usize> = (0..players.len()).collect();
+    order.sort_by_key(|index| (std::cmp::Reverse(players[*index].score), *index));
+    let bye_candidates: Vec<Option<usize>> = if order.len() % 2 == 1 {
+        let mut candidates: Vec<Option<usize>> = order.iter().rev().filter(|index| !players[**index].had_bye(round)).map(|index| Some(*index)).collect();
+        candidates.push(order.last().copied()); // Everyone had a bye
+        candidates
+    } else {
+        vec![None]
+    };
+    let preferences: Vec<(u8, u8)> = players.iter().map(|player| player.color_preference(round)).collect();
+    let mut budget = PAIRING_BUDGET;
+    for strictness in (0..3).rev() {
+        for bye in &bye_candidates {
+            let mut remaining: Vec<usize> = order.iter().copied().filter(|index| Some(*index) != *bye).collect();
+            let mut pairs = Vec::new();
+            if pair_remaining(players, &preferences, round, strictness, &mut remaining, &mut pairs, &mut budget) {
+                return Ok((pairs, *bye));
+            }
+        }
+    }
+    err!(ChessError::PairingFailed)
+}
+
+fn pair_remaining(players: &[TournamentPlayer], preferences: &[(u8, u8)], round: usize, strictness: u8,
+    remaining: &mut Vec<usize>, pairs: &mut Vec<(usize, usize)>, budget: &mut u32) -> bool {
+    // strictness 2: no repeats and color rules, 1: no repeats, 0: anything, which takes the
+    // first candidate every time and needs no budget
+    if remaining.is_empty() {
+        return true;
+    }
+    let top = remaining[0];
+    let same_score = |start: usize| remaining[start..].iter().take_while(|index| players[**index].score == players[remaining[start]].score).count();
+    let mut bracket = same_score(0);
+    if bracket == 1 {
+        bracket += same_score(1); // Downfloater joins the next score group
+    }
+    let half = bracket / 2;
+    let candidates = (half..bracket).chain((1..half).rev()).chain(bracket..remaining.len());
+    for candidate in candidates {
+        let other = remaining[candidate];
+        if strictness > 0 {
+            if *budget == 0 {
+                return false;
+            }
+            *budget -= 1;
+        }
+        if strictness >= 1 && players[top].has_played(other, round) {
+            continue;
+        }
+        let (top_color, top_strength) = preferences[top];
+        let (other_color, other_strength) = preferences[other];
+        if strictness >= 2 && top_strength == 3 && other_strength == 3 && top_color == other_color {
+            continue;
+        }
+        // The stronger preference wins, the higher ranked player on a tie
+        let top_white = match (top_color, other_color) {
+            (NO_COLOR, NO_COLOR) => pairs.len() & 1 == 0, // Alternate colors down the boards
+            (NO_COLOR, _) => other_color == BLACK,
+            (_, NO_COLOR) => top_color == WHITE,
+            _ if top_color != other_color => top_color == WHITE,
+            _ if other_strength > top_strength => other_color == BLACK,
+            _ => top_color == WHITE,
+        };
+        pairs.push(if top_white { (top, other) } else { (other, top) });
+        remaining.remove(candidate);
+        remaining.remove(0);
+        if pair_remaining(players, preferences, round, strictness, remaining, pairs, budget) {
+            return true;
+        }
+        remaining.insert(0, top);
+        remaining.insert(candidate, other);
+        pairs.pop();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(players: &mut [TournamentPlayer], round: usize, white: usize, black: usize) {
+        players[white].rounds[round] = RoundRecord { opponent: black as u8, color: WHITE, result: DRAW };
+        players[black].rounds[round] = RoundRecord { opponent: white as u8, color: BLACK, result: DRAW };
+    }
+
+    #[test]
+    fn color_preferences() {
+        let mut players = [TournamentPlayer::default(); 2];
+        assert_eq!(players[0].color_preference(0), (NO_COLOR, 0));
+        play(&mut players, 0, 0, 1);
+        assert_eq!(players[0].color_preference(1), (BLACK, 2));
+        play(&mut players, 1, 1, 0);
+        assert_eq!(players[0].color_preference(2), (WHITE, 1));
+        play(&mut players, 2, 1, 0);
+        assert_eq!(players[0].color_preference(3), (WHITE, 3));
+        assert_eq!(players[1].color_preference(3), (BLACK, 3));
+    }
+
+    #[test]
+    fn swiss_avoids_repeats() {
+        let mut players = [TournamentPlayer::default(); 4];
+        play(&mut players, 0, 0, 1);
+        play(&mut players, 0, 2, 3);
+        let (pairs, bye) = pair_swiss(&players, 1).unwrap();
+        assert_eq!(bye, None);
+        assert_eq!(pairs.len(), 2);
+        for (white, black) in pairs {
+            assert!(!players[white].has_played(black, 1));
+        }
+    }
+
+    #[test]
+    fn swiss_falls_back_to_repeats() {
+        // After a full round robin of 4 every pairing repeats
+        let mut players = [TournamentPlayer::default(); 4];
+        for (round, boards) in [[(0, 1), (2, 3)], [(3, 0), (1, 2)], [(0, 2), (3, 1)]].iter().enumerate() {
+            for (white, black) in boards {
+                play(&mut players, round, *white, *black);
+            }
+        }
+        let (pairs, bye) = pair_swiss(&players, 3).unwrap();
+        assert_eq!(bye, None);
+        let mut paired: Vec<usize> = pairs.iter().flat_map(|(white, black)| [*white, *black]).collect();
+        paired.sort_unstable();
+        assert_eq!(paired, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn spent_budget_pairs_in_order() {
+        let players = [TournamentPlayer::default(); 4];
+        let preferences = [(NO_COLOR, 0); 4];
+        let (mut remaining, mut pairs, mut budget) = (vec![0, 1, 2, 3], Vec::new(), 0);
+        assert!(!pair_remaining(&players, &preferences, 0, 1, &mut remaining, &mut pairs, &mut budget));
+        assert_eq!(remaining, vec![0, 1, 2, 3]);
+        assert!(pair_remaining(&players, &preferences, 0, 0, &mut remaining, &mut pairs, &mut budget));
+        assert_eq!(pairs, vec![(0, 2), (3, 1)]);
+    }
+}
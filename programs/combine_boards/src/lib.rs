@@ -196,10 +196,10 @@ impl RequestRandom<'_> {
             token_program: ctx.accounts.token_program.to_account_info(),
         };
 
-        let vrf_key = ctx.accounts.vrf.key.clone();
-        let authority_key = ctx.accounts.authority.key.clone();
+        let vrf_key = *ctx.accounts.vrf.key;
+        let authority_key = *ctx.accounts.authority.key;
         let state_seeds: &[&[&[u8]]] = &[&[
-            &SEED,
+            SEED,
             vrf_key.as_ref(),
             authority_key.as_ref(),
             &[params.client_state_bump],
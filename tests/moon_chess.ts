@@ -2,29 +2,413 @@ import * as anchor from "@project-serum/anchor";
 import { Program } from "@project-serum/anchor";
 import { ChessGame } from "../target/types/chess_game";
 import { expect } from 'chai';
+import { Token, TOKEN_PROGRAM_ID } from "@solana/spl-token";
 
-async function play(program, game, authority, piece, from_rank, from_col, to_rank, to_col) {
-  await program.rpc.play((piece << 12)+(from_rank << 9)+(from_col << 6)+(to_rank << 3)+(to_col), {
+// GameCodes in order, the Game account stores the status as its index
+const GAME_CODES = ['active', 'invalid', 'whiteWinCheckmate', 'whiteWinResignation', 'whiteWinTime',
+  'blackWinCheckmate', 'blackWinResignation', 'blackWinTime', 'drawStalemate', 'drawInsufficientMaterial',
+  'drawFiftyMoves', 'drawAgreement', 'drawMaxMoves', 'drawRepetition', 'aborted'];
+
+function status_of(gameState) {
+  return GAME_CODES[gameState.status];
+}
+
+async function play(program, game, player, piece, from_rank, from_col, to_rank, to_col, payer=null, offerDraw=false) {
+  // The wallet pays to grow the move list unless another payer is given
+  await program.rpc.play((piece << 12)+(from_rank << 9)+(from_col << 6)+(to_rank << 3)+(to_col), offerDraw, {
     accounts: {
-      authority: authority.publicKey,
+      player: player.publicKey,
+      game,
+      payer: payer ? payer.publicKey : program.provider.wallet.publicKey,
+      systemProgram: anchor.web3.SystemProgram.programId
+    },
+    signers: payer ? [player, payer] : [player]
+  });
+}
+
+async function take_vacation(program, game, player, days) {
+  await program.rpc.takeVacation(days, {
+    accounts: {
+      player: player.publicKey,
+      game
+    },
+    signers: [player]
+  });
+}
+
+async function takeback(program, game, player, action) {
+  // action is "requestTakeback", "acceptTakeback" or "declineTakeback"
+  await program.rpc[action]({
+    accounts: {
+      player: player.publicKey,
+      game
+    },
+    signers: [player]
+  });
+}
+
+async function set_premoves(program, game, player, premoves) {
+  await program.rpc.setPremoves(premoves, {
+    accounts: {
+      player: player.publicKey,
+      game
+    },
+    signers: [player]
+  });
+}
+
+async function claim_timeout(program, game, reporter) {
+  await program.rpc.claimTimeout({
+    accounts: {
+      reporter: reporter.publicKey,
+      game
+    },
+    signers: [reporter]
+  });
+}
+
+async function abort(program, game, player) {
+  await program.rpc.abort({
+    accounts: {
+      player: player.publicKey,
+      game
+    },
+    signers: [player]
+  });
+}
+
+async function resign(program, game, player) {
+  await program.rpc.resign({
+    accounts: {
+      player: player.publicKey,
+      game
+    },
+    signers: [player]
+  });
+}
+
+async function update_draw(program, game, player, is_draw) {
+  await program.rpc.updateDraw(is_draw, {
+    accounts: {
+      player: player.publicKey,
+      game
+    },
+    signers: [player]
+  });
+}
+
+async function authorize_session(program, game, player, sessionKey, expiresAt) {
+  await program.rpc.authorizeSession(sessionKey.publicKey, new anchor.BN(expiresAt), {
+    accounts: {
+      player: player.publicKey,
+      game
+    },
+    signers: [player]
+  });
+}
+
+async function revoke_session(program, game, player) {
+  await program.rpc.revokeSession({
+    accounts: {
+      player: player.publicKey,
       game
     },
+    signers: [player]
+  });
+}
+
+async function airdrop(program, player) {
+  const signature = await program.provider.connection.requestAirdrop(player.publicKey, anchor.web3.LAMPORTS_PER_SOL);
+  await program.provider.connection.confirmTransaction(signature);
+}
+
+async function create_challenge(program, creator, opponent, color, time, bonus, wager=null, rated=false, timeMode={ fischer: {} }) {
+  const challengeKeypair = anchor.web3.Keypair.generate();
+  await program.rpc.createChallenge(opponent, color, new anchor.BN(time), bonus, wager, rated, timeMode, {
+    accounts: {
+      challenge: challengeKeypair.publicKey,
+      creator: creator.publicKey,
+      systemProgram: anchor.web3.SystemProgram.programId
+    },
+    signers: [challengeKeypair, creator]
+  });
+  return challengeKeypair;
+}
+
+async function accept_challenge(program, challenge, creator, acceptor) {
+  const game = await next_game_address(program, creator.publicKey);
+  await program.rpc.acceptChallenge({
+    accounts: {
+      challenge,
+      creator: creator.publicKey,
+      creatorProfile: await profile_address(program, creator.publicKey),
+      acceptorProfile: await profile_address(program, acceptor.publicKey),
+      game,
+      creatorEntry: await next_entry_address(program, creator.publicKey),
+      acceptorEntry: await next_entry_address(program, acceptor.publicKey),
+      acceptor: acceptor.publicKey,
+      systemProgram: anchor.web3.SystemProgram.programId
+    },
+    signers: [acceptor]
+  });
+  return game;
+}
+
+async function house_address(program) {
+  const [house] = await anchor.web3.PublicKey.findProgramAddress([Buffer.from("HOUSE")], program.programId);
+  return house;
+}
+
+async function program_data_address(program) {
+  const [programData] = await anchor.web3.PublicKey.findProgramAddress(
+    [program.programId.toBuffer()], new anchor.web3.PublicKey("BPFLoaderUpgradeab1e11111111111111111111111"));
+  return programData;
+}
+
+async function escrow_address(program, game) {
+  const [escrow] = await anchor.web3.PublicKey.findProgramAddress(
+    [Buffer.from("ESCROW"), game.toBuffer()], program.programId);
+  return escrow;
+}
+
+async function deposit_stake(program, game, mint, player, playerToken) {
+  await program.rpc.depositStake({
+    accounts: {
+      game,
+      house: await house_address(program),
+      mint,
+      escrow: await escrow_address(program, game),
+      playerToken,
+      player: player.publicKey,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+      rent: anchor.web3.SYSVAR_RENT_PUBKEY
+    },
+    signers: [player]
+  });
+}
+
+async function settle(program, game, whiteToken, blackToken, houseToken) {
+  await program.rpc.settle({
+    accounts: {
+      game,
+      escrow: await escrow_address(program, game),
+      house: await house_address(program),
+      whiteToken,
+      blackToken,
+      houseToken,
+      tokenProgram: TOKEN_PROGRAM_ID
+    },
+    signers: []
+  });
+}
+
+async function finalize_game(program, game, whitePlayer, blackPlayer) {
+  await program.rpc.finalizeGame({
+    accounts: {
+      game,
+      whiteProfile: await profile_address(program, whitePlayer.publicKey),
+      blackProfile: await profile_address(program, blackPlayer.publicKey)
+    },
     signers: []
   });
 }
 
-async function setup_game(program,authority,whitePlayer,blackPlayer,whiteTime,blackTime,whiteBonus,blackBonus) {
-  const gameKeypair = anchor.web3.Keypair.generate();
+function tournament_params(format, maxPlayers, numRounds, time, bonus, rated, knockout = {}) {
+  return {
+    format: { [format]: {} },
+    maxPlayers,
+    numRounds,
+    time: new anchor.BN(time),
+    bonus,
+    rated,
+    matchGames: knockout.matchGames || 0,
+    rapidGames: knockout.rapidGames || 0,
+    rapidTime: new anchor.BN(knockout.rapidTime || 0),
+    rapidBonus: knockout.rapidBonus || 0,
+    armageddonWhiteTime: new anchor.BN(knockout.armageddonWhiteTime || 0),
+    armageddonBlackTime: new anchor.BN(knockout.armageddonBlackTime || 0),
+    prizeSharesBps: knockout.prizeSharesBps || [0, 0, 0, 0]
+  };
+}
+
+async function create_tournament(program, organizer, params) {
+  const tournamentKeypair = anchor.web3.Keypair.generate();
+  await program.rpc.createTournament(params, {
+    accounts: {
+      tournament: tournamentKeypair.publicKey,
+      organizer: organizer.publicKey,
+      systemProgram: anchor.web3.SystemProgram.programId
+    },
+    signers: [tournamentKeypair]
+  });
+  return tournamentKeypair.publicKey;
+}
+
+async function register_players(program, tournament, players) {
+  for (const player of players) {
+    await program.rpc.registerPlayer({
+      accounts: { tournament, player: player.publicKey },
+      signers: [player]
+    });
+  }
+}
+
+async function play_tournament_round(program, tournament, organizer, players, decide) {
+  // Starts the next round, creates its games until every board is finished and lets
+  // `decide` pick the loser of each game, or null for a draw
+  await program.rpc.startRound({
+    accounts: { tournament, organizer: organizer.publicKey },
+    signers: []
+  });
+  let state = await program.account.tournament.fetch(tournament);
+  for (let board = 0; board < state.numPairings; board++) {
+    while (!state.pairings[board].finished) {
+      const [game] = await anchor.web3.PublicKey.findProgramAddress(
+        [Buffer.from("GAME"), tournament.toBuffer(), Buffer.from([state.currentRound]), Buffer.from([board]),
+          Buffer.from([state.pairings[board].games])], program.programId);
+      const first = state.players[state.pairings[board].white].key;
+      const second = state.players[state.pairings[board].black].key;
+      await program.rpc.createTournamentGame(board, {
+        accounts: {
+          tournament,
+          game,
+          firstProfile: await profile_address(program, first),
+          secondProfile: await profile_address(program, second),
+          firstEntry: await next_entry_address(program, first),
+          secondEntry: await next_entry_address(program, second),
+          payer: organizer.publicKey,
+          systemProgram: anchor.web3.SystemProgram.programId
+        },
+        signers: []
+      });
+      const gameState = await program.account.game.fetch(game);
+      const white = players.find(player => player.publicKey.equals(gameState.whitePlayer));
+      const black = players.find(player => player.publicKey.equals(gameState.blackPlayer));
+      const loser = decide(white.publicKey, black.publicKey);
+      if (loser) {
+        await resign(program, game, players.find(player => player.publicKey.equals(loser)));
+      } else {
+        await update_draw(program, game, white, true);
+        await update_draw(program, game, black, true);
+      }
+      await program.rpc.recordTournamentResult(board, {
+        accounts: { tournament, game },
+        signers: []
+      });
+      state = await program.account.tournament.fetch(tournament);
+    }
+  }
+}
+
+async function prize_address(program, tournament) {
+  const [prizeEscrow] = await anchor.web3.PublicKey.findProgramAddress(
+    [Buffer.from("PRIZE"), tournament.toBuffer()], program.programId);
+  return prizeEscrow;
+}
+
+async function game_moves(program, game) {
+  // The move list follows the zero-copy Game struct, one u16 per half-move
+  const data = (await program.provider.connection.getAccountInfo(game)).data;
+  const numMoves = (await program.account.game.fetch(game)).numMoves;
+  const moves = [];
+  for (let i = 0; i < numMoves; i++) {
+    moves.push(data.readUInt16LE(program.account.game.size + 2 * i));
+  }
+  return moves;
+}
+
+async function close_game(program, game, payer) {
+  const [record] = await anchor.web3.PublicKey.findProgramAddress(
+    [Buffer.from("RECORD"), game.toBuffer()], program.programId);
+  const gameState = await program.account.game.fetch(game);
+  await program.rpc.closeGame({
+    accounts: {
+      game,
+      record,
+      whitePlayer: gameState.whitePlayer,
+      blackPlayer: gameState.blackPlayer,
+      payer: payer.publicKey,
+      systemProgram: anchor.web3.SystemProgram.programId
+    },
+    signers: [payer]
+  });
+  return record;
+}
+
+async function expect_error(promise, code) {
+  try {
+    await promise;
+  } catch (err) {
+    expect(err.toString()).to.contain(code);
+    return;
+  }
+  expect.fail("expected " + code);
+}
+
+async function profile_address(program, player) {
+  const [profile] = await anchor.web3.PublicKey.findProgramAddress(
+    [Buffer.from("PROFILE"), player.toBuffer()], program.programId);
+  return profile;
+}
+
+async function next_game_address(program, player) {
+  // A game set up with player as white, or created from player's challenge, is at their
+  // next index, profile.numGames
+  const profile = await profile_address(program, player);
+  const numGames = (await program.account.profile.fetch(profile)).numGames;
+  const [game] = await anchor.web3.PublicKey.findProgramAddress(
+    [Buffer.from("GAME"), player.toBuffer(), numGames.toArrayLike(Buffer, "le", 8)], program.programId);
+  return game;
+}
+
+async function entry_address(program, player, index) {
+  const [entry] = await anchor.web3.PublicKey.findProgramAddress(
+    [Buffer.from("PLAYER_GAME"), player.toBuffer(), new anchor.BN(index).toArrayLike(Buffer, "le", 8)], program.programId);
+  return entry;
+}
+
+async function next_entry_address(program, player) {
+  const profile = await program.account.profile.fetch(await profile_address(program, player));
+  return entry_address(program, player, profile.numGames);
+}
+
+async function create_profile(program, player, signers) {
+  await program.rpc.createProfile({
+    accounts: {
+      profile: await profile_address(program, player.publicKey),
+      player: player.publicKey,
+      systemProgram: anchor.web3.SystemProgram.programId
+    },
+    signers
+  });
+}
+
+async function new_player(program) {
+  const player = anchor.web3.Keypair.generate();
+  await airdrop(program, player);
+  await create_profile(program, player, [player]);
+  return player;
+}
+
+async function setup_game(program,authority,whitePlayer,blackPlayer,whiteTime,blackTime,whiteBonus,blackBonus,wager=null,rated=false,timeMode={ fischer: {} },periods=[],signers=[whitePlayer,blackPlayer]) {
+  const game = await next_game_address(program, whitePlayer.publicKey);
   await program.rpc.setupGame(whitePlayer.publicKey,blackPlayer.publicKey, new anchor.BN(whiteTime), new anchor.BN(blackTime), 
-    whiteBonus, blackBonus, {
+    whiteBonus, blackBonus, wager, rated, timeMode, periods, {
     accounts: {
-      game: gameKeypair.publicKey,
+      whiteProfile: await profile_address(program, whitePlayer.publicKey),
+      blackProfile: await profile_address(program, blackPlayer.publicKey),
+      game,
+      whiteEntry: await next_entry_address(program, whitePlayer.publicKey),
+      blackEntry: await next_entry_address(program, blackPlayer.publicKey),
+      white: whitePlayer.publicKey,
+      black: blackPlayer.publicKey,
       authority: authority.publicKey,
       systemProgram: anchor.web3.SystemProgram.programId
     },
-    signers: [gameKeypair]
+    signers
   });
-  return gameKeypair;
+  return game;
 }
 
 describe("chess_game", () => {
@@ -36,61 +420,701 @@ describe("chess_game", () => {
   const whitePlayer = anchor.web3.Keypair.generate();
   const blackPlayer = anchor.web3.Keypair.generate();
 
+  before(async () => {
+    await airdrop(program, whitePlayer);
+    await airdrop(program, blackPlayer);
+    await create_profile(program, authority, []);
+    await create_profile(program, whitePlayer, [whitePlayer]);
+    await create_profile(program, blackPlayer, [blackPlayer]);
+    await program.rpc.initHouse(500, {
+      accounts: {
+        house: await house_address(program),
+        programData: await program_data_address(program),
+        admin: authority.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId
+      },
+      signers: []
+    });
+  });
+
   it("setup_game", async () => {
-    const gameKeypair = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
 
-    let gameState = await program.account.game.fetch(gameKeypair.publicKey);
+    let gameState = await program.account.game.fetch(game);
     expect(gameState.numMoves).to.equal(0);
+    // Both players have to sign
+    await expect_error(setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1,null,false,{ fischer: {} },[],[whitePlayer]), "Signature verification failed");
+  });
+
+  it("games_are_enumerable", async () => {
+    // Both players list the game, whoever set it up
+    const numGames = async (player) =>
+      (await program.account.profile.fetch(await profile_address(program, player.publicKey))).numGames.toNumber();
+    const whiteBefore = await numGames(whitePlayer);
+    const blackBefore = await numGames(blackPlayer);
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    expect(await numGames(whitePlayer)).to.equal(whiteBefore + 1);
+    expect(await numGames(blackPlayer)).to.equal(blackBefore + 1);
+    const whiteEntry = await program.account.playerGame.fetch(await entry_address(program, whitePlayer.publicKey, whiteBefore));
+    const blackEntry = await program.account.playerGame.fetch(await entry_address(program, blackPlayer.publicKey, blackBefore));
+    expect(whiteEntry.game.toString()).to.equal(game.toString());
+    expect(blackEntry.game.toString()).to.equal(game.toString());
+    await expect_error(setup_game(program,authority,whitePlayer,whitePlayer,100,100,1,1), "0x0");
   });
 
   it("play_16_moves_valid", async () => {
-    const gameKeypair = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
     for (let i=0;i<8;i++) {
-      await play(program,gameKeypair.publicKey,authority,5,1,i,3,i);
-      await play(program,gameKeypair.publicKey,authority,5,6,i,4,i);
+      await play(program,game,whitePlayer,5,1,i,3,i);
+      await play(program,game,blackPlayer,5,6,i,4,i);
     }
-    let gameState = await program.account.game.fetch(gameKeypair.publicKey);
+    let gameState = await program.account.game.fetch(game);
     expect(gameState.numMoves).to.equal(16);
-    expect(gameState.currBoard.halfMoves).to.equal(0);
+    expect(gameState.board.halfMoves).to.equal(0);
   });
 
   it("play_repitition", async () => {
-    const gameKeypair = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
     for (let i=0;i<2;i++) {
-      await play(program,gameKeypair.publicKey,authority,1,0,1,2,2);
-      await play(program,gameKeypair.publicKey,authority,1,7,1,5,2);
-      await play(program,gameKeypair.publicKey,authority,1,2,2,0,1);
-      await play(program,gameKeypair.publicKey,authority,1,5,2,7,1);
+      await play(program,game,whitePlayer,1,0,1,2,2);
+      await play(program,game,blackPlayer,1,7,1,5,2);
+      await play(program,game,whitePlayer,1,2,2,0,1);
+      await play(program,game,blackPlayer,1,5,2,7,1);
     }
-    let gameState = await program.account.game.fetch(gameKeypair.publicKey);
-    expect(gameState.currBoard.halfMoves).to.equal(8);
-    expect(Object.keys(gameState.status)[0]).to.equal('drawRepetition');
+    let gameState = await program.account.game.fetch(game);
+    expect(gameState.board.halfMoves).to.equal(8);
+    expect(status_of(gameState)).to.equal('drawRepetition');
   });
 
   it("play_checkmate", async () => {
-    const gameKeypair = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
-    await play(program,gameKeypair.publicKey,authority,5,1,4,3,4); // 1. e4
-    await play(program,gameKeypair.publicKey,authority,5,6,5,4,5); // 1. f6
-    await play(program,gameKeypair.publicKey,authority,1,0,1,2,2); // 2. Nc3
-    await play(program,gameKeypair.publicKey,authority,5,6,6,4,6); // 2. g5
-    await play(program,gameKeypair.publicKey,authority,3,0,3,4,7); // 3. Qh5#
-    let gameState = await program.account.game.fetch(gameKeypair.publicKey);
-    expect(Object.keys(gameState.status)[0]).to.equal('whiteWinCheckmate');
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    await play(program,game,blackPlayer,5,6,5,4,5); // 1. f6
+    await play(program,game,whitePlayer,1,0,1,2,2); // 2. Nc3
+    await play(program,game,blackPlayer,5,6,6,4,6); // 2. g5
+    await play(program,game,whitePlayer,3,0,3,4,7); // 3. Qh5#
+    let gameState = await program.account.game.fetch(game);
+    expect(status_of(gameState)).to.equal('whiteWinCheckmate');
+  });
+
+  it("pawns_capture_only_diagonally", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    await play(program,game,blackPlayer,5,6,3,4,3); // 1. d5
+    await expect_error(play(program,game,whitePlayer,5,3,4,4,5), "NothingToCapture"); // 2. exf5
+    await play(program,game,whitePlayer,5,3,4,4,3); // 2. exd5
+    await play(program,game,blackPlayer,5,6,4,4,4); // 2. e5
+    await play(program,game,whitePlayer,5,4,3,5,4); // 3. dxe6 e.p.
+    expect((await program.account.game.fetch(game)).numMoves).to.equal(5);
+  });
+
+  it("castling_out_of_and_through_check", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    await play(program,game,blackPlayer,5,6,4,4,4); // 1. e5
+    await play(program,game,whitePlayer,1,0,6,2,5); // 2. Nf3
+    await play(program,game,blackPlayer,1,7,1,5,2); // 2. Nc6
+    await play(program,game,whitePlayer,2,0,5,3,2); // 3. Bc4
+    await play(program,game,blackPlayer,2,7,5,4,2); // 3. Bc5
+    await play(program,game,whitePlayer,5,1,3,3,3); // 4. d4
+    await play(program,game,blackPlayer,2,4,2,3,1); // 4. Bb4+
+    await expect_error(play(program,game,whitePlayer,4,0,4,0,6), "CastlingOutOfCheck");
+    await play(program,game,whitePlayer,5,1,2,2,2); // 5. c3
+    await play(program,game,blackPlayer,1,7,6,5,5); // 5. Nf6
+    await play(program,game,whitePlayer,4,0,4,0,6); // 6. O-O
+
+    // Ba6 covers f1 once the e-pawn has left e2
+    const through = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await play(program,through,whitePlayer,5,1,6,2,6); // 1. g3
+    await play(program,through,blackPlayer,5,6,1,5,1); // 1. b6
+    await play(program,through,whitePlayer,2,0,5,1,6); // 2. Bg2
+    await play(program,through,blackPlayer,2,7,2,5,0); // 2. Ba6
+    await play(program,through,whitePlayer,1,0,6,2,5); // 3. Nf3
+    await play(program,through,blackPlayer,1,7,1,5,2); // 3. Nc6
+    await play(program,through,whitePlayer,5,1,4,2,4); // 4. e3
+    await play(program,through,blackPlayer,1,7,6,5,5); // 4. Nf6
+    await expect_error(play(program,through,whitePlayer,4,0,4,0,6), "CastlingThroughCheck");
+  });
+
+  it("promoted_piece_moves_as_promoted", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await play(program,game,whitePlayer,5,1,7,3,7); // 1. h4
+    await play(program,game,blackPlayer,5,6,6,4,6); // 1. g5
+    await play(program,game,whitePlayer,5,3,7,4,6); // 2. hxg5
+    await play(program,game,blackPlayer,5,6,0,5,0); // 2. a6
+    await play(program,game,whitePlayer,5,4,6,5,6); // 3. g6
+    await play(program,game,blackPlayer,5,5,0,4,0); // 3. a5
+    await play(program,game,whitePlayer,5,5,6,6,7); // 4. gxh7
+    await play(program,game,blackPlayer,5,4,0,3,0); // 4. a4
+    await play(program,game,whitePlayer,9,6,7,7,6); // 5. hxg8=Q
+    await play(program,game,blackPlayer,5,3,0,2,0); // 5. a3
+    await play(program,game,whitePlayer,3,7,6,7,7); // 6. Qxh8, only legal if g8 holds a queen
+    expect((await program.account.game.fetch(game)).numMoves).to.equal(11);
+  });
+
+  it("check_detection", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    await play(program,game,blackPlayer,5,6,5,4,5); // 1. f5
+    await play(program,game,whitePlayer,3,0,3,4,7); // 2. Qh5+
+    // The pawn push g6 is black's only move, so this is not mate
+    let gameState = await program.account.game.fetch(game);
+    expect(status_of(gameState)).to.equal('active');
+    await play(program,game,blackPlayer,5,6,6,5,6); // 2. g6
+    await play(program,game,whitePlayer,4,0,4,1,4); // 3. Ke2
+    await play(program,game,blackPlayer,5,4,5,3,4); // 3. fxe4
+    // Black pawns attack towards the first rank
+    await expect_error(play(program,game,whitePlayer,4,1,4,2,3), "LeavesKingInCheck"); // 4. Kd3
+    await play(program,game,whitePlayer,4,1,4,2,4); // 4. Ke3
+  });
+
+  it("move_history_is_stored", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    const before = (await program.provider.connection.getAccountInfo(game)).data.length;
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    await play(program,game,blackPlayer,5,6,4,4,4); // 1. e5
+    await play(program,game,whitePlayer,1,0,6,2,5); // 2. Nf3
+    expect(await game_moves(program, game)).to.deep.equal([
+      (5 << 12)+(1 << 9)+(4 << 6)+(3 << 3)+4,
+      (5 << 12)+(6 << 9)+(4 << 6)+(4 << 3)+4,
+      (1 << 12)+(0 << 9)+(6 << 6)+(2 << 3)+5
+    ]);
+    // The account grew once, with room for more moves
+    expect((await program.provider.connection.getAccountInfo(game)).data.length).to.be.greaterThan(before + 6);
+  });
+
+  it("long_game_grows_the_move_list", async () => {
+    // Every player pays for the growth their moves need and gets it back on close
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,600,600,1,1);
+    // [player, piece, from_rank, from_col, to_rank, to_col]. Pawn moves keep the knight moves from
+    // repeating a position three times.
+    const turns = [];
+    for (const step of [0, 1]) {
+      for (const file of [0, 1, 2, 3, 4, 7]) {
+        turns.push([whitePlayer,5,1 + step,file,2 + step,file], [blackPlayer,5,6 - step,file,5 - step,file]);
+        turns.push([whitePlayer,1,0,6,2,5], [blackPlayer,1,7,6,5,5], [whitePlayer,1,2,5,0,6], [blackPlayer,1,5,5,7,6]);
+      }
+    }
+    for (const [player, piece, fromRank, fromCol, toRank, toCol] of turns) {
+      await play(program,game,player,piece,fromRank,fromCol,toRank,toCol,player);
+    }
+    const moves = await game_moves(program, game);
+    expect(moves.length).to.equal(72);
+    expect(moves).to.deep.equal(turns.map(([player, piece, fromRank, fromCol, toRank, toCol]) =>
+      (piece << 12)+(fromRank << 9)+(fromCol << 6)+(toRank << 3)+toCol));
+    // White paid for the first chunk of moves, black for the second
+    const gameState = await program.account.game.fetch(game);
+    expect(gameState.whiteRent.toNumber()).to.be.greaterThan(0);
+    expect(gameState.blackRent.toNumber()).to.be.greaterThan(0);
+
+    await resign(program,game,blackPlayer);
+    const whiteBalance = await program.provider.connection.getBalance(whitePlayer.publicKey);
+    const blackBalance = await program.provider.connection.getBalance(blackPlayer.publicKey);
+    await close_game(program,game,authority);
+    expect(await program.provider.connection.getBalance(whitePlayer.publicKey)).to.equal(whiteBalance + gameState.whiteRent.toNumber());
+    expect(await program.provider.connection.getBalance(blackPlayer.publicKey)).to.equal(blackBalance + gameState.blackRent.toNumber());
+  });
+
+  it("hourglass_clock", async () => {
+    // Time used by the mover goes to the opponent, so the total never changes
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,0,0,null,false,{ hourglass: {} });
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    await new Promise(resolve => setTimeout(resolve, 2000));
+    await play(program,game,blackPlayer,5,6,4,4,4); // 1. e5
+    const gameState = await program.account.game.fetch(game);
+    expect(gameState.whiteTimeLeft.toNumber() + gameState.blackTimeLeft.toNumber()).to.equal(200);
+    expect(gameState.whiteTimeLeft.toNumber()).to.be.greaterThan(gameState.blackTimeLeft.toNumber());
+  });
+
+  it("classical_periods", async () => {
+    // 1 move in 100 seconds, then 50 more seconds with 5 per move
+    const periods = [{ afterMoves: 1, time: new anchor.BN(50), bonus: 5 }];
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,0,0,null,false,{ fischer: {} },periods);
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    const gameState = await program.account.game.fetch(game);
+    expect(gameState.whitePeriod).to.equal(1);
+    expect(gameState.blackPeriod).to.equal(0);
+    expect(gameState.whiteBonusTime).to.equal(5);
+    expect(gameState.whiteTimeLeft.toNumber()).to.be.greaterThan(140);
+    const unordered = [periods[0], periods[0]];
+    await expect_error(setup_game(program,authority,whitePlayer,blackPlayer,100,100,0,0,null,false,{ fischer: {} },unordered), "InvalidTimeControl");
+  });
+
+  it("correspondence_vacation", async () => {
+    // Three days per move, no bank and a week of vacation each
+    const day = 24 * 60 * 60;
+    const timeMode = { correspondence: { maxBankDays: 0, vacationDays: 7 } };
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,3*day,3*day,3*day,3*day,null,false,timeMode);
+    await expect_error(take_vacation(program,game,whitePlayer,8), "VacationUnavailable");
+    await take_vacation(program,game,whitePlayer,2);
+    await expect_error(take_vacation(program,game,whitePlayer,1), "VacationUnavailable");
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    const gameState = await program.account.game.fetch(game);
+    expect(gameState.whiteVacation.daysLeft).to.equal(5);
+    expect(gameState.whiteTimeLeft.toNumber()).to.equal(3*day);
+    const blitz = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await expect_error(take_vacation(program,blitz,whitePlayer,1), "NotCorrespondence");
+  });
+
+  it("abort_before_both_moved", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1,null,true);
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    await abort(program,game,blackPlayer);
+    const gameState = await program.account.game.fetch(game);
+    expect(status_of(gameState)).to.equal('aborted');
+    expect(gameState.rated).to.equal(0);
+    await expect_error(resign(program,game,whitePlayer), "GameAlreadyOver");
+    const started = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await play(program,started,whitePlayer,5,1,4,3,4); // 1. e4
+    await play(program,started,blackPlayer,5,6,4,4,4); // 1. e5
+    await expect_error(abort(program,started,whitePlayer), "CannotAbort");
+  });
+
+  it("takeback", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    await play(program,game,blackPlayer,5,6,4,4,4); // 1. e5
+    await expect_error(takeback(program,game,whitePlayer,"acceptTakeback"), "NoTakebackRequested");
+    await takeback(program,game,blackPlayer,"requestTakeback");
+    await takeback(program,game,whitePlayer,"declineTakeback");
+    // White to move, so white's request undoes both 1. e4 and 1. e5
+    await takeback(program,game,whitePlayer,"requestTakeback");
+    await expect_error(takeback(program,game,whitePlayer,"acceptTakeback"), "NoTakebackRequested");
+    await takeback(program,game,blackPlayer,"acceptTakeback");
+    let gameState = await program.account.game.fetch(game);
+    expect(gameState.numMoves).to.equal(0);
+    expect(gameState.whiteTimeLeft.toNumber()).to.equal(100);
+    await play(program,game,whitePlayer,5,1,3,3,3); // 1. d4
+    const rated = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1,null,true);
+    await play(program,rated,whitePlayer,5,1,4,3,4); // 1. e4
+    await expect_error(takeback(program,rated,whitePlayer,"requestTakeback"), "TakebacksNotAllowed");
+  });
+
+  it("takebacks_in_a_row", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    await play(program,game,blackPlayer,5,6,4,4,4); // 1. e5
+    await play(program,game,whitePlayer,1,0,6,2,5); // 2. Nf3
+    await play(program,game,blackPlayer,1,7,1,5,2); // 2. Nc6
+    // Undoes 2. Nc6, then with black to move 2. Nf3 and 1. e5
+    await takeback(program,game,blackPlayer,"requestTakeback");
+    await takeback(program,game,whitePlayer,"acceptTakeback");
+    await takeback(program,game,blackPlayer,"requestTakeback");
+    await takeback(program,game,whitePlayer,"acceptTakeback");
+    const gameState = await program.account.game.fetch(game);
+    expect(gameState.numMoves).to.equal(1);
+    expect(gameState.blackTimeLeft.toNumber()).to.equal(100);
+    await play(program,game,blackPlayer,5,6,3,4,3); // 1. d5
+  });
+
+  it("takeback_after_a_long_game", async () => {
+    // Closed Ruy Lopez, Chigorin Defense, to 22. Nf1 Na5. The takeback restores a
+    // snapshot, so its cost does not grow with the game.
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,600,600,1,1);
+    const moves = [[5,1,4,3,4], [5,6,4,4,4], [1,0,6,2,5], [1,7,1,5,2], [2,0,5,4,1], [5,6,0,5,0], [2,4,1,3,0], [1,7,6,5,5],
+      [4,0,4,0,6], [2,7,5,6,4], [0,0,5,0,4], [5,6,1,4,1], [2,3,0,2,1], [5,6,3,5,3], [5,1,2,2,2], [4,7,4,7,6],
+      [5,1,7,2,7], [1,5,2,4,0], [2,2,1,1,2], [5,6,2,4,2], [5,1,3,3,3], [3,7,3,6,2], [1,0,1,1,3], [5,4,2,3,3],
+      [5,2,2,3,3], [1,4,0,5,2], [1,1,3,2,1], [5,5,0,4,0], [2,0,2,2,4], [5,4,0,3,0], [1,2,1,1,3], [2,7,2,6,3],
+      [0,0,0,0,2], [3,6,2,6,1], [3,0,3,1,4], [0,7,5,7,4], [2,1,2,2,3], [0,7,0,7,2], [5,1,0,2,0], [5,6,7,5,7],
+      [2,2,3,0,1], [2,6,4,7,5], [1,1,3,0,5], [1,5,2,4,0]];
+    for (let index = 0; index < moves.length; index++) {
+      await play(program,game,index % 2 == 0 ? whitePlayer : blackPlayer,...moves[index]);
+    }
+    // White to move, so white's request undoes 22. Nf1 Na5
+    await takeback(program,game,whitePlayer,"requestTakeback");
+    await takeback(program,game,blackPlayer,"acceptTakeback");
+    const gameState = await program.account.game.fetch(game);
+    expect(gameState.numMoves).to.equal(42);
+    await play(program,game,whitePlayer,1,1,3,0,5); // 22. Nf1
+  });
+
+  it("illegal_moves_and_timeouts_are_reported", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,2,100,0,0);
+    await expect_error(play(program,game,whitePlayer,1,0,2,2,3), "WrongPiece");
+    await expect_error(play(program,game,whitePlayer,0,0,0,2,0), "PathBlocked");
+    await expect_error(claim_timeout(program,game,blackPlayer), "NoTimeout");
+    await new Promise(resolve => setTimeout(resolve, 4000));
+    await expect_error(play(program,game,whitePlayer,5,1,4,3,4), "FlagFell");
+    // White never moved, so the game is aborted rather than lost
+    await claim_timeout(program,game,blackPlayer);
+    const gameState = await program.account.game.fetch(game);
+    expect(status_of(gameState)).to.equal('aborted');
+    await expect_error(claim_timeout(program,game,blackPlayer), "GameAlreadyOver");
+  });
+
+  it("premoves_answer_in_the_same_instruction", async () => {
+    const turn = (piece, from_rank, from_col, to_rank, to_col) => (piece << 12)+(from_rank << 9)+(from_col << 6)+(to_rank << 3)+(to_col);
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,0,0);
+    // Black answers 1. d4 with d5 and 1. e4 with e5, then 2. Nf3 with Nc6
+    await set_premoves(program,game,blackPlayer,[
+      { after: 0, condition: turn(5,1,3,3,3), reply: turn(5,6,3,4,3) },
+      { after: 0, condition: turn(5,1,4,3,4), reply: turn(5,6,4,4,4) },
+      { after: 2, condition: turn(1,0,6,2,5), reply: turn(1,7,1,5,2) },
+    ]);
+    await expect_error(set_premoves(program,game,blackPlayer,[{ after: 1, condition: 0, reply: turn(5,6,4,4,4) }]), "InvalidPremoves");
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4 e5
+    await play(program,game,whitePlayer,1,0,6,2,5); // 2. Nf3 Nc6
+    const gameState = await program.account.game.fetch(game);
+    expect(gameState.numMoves).to.equal(4);
+    expect(gameState.blackTimeLeft.toNumber()).to.equal(100);
+    await play(program,game,whitePlayer,2,0,5,3,2); // 3. Bc4
+    expect((await program.account.game.fetch(game)).numMoves).to.equal(5);
+  });
+
+  it("premove_chains_stop_at_the_cap", async () => {
+    const turn = (piece, from_rank, from_col, to_rank, to_col) => (piece << 12)+(from_rank << 9)+(from_col << 6)+(to_rank << 3)+(to_col);
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,0,0);
+    // Both sides premove the Closed Ruy Lopez to 8...O-O, each entry following the side's previous one
+    const line = [[5,1,4,3,4], [5,6,4,4,4], [1,0,6,2,5], [1,7,1,5,2], [2,0,5,4,1], [5,6,0,5,0], [2,4,1,3,0], [1,7,6,5,5],
+      [4,0,4,0,6], [2,7,5,6,4], [0,0,5,0,4], [5,6,1,4,1], [2,3,0,2,1], [5,6,3,5,3], [5,1,2,2,2], [4,7,4,7,6],
+      [5,1,7,2,7]].map(move => turn(...move));
+    const entries = (first) => [...Array(8).keys()].map(index => ({ after: index, condition: line[first + 2*index], reply: line[first + 2*index + 1] }));
+    await set_premoves(program,game,blackPlayer,entries(0));
+    await set_premoves(program,game,whitePlayer,entries(1));
+    // 1. e4 sets off four premoves, up to 3. Bb5, then black plays on by hand
+    await play(program,game,whitePlayer,5,1,4,3,4);
+    expect((await program.account.game.fetch(game)).numMoves).to.equal(5);
+    await play(program,game,blackPlayer,5,6,0,5,0); // 3... a6 4. Ba4
+    expect((await program.account.game.fetch(game)).numMoves).to.equal(7);
+  });
+
+  it("play_wrong_signer", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await expect_error(play(program,game,blackPlayer,5,6,4,4,4), "NotYourTurn");
+    const stranger = anchor.web3.Keypair.generate();
+    await expect_error(play(program,game,stranger,5,1,4,3,4), "NotAPlayer");
+    let gameState = await program.account.game.fetch(game);
+    expect(gameState.numMoves).to.equal(0);
+  });
+
+  it("session_key", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    const whiteSession = anchor.web3.Keypair.generate();
+    const now = Math.floor(Date.now() / 1000);
+    await authorize_session(program,game,whitePlayer,whiteSession,now+3600);
+    await play(program,game,whiteSession,5,1,4,3,4); // 1. e4
+    await play(program,game,blackPlayer,5,6,4,4,4); // 1. e5
+    await expect_error(authorize_session(program,game,whiteSession,whiteSession,now+3600), "NotAPlayer");
+    await revoke_session(program,game,whitePlayer);
+    await expect_error(play(program,game,whiteSession,1,0,6,2,5), "NotAPlayer");
+    await play(program,game,whitePlayer,1,0,6,2,5); // 2. Nf3
+    const expiredSession = anchor.web3.Keypair.generate();
+    await authorize_session(program,game,blackPlayer,expiredSession,now-1);
+    await expect_error(play(program,game,expiredSession,1,7,1,5,2), "NotAPlayer");
+    let gameState = await program.account.game.fetch(game);
+    expect(gameState.numMoves).to.equal(3);
+  });
+
+  it("session_keys_only_play_draw_and_resign", async () => {
+    const timeMode = { correspondence: { maxBankDays: 0, vacationDays: 7 } };
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,3*86400,3*86400,86400,86400,null,false,timeMode);
+    const whiteSession = anchor.web3.Keypair.generate();
+    const now = Math.floor(Date.now() / 1000);
+    await authorize_session(program,game,whitePlayer,whiteSession,now+3600);
+    await expect_error(abort(program,game,whiteSession), "NotAPlayer");
+    await expect_error(take_vacation(program,game,whiteSession,1), "NotAPlayer");
+    await expect_error(set_premoves(program,game,whiteSession,[]), "NotAPlayer");
+    await play(program,game,whiteSession,5,1,4,3,4); // 1. e4
+    await play(program,game,blackPlayer,5,6,4,4,4); // 1. e5
+    await expect_error(takeback(program,game,whiteSession,"requestTakeback"), "NotAPlayer");
+    await takeback(program,game,blackPlayer,"requestTakeback");
+    await expect_error(takeback(program,game,whiteSession,"acceptTakeback"), "NotAPlayer");
+    await expect_error(takeback(program,game,whiteSession,"declineTakeback"), "NotAPlayer");
+    await takeback(program,game,whitePlayer,"acceptTakeback");
+    expect((await program.account.game.fetch(game)).numMoves).to.equal(1);
+  });
+
+  it("resign", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await resign(program,game,blackPlayer);
+    let gameState = await program.account.game.fetch(game);
+    expect(status_of(gameState)).to.equal('whiteWinResignation');
+  });
+
+  it("draw_agreement", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await update_draw(program,game,whitePlayer,true);
+    let gameState = await program.account.game.fetch(game);
+    expect(status_of(gameState)).to.equal('active');
+    await update_draw(program,game,blackPlayer,true);
+    gameState = await program.account.game.fetch(game);
+    expect(status_of(gameState)).to.equal('drawAgreement');
+  });
+
+  it("draw_offer_lapses_with_next_move", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await play(program,game,whitePlayer,5,1,4,3,4,null,true); // 1. e4, offering a draw
+    let gameState = await program.account.game.fetch(game);
+    expect(gameState.whiteDrawOpen).to.equal(1);
+    await play(program,game,blackPlayer,5,6,4,4,4); // 1. e5 declines it
+    gameState = await program.account.game.fetch(game);
+    expect(gameState.whiteDrawOpen).to.equal(0);
+    await expect_error(update_draw(program,game,whitePlayer,true), "DrawOfferTooSoon");
+    await update_draw(program,game,blackPlayer,true);
+    await update_draw(program,game,whitePlayer,true);
+    gameState = await program.account.game.fetch(game);
+    expect(status_of(gameState)).to.equal('drawAgreement');
+  });
+
+  it("close_game", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    await expect_error(close_game(program,game,authority), "GameNotOver");
+    await resign(program,game,blackPlayer);
+    await expect_error(close_game(program,game,whitePlayer), "ConstraintHasOne");
+
+    const balance = await program.provider.connection.getBalance(authority.publicKey);
+    const record = await close_game(program,game,authority);
+    expect(await program.provider.connection.getAccountInfo(game)).to.be.null;
+    expect(await program.provider.connection.getBalance(authority.publicKey)).to.be.greaterThan(balance);
+    const gameRecord = await program.account.gameRecord.fetch(record);
+    expect(gameRecord.whitePlayer.toString()).to.equal(whitePlayer.publicKey.toString());
+    expect(Object.keys(gameRecord.status)[0]).to.equal('whiteWinResignation');
+    expect(gameRecord.numMoves).to.equal(1);
+  });
+
+  it("rated_game_must_be_finalized_before_close", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1,null,true);
+    await resign(program,game,blackPlayer);
+    await expect_error(close_game(program,game,authority), "RatingsNotUpdated");
+    await finalize_game(program,game,whitePlayer,blackPlayer);
+    await close_game(program,game,authority);
+  });
+
+  it("emits_game_events", async () => {
+    const events = [];
+    const names = ["GameCreated", "MovePlayed", "DrawOffered", "DrawWithdrawn", "GameEnded"];
+    const listeners = names.map(name => program.addEventListener(name, event => events.push([name, event])));
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    await update_draw(program,game,blackPlayer,true);
+    await update_draw(program,game,blackPlayer,false);
+    await resign(program,game,blackPlayer);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+    for (const listener of listeners) {
+      await program.removeEventListener(listener);
+    }
+
+    const ofGame = events.filter(([name, event]) => event.game.equals(game));
+    expect(ofGame.map(([name]) => name)).to.deep.equal(["GameCreated", "MovePlayed", "DrawOffered", "DrawWithdrawn", "GameEnded"]);
+    const move = ofGame[1][1];
+    expect(move.moveNumber).to.equal(1);
+    expect(move.whiteTimeLeft.toNumber()).to.be.greaterThan(0);
+    expect(ofGame[2][1].white).to.be.false;
+    const ended = ofGame[4][1];
+    expect(Object.keys(ended.status)[0]).to.equal('whiteWinResignation');
+    expect(Object.keys(ended.reason)[0]).to.equal('resignation');
+  });
+
+  it("challenge_accept", async () => {
+    const challengeKeypair = await create_challenge(program,blackPlayer,null,{ black: {} },300,2);
+    let challenge = await program.account.challenge.fetch(challengeKeypair.publicKey);
+    expect(challenge.creator.toString()).to.equal(blackPlayer.publicKey.toString());
+    const game = await accept_challenge(program,challengeKeypair.publicKey,blackPlayer,whitePlayer);
+    let gameState = await program.account.game.fetch(game);
+    expect(gameState.whitePlayer.toString()).to.equal(whitePlayer.publicKey.toString());
+    expect(gameState.blackPlayer.toString()).to.equal(blackPlayer.publicKey.toString());
+    expect(gameState.whiteTimeLeft.toNumber()).to.equal(300);
+    expect(gameState.blackBonusTime).to.equal(2);
+    expect(await program.provider.connection.getAccountInfo(challengeKeypair.publicKey)).to.be.null;
+    // The acceptor paid for the game, so the wallet cannot pay to grow it
+    await expect_error(play(program,game,whitePlayer,5,1,4,3,4), "WrongRentPayer");
+    await play(program,game,whitePlayer,5,1,4,3,4,whitePlayer); // 1. e4
+  });
+
+  it("challenge_specific_opponent", async () => {
+    const stranger = await new_player(program);
+    const challengeKeypair = await create_challenge(program,whitePlayer,blackPlayer.publicKey,{ random: {} },60,0);
+    await expect_error(accept_challenge(program,challengeKeypair.publicKey,whitePlayer,stranger), "CannotAcceptChallenge");
+    // The creator's two index entries would share an address, the system program rejects the second
+    await expect_error(accept_challenge(program,challengeKeypair.publicKey,whitePlayer,whitePlayer), "0x0");
+    const game = await accept_challenge(program,challengeKeypair.publicKey,whitePlayer,blackPlayer);
+    let gameState = await program.account.game.fetch(game);
+    const players = [gameState.whitePlayer.toString(), gameState.blackPlayer.toString()].sort();
+    expect(players).to.deep.equal([whitePlayer.publicKey.toString(), blackPlayer.publicKey.toString()].sort());
+  });
+
+  it("challenge_cancel", async () => {
+    const challengeKeypair = await create_challenge(program,whitePlayer,null,{ white: {} },60,0);
+    await program.rpc.cancelChallenge({
+      accounts: {
+        challenge: challengeKeypair.publicKey,
+        creator: whitePlayer.publicKey
+      },
+      signers: [whitePlayer]
+    });
+    expect(await program.provider.connection.getAccountInfo(challengeKeypair.publicKey)).to.be.null;
+  });
+
+  it("wager_settle", async () => {
+    const mint = await Token.createMint(program.provider.connection, whitePlayer, whitePlayer.publicKey, null, 0, TOKEN_PROGRAM_ID);
+    const whiteToken = await mint.createAccount(whitePlayer.publicKey);
+    const blackToken = await mint.createAccount(blackPlayer.publicKey);
+    const houseToken = await mint.createAccount(authority.publicKey);
+    await mint.mintTo(whiteToken, whitePlayer, [], 1000);
+    await mint.mintTo(blackToken, whitePlayer, [], 1000);
+
+    const wager = { mint: mint.publicKey, stake: new anchor.BN(100), maxFeeBps: 500 };
+    // The house takes 5%, more than these players accept
+    const stingy = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1,{ ...wager, maxFeeBps: 100 });
+    await expect_error(deposit_stake(program,stingy,mint.publicKey,whitePlayer,whiteToken), "HouseFeeTooHigh");
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1,wager);
+    await deposit_stake(program,game,mint.publicKey,whitePlayer,whiteToken);
+    await expect_error(play(program,game,whitePlayer,5,1,4,3,4), "StakesNotDeposited");
+    await deposit_stake(program,game,mint.publicKey,blackPlayer,blackToken);
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    await expect_error(settle(program,game,whiteToken,blackToken,houseToken), "GameNotOver");
+    await resign(program,game,blackPlayer);
+    await settle(program,game,whiteToken,blackToken,houseToken);
+    await expect_error(settle(program,game,whiteToken,blackToken,houseToken), "AlreadySettled");
+
+    expect((await mint.getAccountInfo(whiteToken)).amount.toNumber()).to.equal(1090);
+    expect((await mint.getAccountInfo(blackToken)).amount.toNumber()).to.equal(900);
+    expect((await mint.getAccountInfo(houseToken)).amount.toNumber()).to.equal(10);
+  });
+
+  it("rated_game_updates_profiles", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1,null,true);
+    await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+    await play(program,game,blackPlayer,5,6,5,4,5); // 1. f6
+    await play(program,game,whitePlayer,5,1,3,3,3); // 2. d4
+    await play(program,game,blackPlayer,5,6,6,4,6); // 2. g5
+    await play(program,game,whitePlayer,3,0,3,4,7); // 3. Qh5#
+    await finalize_game(program,game,whitePlayer,blackPlayer);
+    await expect_error(finalize_game(program,game,whitePlayer,blackPlayer), "AlreadyFinalized");
+
+    // 100 seconds + 1 per move is a bullet game
+    const white = (await program.account.profile.fetch(await profile_address(program, whitePlayer.publicKey))).ratings[0];
+    const black = (await program.account.profile.fetch(await profile_address(program, blackPlayer.publicKey))).ratings[0];
+    expect(white.wins).to.equal(1);
+    expect(black.losses).to.equal(1);
+    expect(white.rating).to.be.greaterThan(1500);
+    expect(black.rating).to.be.lessThan(1500);
+    expect(white.deviation).to.be.lessThan(350);
+  });
+
+  it("unrated_game_cannot_be_finalized", async () => {
+    const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+    await resign(program,game,whitePlayer);
+    await expect_error(finalize_game(program,game,whitePlayer,blackPlayer), "GameNotRated");
+  });
+
+  it("swiss_tournament", async () => {
+    const players = [whitePlayer, blackPlayer, await new_player(program), await new_player(program)];
+    const tournament = await create_tournament(program,authority,tournament_params('swiss',4,2,300,2,false));
+    await register_players(program, tournament, players);
+    await expect_error(program.rpc.registerPlayer({
+      accounts: { tournament, player: players[0].publicKey },
+      signers: [players[0]]
+    }), "AlreadyRegistered");
+
+    // Player 0 wins every game, otherwise white wins
+    const decide = (white, black) => white.equals(players[0].publicKey) ? black : white;
+    await play_tournament_round(program, tournament, authority, players, decide);
+    let state = await program.account.tournament.fetch(tournament);
+    expect(state.players[0].score).to.equal(2);
+    await play_tournament_round(program, tournament, authority, players, decide);
+
+    state = await program.account.tournament.fetch(tournament);
+    expect(Object.keys(state.status)[0]).to.equal('finished');
+    expect(state.standings[0]).to.equal(0);
+    expect(state.players[0].score).to.equal(4);
+    // No one meets the same opponent twice
+    for (const player of state.players.slice(0, 4)) {
+      expect(player.rounds[0].opponent).to.not.equal(player.rounds[1].opponent);
+    }
+  });
+
+  it("round_robin_tournament", async () => {
+    const players = [whitePlayer, blackPlayer, await new_player(program)];
+    const tournament = await create_tournament(program,authority,tournament_params('roundRobin',3,0,300,2,false));
+    await register_players(program, tournament, players);
+
+    // Three players play two rounds each and sit out one
+    const draw = () => null;
+    for (let round = 0; round < 3; round++) {
+      await play_tournament_round(program, tournament, authority, players, draw);
+    }
+    const state = await program.account.tournament.fetch(tournament);
+    expect(Object.keys(state.status)[0]).to.equal('finished');
+    for (const player of state.players.slice(0, 3)) {
+      const opponents = player.rounds.slice(0, 3).filter(record => record.color != 0).map(record => record.opponent);
+      expect(opponents.length).to.equal(2);
+      expect(new Set(opponents).size).to.equal(2);
+      expect(player.score).to.equal(4);
+    }
+  });
+
+  it("knockout_tournament_with_prizes", async () => {
+    const players = [whitePlayer, blackPlayer, await new_player(program)];
+    const knockout = {
+      matchGames: 2, rapidGames: 2, rapidTime: 60, rapidBonus: 1,
+      armageddonWhiteTime: 50, armageddonBlackTime: 40, prizeSharesBps: [6000, 3000, 0, 0]
+    };
+    const tournament = await create_tournament(program,authority,tournament_params('knockout',3,0,300,2,false,knockout));
+    await register_players(program, tournament, players);
+
+    const mint = await Token.createMint(program.provider.connection, whitePlayer, whitePlayer.publicKey, null, 0, TOKEN_PROGRAM_ID);
+    const funderToken = await mint.createAccount(whitePlayer.publicKey);
+    const organizerToken = await mint.createAccount(authority.publicKey);
+    const blackToken = await mint.createAccount(blackPlayer.publicKey);
+    await mint.mintTo(funderToken, whitePlayer, [], 1000);
+    const prizeEscrow = await prize_address(program, tournament);
+    await program.rpc.fundPrizes(new anchor.BN(1000), {
+      accounts: {
+        tournament,
+        mint: mint.publicKey,
+        prizeEscrow,
+        funderToken,
+        funder: whitePlayer.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY
+      },
+      signers: [whitePlayer]
+    });
+    const distribute = (remainingAccounts) => program.rpc.distributePrizes({
+      accounts: { tournament, prizeEscrow, organizerToken, tokenProgram: TOKEN_PROGRAM_ID },
+      remainingAccounts,
+      signers: []
+    });
+    await expect_error(distribute([]), "TournamentNotFinished");
+
+    // The top seed has a bye and the other match goes to Armageddon. Every game of the
+    // final is drawn and the Armageddon draw goes to the top seed with black.
+    await play_tournament_round(program, tournament, authority, players, (white, black) => white);
+    let state = await program.account.tournament.fetch(tournament);
+    expect(state.pairings[0].finished).to.be.true;
+    expect(state.players[0].score).to.equal(2);
+    await play_tournament_round(program, tournament, authority, players, () => null);
+
+    state = await program.account.tournament.fetch(tournament);
+    expect(Object.keys(state.status)[0]).to.equal('finished');
+    expect(state.pairings[0].games).to.equal(5);
+    expect(state.standings[0]).to.equal(0);
+    expect(state.standings[1]).to.equal(1);
+
+    const accounts = [funderToken, blackToken].map(pubkey => ({ pubkey, isWritable: true, isSigner: false }));
+    await distribute(accounts);
+    await expect_error(distribute(accounts), "PrizesAlreadyPaid");
+    expect((await mint.getAccountInfo(funderToken)).amount.toNumber()).to.equal(600);
+    expect((await mint.getAccountInfo(blackToken)).amount.toNumber()).to.equal(300);
+    expect((await mint.getAccountInfo(organizerToken)).amount.toNumber()).to.equal(100);
   });
 
   // it("play_evergreen_game", async () => {
-  //   const gameKeypair = await setup_game(program,whitePlayer,blackPlayer,100,100,1,1);
-  //   await play(program,gameKeypair.publicKey,whitePlayer,5,1,4,3,4); // 1. e4
-  //   await play(program,gameKeypair.publicKey,blackPlayer,5,6,4,4,4); // 1. e5
-  //   await play(program,gameKeypair.publicKey,whitePlayer,1,0,6,2,5); // 2. Nf3
-  //   await play(program,gameKeypair.publicKey,blackPlayer,1,7,1,5,2); // 2. Nc6
-  //   await play(program,gameKeypair.publicKey,whitePlayer,2,0,5,3,2); // 3. Bc4
-  //   await play(program,gameKeypair.publicKey,blackPlayer,2,7,5,4,2); // 3. Bc5
-  //   await play(program,gameKeypair.publicKey,whitePlayer,5,1,1,3,1); // 4. b4
-  //   await play(program,gameKeypair.publicKey,blackPlayer,2,4,2,3,1); // 4. Bxb4
-  //   await play(program,gameKeypair.publicKey,whitePlayer,5,1,2,2,2); // 5. c3
-  //   await play(program,gameKeypair.publicKey,blackPlayer,2,3,1,4,0); // 5. Ba5
-  //   let gameState = await program.account.game.fetch(gameKeypair.publicKey);
+  //   const game = await setup_game(program,authority,whitePlayer,blackPlayer,100,100,1,1);
+  //   await play(program,game,whitePlayer,5,1,4,3,4); // 1. e4
+  //   await play(program,game,blackPlayer,5,6,4,4,4); // 1. e5
+  //   await play(program,game,whitePlayer,1,0,6,2,5); // 2. Nf3
+  //   await play(program,game,blackPlayer,1,7,1,5,2); // 2. Nc6
+  //   await play(program,game,whitePlayer,2,0,5,3,2); // 3. Bc4
+  //   await play(program,game,blackPlayer,2,7,5,4,2); // 3. Bc5
+  //   await play(program,game,whitePlayer,5,1,1,3,1); // 4. b4
+  //   await play(program,game,blackPlayer,2,4,2,3,1); // 4. Bxb4
+  //   await play(program,game,whitePlayer,5,1,2,2,2); // 5. c3
+  //   await play(program,game,blackPlayer,2,3,1,4,0); // 5. Ba5
+  //   let gameState = await program.account.game.fetch(game);
   //   console.log(gameState.currBoard.pieceBoard);
   //   console.log(gameState.currBoard.whiteBoard);
   // });